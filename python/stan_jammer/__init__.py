@@ -1,3 +1,37 @@
-from stan_jammer._lowlevel import hello
+from stan_jammer._lowlevel import (
+    DuplicateBlockError,
+    IncludeCycleError,
+    IncludeNotFoundError,
+    MalformedIncludeError,
+    MissingFileError,
+    SizeError,
+    StanJamError,
+    StanModel,
+    StanSourceParser,
+    StanSyntaxError,
+    UnbalancedBracesError,
+    UnknownBlockError,
+    UnreadableFileError,
+    Variable,
+    cache_stats,
+    flatten,
+)
 
-__all__ = ["hello"]
+__all__ = [
+    "DuplicateBlockError",
+    "IncludeCycleError",
+    "IncludeNotFoundError",
+    "MalformedIncludeError",
+    "MissingFileError",
+    "SizeError",
+    "StanJamError",
+    "StanModel",
+    "StanSourceParser",
+    "StanSyntaxError",
+    "UnbalancedBracesError",
+    "UnknownBlockError",
+    "UnreadableFileError",
+    "Variable",
+    "cache_stats",
+    "flatten",
+]
@@ -0,0 +1,126 @@
+class StanJamError(Exception):
+    """Raised when a Stan program cannot be read, flattened or split into blocks."""
+
+class MissingFileError(StanJamError):
+    """Raised when a Stan file, or a file it includes, does not exist."""
+
+class UnreadableFileError(StanJamError):
+    """Raised when a Stan file exists but cannot be read."""
+
+class IncludeNotFoundError(StanJamError):
+    """Raised when no search folder contains an included file."""
+
+class MalformedIncludeError(StanJamError):
+    """Raised for an `#include` line whose path cannot be parsed."""
+
+class IncludeCycleError(StanJamError):
+    """Raised when a file directly or indirectly includes itself."""
+
+class UnbalancedBracesError(StanJamError):
+    """Raised for a `}` without a matching `{`, or a block left open."""
+
+class UnknownBlockError(StanJamError):
+    """Raised for text at the top level that is not a known block header."""
+
+class DuplicateBlockError(StanJamError):
+    """Raised when a block appears more than once."""
+
+class StanSyntaxError(StanJamError):
+    """Raised for a statement inside a block that does not parse."""
+
+class SizeError(StanJamError):
+    """Raised when the sizes of a declaration cannot be evaluated against the data."""
+
+class StanSourceParser:
+    """Reads a Stan program, resolving `#include` directives through a list of search folders."""
+
+    def __init__(self, filename: str, files: dict[str, str] | None = None) -> None:
+        """Read `filename` from disk, or from `files`, a dict of paths to sources, when it is given."""
+    @staticmethod
+    def from_archive(filename: str, archive: str) -> StanSourceParser:
+        """Read `filename` and its includes from the zip or tar archive at `archive`."""
+    @staticmethod
+    def from_str(source: str, name: str = "<string>") -> StanSourceParser:
+        """Parse `source` as if it had been read from the file `name`, looking for its includes next to `name` and in the search folders."""
+    @property
+    def filename(self) -> str: ...
+    @property
+    def folders(self) -> list[str]: ...
+    def add_folder(self, folder: str) -> None: ...
+    @property
+    def include_once(self) -> str:
+        """Which files are inlined only the first time they are included: `"guarded"`, `"functions"` or `"always"`."""
+    @include_once.setter
+    def include_once(self, include_once: str) -> None: ...
+    @property
+    def cached(self) -> bool:
+        """Whether included files are kept in the process-wide cache once read, so parsers sharing a library read and scan it only once."""
+    @cached.setter
+    def cached(self, cached: bool) -> None: ...
+    def get_lines(self) -> list[str]:
+        """The non-empty, trimmed lines of the file, without resolving includes."""
+    def flatten(self) -> str:
+        """The program with every include recursively inlined."""
+    def rewrite_stanc_message(self, message: str) -> str:
+        """Rewrite every location in stanc error or warning output for the flattened program so it points at the original file and line."""
+    def read_file(self) -> StanModel:
+        """Flatten the program and split it into blocks."""
+    def read_file_with_diagnostics(self) -> tuple[StanModel, list[str]]:
+        """Read the program as far as possible. Returns the blocks that could be read and a message for every structural or syntax error."""
+    def inline_includes(self, model: StanModel) -> StanModel:
+        """A copy of `model` with the includes in its blocks inlined."""
+
+class StanModel:
+    """A Stan program split into its blocks."""
+
+    def __init__(self) -> None: ...
+    def block(self, name: str) -> list[str] | None:
+        """The lines of the named block (e.g. `"transformed data"`), or `None` if the block is absent."""
+    def add(self, name: str, line: str) -> None:
+        """Add a line of Stan code to the named block."""
+    @property
+    def functions(self) -> list[str] | None: ...
+    @property
+    def data(self) -> list[str]: ...
+    @property
+    def transformed_data(self) -> list[str] | None: ...
+    @property
+    def parameters(self) -> list[str]: ...
+    @property
+    def transformed_parameters(self) -> list[str] | None: ...
+    @property
+    def model(self) -> list[str]: ...
+    @property
+    def generated_quantities(self) -> list[str] | None: ...
+    def has_include_directive(self) -> bool: ...
+    def data_interface(self) -> list[Variable]:
+        """The inputs declared in the data block, in declaration order."""
+    def __str__(self) -> str:
+        """The model rendered as Stan source."""
+    def __eq__(self, other: object) -> bool: ...
+
+class Variable:
+    """A variable declared in a block, with its type, dimensions and constraints as Stan source text."""
+
+    @property
+    def name(self) -> str: ...
+    @property
+    def base_type(self) -> str: ...
+    @property
+    def dims(self) -> list[str]: ...
+    @property
+    def lower(self) -> str | None: ...
+    @property
+    def upper(self) -> str | None: ...
+    @property
+    def offset(self) -> str | None: ...
+    @property
+    def multiplier(self) -> str | None: ...
+    @property
+    def declaration(self) -> str: ...
+
+def flatten(filename: str, folders: list[str] = ...) -> str:
+    """Read `filename`, resolve its includes through `folders` and return the flattened Stan source."""
+
+def cache_stats() -> tuple[int, int]:
+    """The `(hits, misses)` of the process-wide cache of included files."""
@@ -0,0 +1,243 @@
+import os
+import tempfile
+import unittest
+import zipfile
+
+import stan_jammer
+from stan_jammer import (
+    DuplicateBlockError,
+    IncludeCycleError,
+    IncludeNotFoundError,
+    MalformedIncludeError,
+    MissingFileError,
+    SizeError,
+    StanJamError,
+    StanModel,
+    StanSourceParser,
+    StanSyntaxError,
+    UnbalancedBracesError,
+    UnknownBlockError,
+    UnreadableFileError,
+)
+
+MAIN = """functions {
+#include lib.stan
+}
+data {
+  int<lower=0> N;
+  vector[N] y;
+}
+parameters {
+  real mu;
+}
+model {
+  y ~ normal(mu, 1);
+}
+"""
+
+LIB = "real twice(real x) { return 2 * x; }\n"
+
+
+def parser(main=MAIN, **files):
+    return StanSourceParser("main.stan", {"main.stan": main, **files})
+
+
+class ParserTest(unittest.TestCase):
+    def test_files_are_read_from_the_given_dict(self):
+        p = parser(**{"lib.stan": LIB})
+        self.assertEqual(p.filename, "main.stan")
+        self.assertIn("real twice(real x)", p.flatten())
+        self.assertNotIn("#include", p.flatten())
+
+    def test_lines_are_trimmed_and_includes_kept(self):
+        lines = parser().get_lines()
+        self.assertEqual(lines[:3], ["functions {", "#include lib.stan", "}"])
+        self.assertNotIn("", lines)
+
+    def test_source_can_be_parsed_from_a_string(self):
+        p = StanSourceParser.from_str("data { int N; }\nmodel { }\n")
+        self.assertEqual(p.read_file().data, ["int N;"])
+
+    def test_files_are_read_from_an_archive(self):
+        with tempfile.TemporaryDirectory() as folder:
+            archive = os.path.join(folder, "model.zip")
+            with zipfile.ZipFile(archive, "w") as zip:
+                zip.writestr("main.stan", MAIN)
+                zip.writestr("lib.stan", LIB)
+            p = StanSourceParser.from_archive("main.stan", archive)
+            self.assertEqual(p.flatten(), parser(**{"lib.stan": LIB}).flatten())
+
+    def test_unreadable_archive_is_reported(self):
+        with self.assertRaises(StanJamError):
+            StanSourceParser.from_archive("main.stan", "missing.zip")
+
+    def test_folders_are_searched_for_includes(self):
+        with tempfile.TemporaryDirectory() as folder:
+            with open(os.path.join(folder, "main.stan"), "w") as f:
+                f.write(MAIN)
+            library = os.path.join(folder, "library")
+            os.mkdir(library)
+            with open(os.path.join(library, "lib.stan"), "w") as f:
+                f.write(LIB)
+            p = StanSourceParser(os.path.join(folder, "main.stan"))
+            with self.assertRaises(IncludeNotFoundError):
+                p.flatten()
+            p.add_folder(library)
+            self.assertEqual(p.folders, [library])
+            self.assertIn("real twice", p.flatten())
+            flat = stan_jammer.flatten(os.path.join(folder, "main.stan"), [library])
+            self.assertEqual(flat, p.flatten())
+
+    def test_include_once_accepts_only_known_settings(self):
+        p = parser()
+        self.assertEqual(p.include_once, "functions")
+        p.include_once = "always"
+        self.assertEqual(p.include_once, "always")
+        with self.assertRaises(StanJamError):
+            p.include_once = "sometimes"
+        self.assertEqual(p.include_once, "always")
+
+    def test_cache_can_be_switched_off(self):
+        p = parser(**{"lib.stan": LIB})
+        p.cached = False
+        self.assertFalse(p.cached)
+        p.cached = True
+        self.assertTrue(p.cached)
+        hits, misses = stan_jammer.cache_stats()
+        p.flatten()
+        self.assertGreater(sum(stan_jammer.cache_stats()), hits + misses)
+
+    def test_stanc_messages_point_at_the_original_files(self):
+        p = parser(**{"lib.stan": LIB})
+        flat = [line.strip() for line in p.flatten().splitlines()]
+        line = flat.index(LIB.strip()) + 1
+        message = p.rewrite_stanc_message(
+            f"Semantic error in 'main.stan', line {line}, column 2"
+        )
+        self.assertIn("lib.stan", message)
+        self.assertIn("line 1,", message)
+
+    def test_includes_are_inlined_into_a_model(self):
+        p = parser(**{"library/lib.stan": LIB})
+        p.add_folder("library")
+        model = p.read_file()
+        self.assertFalse(model.has_include_directive())
+        unflattened = StanModel()
+        unflattened.add("functions", "#include lib.stan")
+        self.assertTrue(unflattened.has_include_directive())
+        inlined = p.inline_includes(unflattened)
+        self.assertFalse(inlined.has_include_directive())
+        self.assertEqual(inlined.functions, model.functions)
+
+    def test_diagnostics_list_every_error(self):
+        source = "data {\n  int N\n}\nparameters {\n  real mu\n}\nmodel { }\n"
+        model, diagnostics = StanSourceParser.from_str(
+            source
+        ).read_file_with_diagnostics()
+        self.assertEqual(len(diagnostics), 2)
+        self.assertIsInstance(model, StanModel)
+
+    def test_repr_shows_the_file_and_folders(self):
+        self.assertEqual(
+            repr(parser()), 'StanSourceParser(filename="main.stan", folders=[])'
+        )
+
+
+class ModelTest(unittest.TestCase):
+    def setUp(self):
+        self.model = parser(**{"lib.stan": LIB}).read_file()
+
+    def test_blocks_are_read_by_name(self):
+        self.assertEqual(self.model.data, ["int<lower=0> N;", "vector[N] y;"])
+        self.assertEqual(self.model.parameters, ["real mu;"])
+        self.assertEqual(self.model.model, ["y ~ normal(mu, 1);"])
+        self.assertEqual(self.model.functions, [LIB.strip()])
+        self.assertIsNone(self.model.transformed_data)
+        self.assertIsNone(self.model.transformed_parameters)
+        self.assertIsNone(self.model.generated_quantities)
+        self.assertEqual(self.model.block("parameters"), ["real mu;"])
+        self.assertIsNone(self.model.block("generated quantities"))
+        with self.assertRaises(StanJamError):
+            self.model.block("priors")
+
+    def test_lines_are_added_to_blocks(self):
+        model = StanModel()
+        model.add("transformed data", "real x = 1;")
+        self.assertEqual(model.transformed_data, ["real x = 1;"])
+        with self.assertRaises(StanJamError):
+            model.add("priors", "real x;")
+
+    def test_models_render_and_compare(self):
+        again = StanSourceParser.from_str(str(self.model)).read_file()
+        self.assertEqual(again, self.model)
+        self.assertNotEqual(StanModel(), self.model)
+
+    def test_data_interface_lists_the_data_variables(self):
+        n, y = self.model.data_interface()
+        self.assertEqual((n.name, n.base_type, n.dims, n.lower), ("N", "int", [], "0"))
+        self.assertIsNone(n.upper)
+        self.assertEqual((y.name, y.base_type, y.dims), ("y", "vector", ["N"]))
+        self.assertEqual(y.declaration, "vector[N] y")
+
+
+class ErrorTest(unittest.TestCase):
+    def assertRaisesKind(self, kind, read):
+        with self.assertRaises(kind) as raised:
+            read()
+        self.assertIsInstance(raised.exception, StanJamError)
+        self.assertTrue(str(raised.exception))
+
+    def test_missing_file(self):
+        self.assertRaisesKind(
+            MissingFileError, StanSourceParser("no/such/file.stan").flatten
+        )
+
+    def test_include_not_found(self):
+        self.assertRaisesKind(IncludeNotFoundError, parser().flatten)
+
+    def test_malformed_include(self):
+        self.assertRaisesKind(MalformedIncludeError, parser("#include\n").flatten)
+
+    def test_include_cycle(self):
+        p = parser("#include a.stan\n", **{"a.stan": "#include main.stan\n"})
+        p.include_once = "always"
+        self.assertRaisesKind(IncludeCycleError, p.flatten)
+
+    def test_unbalanced_braces(self):
+        self.assertRaisesKind(
+            UnbalancedBracesError, StanSourceParser.from_str("data {\n").read_file
+        )
+
+    def test_unknown_block(self):
+        self.assertRaisesKind(
+            UnknownBlockError, StanSourceParser.from_str("priors {\n}\n").read_file
+        )
+
+    def test_duplicate_block(self):
+        source = "data {\n}\ndata {\n}\n"
+        self.assertRaisesKind(
+            DuplicateBlockError, StanSourceParser.from_str(source).read_file
+        )
+
+    def test_syntax_error(self):
+        model = StanSourceParser.from_str("data {\n  int N\n}\n").read_file()
+        self.assertRaisesKind(StanSyntaxError, model.data_interface)
+
+    def test_every_kind_is_a_stan_jam_error(self):
+        for kind in [
+            DuplicateBlockError,
+            IncludeCycleError,
+            IncludeNotFoundError,
+            MalformedIncludeError,
+            MissingFileError,
+            SizeError,
+            StanSyntaxError,
+            UnbalancedBracesError,
+            UnknownBlockError,
+            UnreadableFileError,
+        ]:
+            self.assertTrue(issubclass(kind, StanJamError))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,786 @@
+use std::fmt;
+
+use crate::lexer::Span;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExprKind {
+    IntLiteral(String),
+    RealLiteral(String),
+    ImaginaryLiteral(String),
+    StringLiteral(String),
+    Variable(String),
+    /// A function call. `conditional` is set for `f(y | theta)`, in which
+    /// case the first argument is the one before the bar.
+    Call {
+        name: String,
+        args: Vec<Expr>,
+        conditional: bool,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Ternary {
+        condition: Box<Expr>,
+        if_true: Box<Expr>,
+        if_false: Box<Expr>,
+    },
+    Index {
+        base: Box<Expr>,
+        indices: Vec<Index>,
+    },
+    /// Tuple element access, e.g. `x.2`.
+    TupleProjection {
+        base: Box<Expr>,
+        index: usize,
+    },
+    Transpose(Box<Expr>),
+    /// `{a, b, c}`
+    ArrayLiteral(Vec<Expr>),
+    /// `[a, b, c]`
+    RowVectorLiteral(Vec<Expr>),
+    /// `(a, b)`
+    TupleLiteral(Vec<Expr>),
+    Paren(Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Index {
+    Single(Expr),
+    /// `:`
+    All,
+    /// `a:b`, `a:` or `:b`
+    Range {
+        lower: Option<Expr>,
+        upper: Option<Expr>,
+    },
+    /// An empty slot, as in `a[, 1]`, which is the same as `:`.
+    Omitted,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UnaryOp {
+    Negate,
+    Plus,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    IntDivide,
+    LeftDivide,
+    ElementwiseMultiply,
+    ElementwiseDivide,
+    Power,
+    ElementwisePower,
+}
+
+impl BinaryOp {
+    pub fn from_symbol(symbol: &str) -> Option<BinaryOp> {
+        let op = match symbol {
+            "||" => BinaryOp::Or,
+            "&&" => BinaryOp::And,
+            "==" => BinaryOp::Equal,
+            "!=" => BinaryOp::NotEqual,
+            "<" => BinaryOp::Less,
+            "<=" => BinaryOp::LessEqual,
+            ">" => BinaryOp::Greater,
+            ">=" => BinaryOp::GreaterEqual,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Subtract,
+            "*" => BinaryOp::Multiply,
+            "/" => BinaryOp::Divide,
+            "%" => BinaryOp::Modulo,
+            "%/%" => BinaryOp::IntDivide,
+            "\\" => BinaryOp::LeftDivide,
+            ".*" => BinaryOp::ElementwiseMultiply,
+            "./" => BinaryOp::ElementwiseDivide,
+            "^" => BinaryOp::Power,
+            ".^" => BinaryOp::ElementwisePower,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Or => "||",
+            BinaryOp::And => "&&",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Modulo => "%",
+            BinaryOp::IntDivide => "%/%",
+            BinaryOp::LeftDivide => "\\",
+            BinaryOp::ElementwiseMultiply => ".*",
+            BinaryOp::ElementwiseDivide => "./",
+            BinaryOp::Power => "^",
+            BinaryOp::ElementwisePower => ".^",
+        }
+    }
+
+    /// Binding strength; higher binds tighter. Unary operators sit between
+    /// the multiplicative operators and exponentiation.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Equal | BinaryOp::NotEqual => 3,
+            BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 4,
+            BinaryOp::Add | BinaryOp::Subtract => 5,
+            BinaryOp::Multiply
+            | BinaryOp::Divide
+            | BinaryOp::Modulo
+            | BinaryOp::IntDivide
+            | BinaryOp::LeftDivide
+            | BinaryOp::ElementwiseMultiply
+            | BinaryOp::ElementwiseDivide => 6,
+            BinaryOp::Power | BinaryOp::ElementwisePower => 8,
+        }
+    }
+
+    pub fn is_right_associative(&self) -> bool {
+        matches!(self, BinaryOp::Power | BinaryOp::ElementwisePower)
+    }
+}
+
+impl UnaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Plus => "+",
+            UnaryOp::Not => "!",
+        }
+    }
+}
+
+/// The precedence of prefix unary operators, see `BinaryOp::precedence`.
+pub const UNARY_PRECEDENCE: u8 = 7;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum BaseType {
+    Int,
+    Real,
+    Complex,
+    Vector,
+    RowVector,
+    Matrix,
+    ComplexVector,
+    ComplexRowVector,
+    ComplexMatrix,
+    Simplex,
+    UnitVector,
+    SumToZeroVector,
+    SumToZeroMatrix,
+    Ordered,
+    PositiveOrdered,
+    CholeskyFactorCorr,
+    CholeskyFactorCov,
+    CorrMatrix,
+    CovMatrix,
+    ColumnStochasticMatrix,
+    RowStochasticMatrix,
+    Tuple(Vec<VarType>),
+}
+
+impl BaseType {
+    pub fn from_keyword(keyword: &str) -> Option<BaseType> {
+        let base = match keyword {
+            "int" => BaseType::Int,
+            "real" => BaseType::Real,
+            "complex" => BaseType::Complex,
+            "vector" => BaseType::Vector,
+            "row_vector" => BaseType::RowVector,
+            "matrix" => BaseType::Matrix,
+            "complex_vector" => BaseType::ComplexVector,
+            "complex_row_vector" => BaseType::ComplexRowVector,
+            "complex_matrix" => BaseType::ComplexMatrix,
+            "simplex" => BaseType::Simplex,
+            "unit_vector" => BaseType::UnitVector,
+            "sum_to_zero_vector" => BaseType::SumToZeroVector,
+            "sum_to_zero_matrix" => BaseType::SumToZeroMatrix,
+            "ordered" => BaseType::Ordered,
+            "positive_ordered" => BaseType::PositiveOrdered,
+            "cholesky_factor_corr" => BaseType::CholeskyFactorCorr,
+            "cholesky_factor_cov" => BaseType::CholeskyFactorCov,
+            "corr_matrix" => BaseType::CorrMatrix,
+            "cov_matrix" => BaseType::CovMatrix,
+            "column_stochastic_matrix" => BaseType::ColumnStochasticMatrix,
+            "row_stochastic_matrix" => BaseType::RowStochasticMatrix,
+            _ => return None,
+        };
+        Some(base)
+    }
+
+    /// The keyword for the type; tuples are named `tuple`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BaseType::Int => "int",
+            BaseType::Real => "real",
+            BaseType::Complex => "complex",
+            BaseType::Vector => "vector",
+            BaseType::RowVector => "row_vector",
+            BaseType::Matrix => "matrix",
+            BaseType::ComplexVector => "complex_vector",
+            BaseType::ComplexRowVector => "complex_row_vector",
+            BaseType::ComplexMatrix => "complex_matrix",
+            BaseType::Simplex => "simplex",
+            BaseType::UnitVector => "unit_vector",
+            BaseType::SumToZeroVector => "sum_to_zero_vector",
+            BaseType::SumToZeroMatrix => "sum_to_zero_matrix",
+            BaseType::Ordered => "ordered",
+            BaseType::PositiveOrdered => "positive_ordered",
+            BaseType::CholeskyFactorCorr => "cholesky_factor_corr",
+            BaseType::CholeskyFactorCov => "cholesky_factor_cov",
+            BaseType::CorrMatrix => "corr_matrix",
+            BaseType::CovMatrix => "cov_matrix",
+            BaseType::ColumnStochasticMatrix => "column_stochastic_matrix",
+            BaseType::RowStochasticMatrix => "row_stochastic_matrix",
+            BaseType::Tuple(_) => "tuple",
+        }
+    }
+
+    /// The numbers of sizes the type can be declared with: none for scalars
+    /// and tuples, `[K]` for vectors and square matrices, `[N, M]` for other
+    /// matrices, and either for `cholesky_factor_cov`.
+    pub fn size_counts(&self) -> &'static [usize] {
+        match self {
+            BaseType::Int | BaseType::Real | BaseType::Complex | BaseType::Tuple(_) => &[0],
+            BaseType::Vector
+            | BaseType::RowVector
+            | BaseType::ComplexVector
+            | BaseType::ComplexRowVector
+            | BaseType::Simplex
+            | BaseType::UnitVector
+            | BaseType::SumToZeroVector
+            | BaseType::Ordered
+            | BaseType::PositiveOrdered
+            | BaseType::CholeskyFactorCorr
+            | BaseType::CorrMatrix
+            | BaseType::CovMatrix => &[1],
+            BaseType::Matrix
+            | BaseType::ComplexMatrix
+            | BaseType::SumToZeroMatrix
+            | BaseType::ColumnStochasticMatrix
+            | BaseType::RowStochasticMatrix => &[2],
+            BaseType::CholeskyFactorCov => &[1, 2],
+        }
+    }
+
+    /// A message for a declaration of the type with `found` sizes, when that
+    /// is not one of its `size_counts`.
+    pub fn check_size_count(&self, found: usize) -> Result<(), String> {
+        let counts = self.size_counts();
+        if counts.contains(&found) {
+            return Ok(());
+        }
+        let expected = match counts {
+            [0] => "no sizes".to_string(),
+            [1] => "1 size".to_string(),
+            [count] => format!("{} sizes", count),
+            _ => format!(
+                "{} sizes",
+                counts
+                    .iter()
+                    .map(|count| count.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" or ")
+            ),
+        };
+        Err(format!(
+            "'{}' takes {}, found {}",
+            self.name(),
+            expected,
+            found
+        ))
+    }
+}
+
+/// `lower`, `upper`, `offset` and `multiplier` bounds of a declaration.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Constraints {
+    pub lower: Option<Expr>,
+    pub upper: Option<Expr>,
+    pub offset: Option<Expr>,
+    pub multiplier: Option<Expr>,
+}
+
+impl Constraints {
+    pub fn is_empty(&self) -> bool {
+        self.lower.is_none()
+            && self.upper.is_none()
+            && self.offset.is_none()
+            && self.multiplier.is_none()
+    }
+}
+
+/// A sized declaration type such as `array[N] vector<lower=0>[K]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VarType {
+    pub array_dims: Vec<Expr>,
+    pub base: BaseType,
+    pub constraints: Constraints,
+    /// The sizes in brackets after the base type, e.g. `[K]` or `[N, M]`.
+    pub sizes: Vec<Expr>,
+    pub span: Span,
+}
+
+/// An unsized type in a function signature, such as `data array[,] real`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnsizedType {
+    pub data_only: bool,
+    pub array_dims: usize,
+    pub base: UnsizedBaseType,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum UnsizedBaseType {
+    Named(BaseType),
+    Tuple(Vec<UnsizedType>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Declarator {
+    pub name: String,
+    /// Dimensions written after the name in the removed `real y[N];` syntax.
+    pub legacy_array_dims: Vec<Expr>,
+    pub init: Option<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VarDecl {
+    pub ty: VarType,
+    pub declarators: Vec<Declarator>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Param {
+    pub ty: UnsizedType,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionDef {
+    /// `None` for `void` functions.
+    pub return_type: Option<UnsizedType>,
+    pub name: String,
+    pub params: Vec<Param>,
+    /// `None` for a forward declaration.
+    pub body: Option<Box<Stmt>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AssignOp {
+    Assign,
+    AddAssign,
+    SubtractAssign,
+    MultiplyAssign,
+    DivideAssign,
+    ElementwiseMultiplyAssign,
+    ElementwiseDivideAssign,
+}
+
+impl AssignOp {
+    pub fn from_symbol(symbol: &str) -> Option<AssignOp> {
+        let op = match symbol {
+            "=" => AssignOp::Assign,
+            "+=" => AssignOp::AddAssign,
+            "-=" => AssignOp::SubtractAssign,
+            "*=" => AssignOp::MultiplyAssign,
+            "/=" => AssignOp::DivideAssign,
+            ".*=" => AssignOp::ElementwiseMultiplyAssign,
+            "./=" => AssignOp::ElementwiseDivideAssign,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            AssignOp::Assign => "=",
+            AssignOp::AddAssign => "+=",
+            AssignOp::SubtractAssign => "-=",
+            AssignOp::MultiplyAssign => "*=",
+            AssignOp::DivideAssign => "/=",
+            AssignOp::ElementwiseMultiplyAssign => ".*=",
+            AssignOp::ElementwiseDivideAssign => "./=",
+        }
+    }
+}
+
+/// `T[lower, upper]` on a sampling statement; either bound may be omitted.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Truncation {
+    pub lower: Option<Expr>,
+    pub upper: Option<Expr>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StmtKind {
+    VarDecl(VarDecl),
+    Assign {
+        target: Expr,
+        op: AssignOp,
+        value: Expr,
+    },
+    /// `y ~ dist(args) T[lower, upper];`
+    Tilde {
+        lhs: Expr,
+        distribution: String,
+        args: Vec<Expr>,
+        truncation: Option<Truncation>,
+    },
+    TargetPlusAssign(Expr),
+    For {
+        variable: String,
+        lower: Expr,
+        upper: Expr,
+        body: Box<Stmt>,
+    },
+    ForEach {
+        variable: String,
+        collection: Expr,
+        body: Box<Stmt>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    Block(Vec<Stmt>),
+    Profile {
+        name: String,
+        body: Vec<Stmt>,
+    },
+    Break,
+    Continue,
+    Return(Option<Expr>),
+    Print(Vec<Expr>),
+    Reject(Vec<Expr>),
+    FatalError(Vec<Expr>),
+    /// A function call used as a statement.
+    Expr(Expr),
+    FunctionDef(FunctionDef),
+    /// An `#include` directive that has not been resolved.
+    Include(String),
+    /// A lone `;`.
+    Empty,
+}
+
+impl Stmt {
+    /// Call `visit` on this statement and then on every statement nested
+    /// inside it, in source order.
+    pub fn walk<'a>(&'a self, visit: &mut impl FnMut(&'a Stmt)) {
+        visit(self);
+        match &self.kind {
+            StmtKind::For { body, .. }
+            | StmtKind::ForEach { body, .. }
+            | StmtKind::While { body, .. } => body.walk(visit),
+            StmtKind::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                then_branch.walk(visit);
+                if let Some(else_branch) = else_branch {
+                    else_branch.walk(visit);
+                }
+            }
+            StmtKind::Block(body) | StmtKind::Profile { body, .. } => {
+                body.iter().for_each(|statement| statement.walk(visit))
+            }
+            StmtKind::FunctionDef(FunctionDef {
+                body: Some(body), ..
+            }) => body.walk(visit),
+            _ => {}
+        }
+    }
+}
+
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter<'_>, items: &[T]) -> fmt::Result {
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expr {
+    /// Print the expression in Stan syntax, with single spaces around binary
+    /// operators. Parentheses are printed only where the source had them.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::IntLiteral(text)
+            | ExprKind::RealLiteral(text)
+            | ExprKind::ImaginaryLiteral(text)
+            | ExprKind::StringLiteral(text)
+            | ExprKind::Variable(text) => write!(f, "{}", text),
+            ExprKind::Call {
+                name,
+                args,
+                conditional,
+            } => {
+                write!(f, "{}(", name)?;
+                match (conditional, args.split_first()) {
+                    (true, Some((first, rest))) => {
+                        write!(f, "{} | ", first)?;
+                        write_list(f, rest)?;
+                    }
+                    _ => write_list(f, args)?,
+                }
+                write!(f, ")")
+            }
+            ExprKind::Unary { op, operand } => write!(f, "{}{}", op.symbol(), operand),
+            ExprKind::Binary { op, left, right } => match op {
+                BinaryOp::Power | BinaryOp::ElementwisePower => {
+                    write!(f, "{}{}{}", left, op.symbol(), right)
+                }
+                _ => write!(f, "{} {} {}", left, op.symbol(), right),
+            },
+            ExprKind::Ternary {
+                condition,
+                if_true,
+                if_false,
+            } => write!(f, "{} ? {} : {}", condition, if_true, if_false),
+            ExprKind::Index { base, indices } => {
+                write!(f, "{}[", base)?;
+                write_list(f, indices)?;
+                write!(f, "]")
+            }
+            ExprKind::TupleProjection { base, index } => write!(f, "{}.{}", base, index),
+            ExprKind::Transpose(operand) => write!(f, "{}'", operand),
+            ExprKind::ArrayLiteral(items) => {
+                write!(f, "{{")?;
+                write_list(f, items)?;
+                write!(f, "}}")
+            }
+            ExprKind::RowVectorLiteral(items) => {
+                write!(f, "[")?;
+                write_list(f, items)?;
+                write!(f, "]")
+            }
+            ExprKind::TupleLiteral(items) => {
+                write!(f, "(")?;
+                write_list(f, items)?;
+                write!(f, ")")
+            }
+            ExprKind::Paren(inner) => write!(f, "({})", inner),
+        }
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Index::Single(expr) => write!(f, "{}", expr),
+            Index::All => write!(f, ":"),
+            Index::Omitted => Ok(()),
+            Index::Range { lower, upper } => {
+                if let Some(lower) = lower {
+                    write!(f, "{}", lower)?;
+                }
+                write!(f, ":")?;
+                if let Some(upper) = upper {
+                    write!(f, "{}", upper)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Constraints {
+    /// `<lower=0, upper=1>`, or nothing when there are no constraints.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            ("lower", &self.lower),
+            ("upper", &self.upper),
+            ("offset", &self.offset),
+            ("multiplier", &self.multiplier),
+        ]
+        .iter()
+        .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}={}", name, value)))
+        .collect::<Vec<String>>();
+
+        if parts.is_empty() {
+            Ok(())
+        } else {
+            write!(f, "<{}>", parts.join(", "))
+        }
+    }
+}
+
+impl fmt::Display for VarType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.array_dims.is_empty() {
+            write!(f, "array[")?;
+            write_list(f, &self.array_dims)?;
+            write!(f, "] ")?;
+        }
+        match &self.base {
+            BaseType::Tuple(items) => {
+                write!(f, "tuple(")?;
+                write_list(f, items)?;
+                write!(f, ")")?;
+            }
+            base => write!(f, "{}", base.name())?,
+        }
+        write!(f, "{}", self.constraints)?;
+        if !self.sizes.is_empty() {
+            write!(f, "[")?;
+            write_list(f, &self.sizes)?;
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for UnsizedType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.data_only {
+            write!(f, "data ")?;
+        }
+        if self.array_dims > 0 {
+            write!(f, "array[{}] ", ",".repeat(self.array_dims - 1))?;
+        }
+        match &self.base {
+            UnsizedBaseType::Named(base) => write!(f, "{}", base.name()),
+            UnsizedBaseType::Tuple(items) => {
+                write!(f, "tuple(")?;
+                write_list(f, items)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.ty, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(kind: ExprKind) -> Expr {
+        Expr {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    fn variable(name: &str) -> Expr {
+        expr(ExprKind::Variable(name.to_string()))
+    }
+
+    #[test]
+    fn operator_symbols_round_trip() {
+        for symbol in [
+            "||", "&&", "==", "!=", "<", "<=", ">", ">=", "+", "-", "*", "/", "%", "%/%", "\\",
+            ".*", "./", "^", ".^",
+        ] {
+            assert_eq!(BinaryOp::from_symbol(symbol).unwrap().symbol(), symbol);
+        }
+        for symbol in ["=", "+=", "-=", "*=", "/=", ".*=", "./="] {
+            assert_eq!(AssignOp::from_symbol(symbol).unwrap().symbol(), symbol);
+        }
+    }
+
+    #[test]
+    fn expressions_display_as_stan() {
+        let call = expr(ExprKind::Call {
+            name: "normal_lpdf".to_string(),
+            args: vec![variable("y"), variable("mu"), variable("sigma")],
+            conditional: true,
+        });
+        assert_eq!(call.to_string(), "normal_lpdf(y | mu, sigma)");
+
+        let indexed = expr(ExprKind::Index {
+            base: Box::new(variable("x")),
+            indices: vec![
+                Index::Single(variable("i")),
+                Index::All,
+                Index::Range {
+                    lower: Some(variable("a")),
+                    upper: None,
+                },
+            ],
+        });
+        assert_eq!(indexed.to_string(), "x[i, :, a:]");
+
+        let power = expr(ExprKind::Binary {
+            op: BinaryOp::Power,
+            left: Box::new(variable("x")),
+            right: Box::new(expr(ExprKind::IntLiteral("2".to_string()))),
+        });
+        assert_eq!(power.to_string(), "x^2");
+    }
+
+    #[test]
+    fn types_display_as_stan() {
+        let ty = VarType {
+            array_dims: vec![variable("N")],
+            base: BaseType::Vector,
+            constraints: Constraints {
+                lower: Some(expr(ExprKind::IntLiteral("0".to_string()))),
+                ..Constraints::default()
+            },
+            sizes: vec![variable("K")],
+            span: Span::default(),
+        };
+        assert_eq!(ty.to_string(), "array[N] vector<lower=0>[K]");
+
+        let unsized_type = UnsizedType {
+            data_only: true,
+            array_dims: 2,
+            base: UnsizedBaseType::Named(BaseType::Real),
+        };
+        assert_eq!(unsized_type.to_string(), "data array[,] real");
+    }
+}
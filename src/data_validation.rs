@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::ast::{BaseType, Expr, VarType};
+use crate::error::SourceLocation;
+use crate::eval::{container_value, json_scalar, Data};
+use crate::interface::{array_elements, element_type, json_shape, Bounds, ElementType, Variable};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DataProblemKind {
+    /// A declared variable has no value in the data.
+    Missing,
+    /// A value is of the wrong type, such as a real where an int is declared.
+    WrongType,
+    /// An array has the wrong number of elements for its declared size.
+    WrongDimensions,
+    /// A value is outside its declared `lower` or `upper` bound.
+    OutOfBounds,
+    /// The declared sizes could not be evaluated, so the dimensions of the
+    /// value could not be checked.
+    UnknownSize,
+    /// The declared bounds could not be evaluated, or no value can satisfy
+    /// them, so the value could not be checked against them.
+    UnknownBounds,
+}
+
+/// One way in which the data does not match the data block.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataProblem {
+    pub kind: DataProblemKind,
+    pub variable: String,
+    pub message: String,
+    /// Where the variable is declared.
+    pub location: SourceLocation,
+}
+
+impl fmt::Display for DataProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Parse the text of a CmdStan JSON data file, which must hold an object.
+pub fn parse_data(text: &str) -> Result<Data, String> {
+    match serde_json::from_str(text).map_err(|error| error.to_string())? {
+        Value::Object(data) => Ok(data),
+        _ => Err("the data must be a JSON object".to_string()),
+    }
+}
+
+/// Check `data` against the declarations of the data block and return every
+/// problem found, in declaration order. Variables in the data that are not
+/// declared are ignored, as CmdStan does. Sizes are evaluated against the
+/// data itself. A bound that is not a scalar, such as a vector of lower
+/// bounds, is checked element by element.
+pub fn validate_data(variables: &[Variable], data: &Data) -> Vec<DataProblem> {
+    let mut problems = Vec::new();
+    for variable in variables {
+        let mut checker = Checker {
+            variable,
+            data,
+            problems: &mut problems,
+        };
+        match data.get(&variable.name) {
+            Some(value) => checker.check(value, &variable.ty, &variable.name),
+            None => checker.report(
+                DataProblemKind::Missing,
+                format!("'{}' is missing from the data", variable.name),
+            ),
+        }
+    }
+    problems
+}
+
+struct Checker<'a> {
+    variable: &'a Variable,
+    data: &'a Data,
+    problems: &'a mut Vec<DataProblem>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, kind: DataProblemKind, message: String) {
+        self.problems.push(DataProblem {
+            kind,
+            variable: self.variable.name.clone(),
+            message,
+            location: self.variable.location.clone(),
+        });
+    }
+
+    /// Check the value named `path` against `ty`.
+    fn check(&mut self, value: &Value, ty: &VarType, path: &str) {
+        let shape = match json_shape(ty, self.data) {
+            Ok(shape) => shape,
+            Err(error) => {
+                return self.report(
+                    DataProblemKind::UnknownSize,
+                    format!("cannot check the dimensions of '{}': {}", path, error),
+                )
+            }
+        };
+        let (array_shape, element_shape) = shape.split_at(ty.array_dims.len());
+        let lower_bounds = self.element_bounds(&ty.constraints.lower, element_shape, path);
+        let upper_bounds = self.element_bounds(&ty.constraints.upper, element_shape, path);
+        let bounds =
+            Bounds::new(&scalar_bounds_type(ty, self.data), self.data).unwrap_or_else(|error| {
+                self.report(
+                    DataProblemKind::UnknownBounds,
+                    format!("cannot check the bounds of '{}': {}", path, error),
+                );
+                Bounds::default()
+            });
+        for item in array_elements(value, array_shape, path.to_string()) {
+            let (item, item_path) = match item {
+                Ok(item) => item,
+                Err(error) => {
+                    self.report(DataProblemKind::WrongDimensions, error);
+                    continue;
+                }
+            };
+            for element in array_elements(item, element_shape, item_path.clone()) {
+                match element {
+                    Ok((element, path)) => {
+                        let index = &path[item_path.len()..];
+                        let mut bounds = bounds;
+                        if let Some(lower_bounds) = &lower_bounds {
+                            bounds.lower = lower_bounds.get(index).copied().flatten();
+                        }
+                        if let Some(upper_bounds) = &upper_bounds {
+                            bounds.upper = upper_bounds.get(index).copied().flatten();
+                        }
+                        self.check_element(element, ty, bounds, path)
+                    }
+                    Err(error) => self.report(DataProblemKind::WrongDimensions, error),
+                }
+            }
+        }
+    }
+
+    /// The bound of each element of a value of `shape`, keyed by the element's
+    /// index such as `[2]`, if `bound` is an array, vector or matrix in the
+    /// data. Infinite bounds are no bound. A bound of the wrong shape is
+    /// reported and not checked.
+    fn element_bounds(
+        &mut self,
+        bound: &Option<Expr>,
+        shape: &[usize],
+        path: &str,
+    ) -> Option<HashMap<String, Option<f64>>> {
+        let bound = bound.as_ref()?;
+        let value = container_value(bound, self.data)?;
+        let name = bound.to_string();
+        let bounds = array_elements(value, shape, name.clone())
+            .into_iter()
+            .map(|element| {
+                let (element, element_path) = element?;
+                let scalar = json_scalar(element)
+                    .ok_or_else(|| format!("'{}' is not a number", element_path))?
+                    .as_f64();
+                let scalar = Some(scalar).filter(|scalar| scalar.is_finite());
+                Ok((element_path[name.len()..].to_string(), scalar))
+            })
+            .collect::<Result<HashMap<String, Option<f64>>, String>>();
+        match bounds {
+            Ok(bounds) => Some(bounds),
+            Err(error) => {
+                self.report(
+                    DataProblemKind::UnknownBounds,
+                    format!("cannot check the bounds of '{}': {}", path, error),
+                );
+                Some(HashMap::new())
+            }
+        }
+    }
+
+    fn check_element(
+        &mut self,
+        value: &Value,
+        ty: &VarType,
+        Bounds { lower, upper, .. }: Bounds,
+        path: String,
+    ) {
+        match element_type(&ty.base) {
+            ElementType::Int if !value.is_i64() => self.report(
+                DataProblemKind::WrongType,
+                format!("'{}' should be an int, found {}", path, value),
+            ),
+            ElementType::Int | ElementType::Real => {
+                let Some(scalar) = json_scalar(value) else {
+                    return self.report(
+                        DataProblemKind::WrongType,
+                        format!("'{}' should be a real, found {}", path, value),
+                    );
+                };
+                if scalar.as_f64().is_nan() && (lower.is_some() || upper.is_some()) {
+                    return self.report(
+                        DataProblemKind::OutOfBounds,
+                        format!("'{}' is NaN, which is outside any bounds", path),
+                    );
+                }
+                if let Some(lower) = lower.filter(|lower| scalar.as_f64() < *lower) {
+                    self.report(
+                        DataProblemKind::OutOfBounds,
+                        format!("'{}' is {}, below the lower bound {}", path, scalar, lower),
+                    );
+                }
+                if let Some(upper) = upper.filter(|upper| scalar.as_f64() > *upper) {
+                    self.report(
+                        DataProblemKind::OutOfBounds,
+                        format!("'{}' is {}, above the upper bound {}", path, scalar, upper),
+                    );
+                }
+            }
+            ElementType::Complex => {
+                let is_complex = matches!(
+                    value,
+                    Value::Array(parts)
+                        if parts.len() == 2 && parts.iter().all(|part| json_scalar(part).is_some())
+                );
+                if !is_complex {
+                    self.report(
+                        DataProblemKind::WrongType,
+                        format!(
+                            "'{}' should be a complex number as [re, im], found {}",
+                            path, value
+                        ),
+                    );
+                }
+            }
+            ElementType::Tuple => {
+                let BaseType::Tuple(items) = &ty.base else {
+                    unreachable!("only tuples have tuple elements");
+                };
+                let Value::Object(fields) = value else {
+                    return self.report(
+                        DataProblemKind::WrongType,
+                        format!(
+                            "'{}' should be a tuple as an object with keys 1 to {}, found {}",
+                            path,
+                            items.len(),
+                            value
+                        ),
+                    );
+                };
+                for (index, item) in items.iter().enumerate() {
+                    let item_path = format!("{}.{}", path, index + 1);
+                    match fields.get(&(index + 1).to_string()) {
+                        Some(field) => self.check(field, item, &item_path),
+                        None => self.report(
+                            DataProblemKind::Missing,
+                            format!("'{}' is missing from the data", item_path),
+                        ),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `ty` without the bounds that are arrays, vectors or matrices in `data`,
+/// which are checked element by element instead.
+fn scalar_bounds_type(ty: &VarType, data: &Data) -> VarType {
+    let mut ty = ty.clone();
+    let constraints = &mut ty.constraints;
+    for bound in [
+        &mut constraints.lower,
+        &mut constraints.upper,
+        &mut constraints.offset,
+        &mut constraints.multiplier,
+    ] {
+        if bound
+            .as_ref()
+            .is_some_and(|expr| container_value(expr, data).is_some())
+        {
+            *bound = None;
+        }
+    }
+    ty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::declared_variables;
+    use crate::stan_model_block_type::StanModelBlockType;
+
+    fn validate(declarations: &[&str], data: &str) -> Vec<String> {
+        let variables = declared_variables(StanModelBlockType::Data, declarations);
+        validate_data(&variables, &parse_data(data).unwrap())
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn valid_data_has_no_problems() {
+        let problems = validate(
+            &[
+                "int<lower=0> N;",
+                "array[N] int<lower=0, upper=1> y;",
+                "matrix[N, 2] x;",
+                "cov_matrix[2] sigma;",
+                "complex z;",
+                "tuple(int, vector[2]) t;",
+            ],
+            r#"{"N": 2, "y": [0, 1], "x": [[1, 2.5], [3, "NaN"]], "sigma": [[1, 0], [0, 1]],
+                "z": [1, -1], "t": {"1": 3, "2": [0.5, 1]}, "unused": "ignored"}"#,
+        );
+
+        assert_eq!(problems, Vec::<String>::new());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let problems = validate(
+            &[
+                "int<lower=1> N;",
+                "array[N] int y;",
+                "vector<lower=0>[N] sigma;",
+                "real mu;",
+                "matrix[N, M] x;",
+            ],
+            r#"{"N": 3, "y": [1, 2.5, 3], "sigma": [1, -2, 3, 4], "x": []}"#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "<data>:2:14: 'y[2]' should be an int, found 2.5",
+                "<data>:3:20: 'sigma' should have 3 elements, found 4",
+                "<data>:4:6: 'mu' is missing from the data",
+                "<data>:5:14: cannot check the dimensions of 'x': 'M' is not defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn bounds_are_checked_against_other_data() {
+        let problems = validate(
+            &[
+                "real<lower=0> lo;",
+                "array[2] real<lower=lo, upper=lo + 1> y;",
+            ],
+            r#"{"lo": 1, "y": [0.5, 2.5]}"#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "<data>:2:39: 'y[1]' is 0.5, below the lower bound 1",
+                "<data>:2:39: 'y[2]' is 2.5, above the upper bound 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn bounds_that_cannot_be_checked_are_reported() {
+        let problems = validate(
+            &["real<lower=hi> x;", "array[2] real<lower=1, upper=0> y;"],
+            r#"{"x": 1, "y": [0.5, "two"]}"#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "<data>:1:16: cannot check the bounds of 'x': 'hi' is not defined",
+                "<data>:2:33: cannot check the bounds of 'y': the lower bound 1 is above the upper bound 0",
+                "<data>:2:33: 'y[2]' should be a real, found \"two\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn container_bounds_are_checked_element_by_element() {
+        let problems = validate(
+            &[
+                "int N;",
+                "vector[N] L;",
+                "vector<lower=L>[N] x;",
+                "row_vector[N] R;",
+                "array[2] row_vector<lower=-1, upper=R>[N] y;",
+                "vector<lower=L>[3] z;",
+            ],
+            r#"{"N": 2, "L": [0, "Inf"], "x": [-1, 2], "R": [1, 2], "y": [[0, 2], [-2, 3]], "z": [1, 2, 3]}"#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "<data>:3:20: 'x[1]' is -1, below the lower bound 0",
+                "<data>:5:43: 'y[2][1]' is -2, below the lower bound -1",
+                "<data>:5:43: 'y[2][2]' is 3, above the upper bound 2",
+                "<data>:6:20: cannot check the bounds of 'z': 'L' should have 3 elements, found 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn nan_is_outside_any_bounds() {
+        let problems = validate(
+            &["real<lower=0> x;", "real<upper=1> y;", "real z;"],
+            r#"{"x": "NaN", "y": "NaN", "z": "NaN"}"#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "<data>:1:15: 'x' is NaN, which is outside any bounds",
+                "<data>:2:15: 'y' is NaN, which is outside any bounds",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_cmdstan_spellings_of_non_finite_reals_are_accepted() {
+        let problems = validate(
+            &["array[4] real x;"],
+            r#"{"x": ["NaN", "-Inf", "+Infinity", "nan"]}"#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![r#"<data>:1:15: 'x[4]' should be a real, found "nan""#]
+        );
+    }
+
+    #[test]
+    fn data_must_be_an_object() {
+        assert!(parse_data("[1, 2]").is_err());
+        assert!(parse_data("{").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A position in a Stan source file. Lines and columns are 1-based.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLocation {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    pub fn new(path: impl Into<PathBuf>, line: usize, column: usize) -> SourceLocation {
+        SourceLocation {
+            path: path.into(),
+            line,
+            column,
+        }
+    }
+
+    /// The location of byte `offset` in `source`, which was read from `path`.
+    pub fn at_offset(path: impl Into<PathBuf>, source: &str, offset: usize) -> SourceLocation {
+        let before = &source[..offset.min(source.len())];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        SourceLocation::new(
+            path,
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+        )
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.path.display(), self.line, self.column)
+    }
+}
+
+/// Everything that can go wrong while reading, flattening or splitting a
+/// Stan program.
+#[derive(Debug)]
+pub enum StanJamError {
+    /// The file does not exist. `included_from` is set when the file was
+    /// reached through an `#include` directive.
+    MissingFile {
+        path: PathBuf,
+        included_from: Option<SourceLocation>,
+    },
+    /// The file exists but could not be read.
+    UnreadableFile {
+        path: PathBuf,
+        included_from: Option<SourceLocation>,
+        source: io::Error,
+    },
+    /// No folder in the search list contains the included file.
+    IncludeNotFound {
+        include: String,
+        searched: Vec<PathBuf>,
+        location: SourceLocation,
+    },
+    /// An `#include` line whose path could not be parsed.
+    MalformedInclude {
+        directive: String,
+        location: SourceLocation,
+    },
+    /// A `}` without a matching `{`, or a block left open at end of file.
+    UnbalancedBraces {
+        message: String,
+        location: SourceLocation,
+    },
+    /// Text at the top level that is not a known block header.
+    UnknownBlock {
+        name: String,
+        location: SourceLocation,
+    },
+    /// A block that appears more than once.
+    DuplicateBlock {
+        name: String,
+        location: SourceLocation,
+    },
+    /// A statement inside a block that does not parse.
+    SyntaxError {
+        message: String,
+        location: SourceLocation,
+    },
+    /// A declaration whose sizes cannot be evaluated against the data, such
+    /// as `vector[N] x;` when `N` is not given.
+    SizeError {
+        message: String,
+        location: SourceLocation,
+    },
+    /// A file that (directly or indirectly) includes itself. `chain` lists
+    /// every file on the include stack, ending with the repeated file.
+    IncludeCycle {
+        chain: Vec<String>,
+        location: SourceLocation,
+    },
+}
+
+impl StanJamError {
+    /// Classify an I/O error raised while reading `path`.
+    pub fn from_io(
+        path: &Path,
+        included_from: Option<SourceLocation>,
+        source: io::Error,
+    ) -> StanJamError {
+        match source.kind() {
+            io::ErrorKind::NotFound => StanJamError::MissingFile {
+                path: path.to_path_buf(),
+                included_from,
+            },
+            _ => StanJamError::UnreadableFile {
+                path: path.to_path_buf(),
+                included_from,
+                source,
+            },
+        }
+    }
+
+    /// Where in the source the error was raised, if it points at a line.
+    pub fn location(&self) -> Option<&SourceLocation> {
+        match self {
+            StanJamError::MissingFile { included_from, .. }
+            | StanJamError::UnreadableFile { included_from, .. } => included_from.as_ref(),
+            StanJamError::IncludeNotFound { location, .. }
+            | StanJamError::MalformedInclude { location, .. }
+            | StanJamError::UnbalancedBraces { location, .. }
+            | StanJamError::UnknownBlock { location, .. }
+            | StanJamError::DuplicateBlock { location, .. }
+            | StanJamError::SyntaxError { location, .. }
+            | StanJamError::SizeError { location, .. }
+            | StanJamError::IncludeCycle { location, .. } => Some(location),
+        }
+    }
+}
+
+impl fmt::Display for StanJamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StanJamError::MissingFile {
+                path,
+                included_from,
+            } => {
+                write!(f, "file '{}' not found", path.display())?;
+                if let Some(location) = included_from {
+                    write!(f, " (included from {})", location)?;
+                }
+                Ok(())
+            }
+            StanJamError::UnreadableFile {
+                path,
+                included_from,
+                source,
+            } => {
+                write!(f, "could not read '{}': {}", path.display(), source)?;
+                if let Some(location) = included_from {
+                    write!(f, " (included from {})", location)?;
+                }
+                Ok(())
+            }
+            StanJamError::IncludeNotFound {
+                include,
+                searched,
+                location,
+            } => {
+                let searched = searched
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(
+                    f,
+                    "{}: included file '{}' not found (searched: {})",
+                    location, include, searched
+                )
+            }
+            StanJamError::MalformedInclude {
+                directive,
+                location,
+            } => write!(
+                f,
+                "{}: malformed include directive '{}'",
+                location, directive
+            ),
+            StanJamError::UnbalancedBraces { message, location } => {
+                write!(f, "{}: unbalanced braces: {}", location, message)
+            }
+            StanJamError::UnknownBlock { name, location } => {
+                write!(f, "{}: unknown block '{}'", location, name)
+            }
+            StanJamError::DuplicateBlock { name, location } => {
+                write!(f, "{}: duplicate block '{}'", location, name)
+            }
+            StanJamError::SyntaxError { message, location } => {
+                write!(f, "{}: syntax error: {}", location, message)
+            }
+            StanJamError::SizeError { message, location } => {
+                write!(f, "{}: {}", location, message)
+            }
+            StanJamError::IncludeCycle { chain, location } => {
+                write!(
+                    f,
+                    "{}: include cycle detected: {}",
+                    location,
+                    chain.join(" -> ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for StanJamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StanJamError::UnreadableFile { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations_can_be_found_from_byte_offsets() {
+        let source = "model {\n  y ~ normal(0, 1);\n}";
+        assert_eq!(
+            SourceLocation::at_offset("model.stan", source, 10),
+            SourceLocation::new("model.stan", 2, 3)
+        );
+        assert_eq!(
+            SourceLocation::at_offset("model.stan", source, 0),
+            SourceLocation::new("model.stan", 1, 1)
+        );
+    }
+
+    #[test]
+    fn io_errors_are_classified_by_kind() {
+        let missing = StanJamError::from_io(
+            Path::new("model.stan"),
+            None,
+            io::Error::new(io::ErrorKind::NotFound, "gone"),
+        );
+        assert!(matches!(missing, StanJamError::MissingFile { .. }));
+        assert_eq!(missing.to_string(), "file 'model.stan' not found");
+
+        let unreadable = StanJamError::from_io(
+            Path::new("lib.stan"),
+            Some(SourceLocation::new("model.stan", 3, 5)),
+            io::Error::new(io::ErrorKind::PermissionDenied, "denied"),
+        );
+        assert!(matches!(unreadable, StanJamError::UnreadableFile { .. }));
+        assert_eq!(
+            unreadable.to_string(),
+            "could not read 'lib.stan': denied (included from model.stan:3:5)"
+        );
+        assert!(std::error::Error::source(&unreadable).is_some());
+    }
+
+    #[test]
+    fn errors_report_their_location() {
+        let error = StanJamError::IncludeNotFound {
+            include: "lib.stan".to_string(),
+            searched: vec![
+                PathBuf::from("./lib.stan"),
+                PathBuf::from("shared/lib.stan"),
+            ],
+            location: SourceLocation::new("model.stan", 2, 3),
+        };
+
+        assert_eq!(
+            error.location(),
+            Some(&SourceLocation::new("model.stan", 2, 3))
+        );
+        assert_eq!(
+            error.to_string(),
+            "model.stan:2:3: included file 'lib.stan' not found (searched: ./lib.stan, shared/lib.stan)"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Evaluation of size and bound expressions, such as `N * K` or `max(a, 1)`,
+//! against CmdStan-style JSON data values.
+
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+use crate::ast::{BinaryOp, Expr, ExprKind, Index, UnaryOp};
+
+/// Data values by variable name, as in a CmdStan JSON data file.
+pub type Data = Map<String, Value>;
+
+/// The value of a scalar expression.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Scalar {
+    Int(i64),
+    Real(f64),
+}
+
+impl Scalar {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Scalar::Int(value) => value as f64,
+            Scalar::Real(value) => value,
+        }
+    }
+
+    /// The value as a size, which must be a non-negative integer.
+    pub fn as_size(self) -> Result<usize, String> {
+        match self {
+            Scalar::Int(value) => {
+                usize::try_from(value).map_err(|_| format!("size {} is negative", value))
+            }
+            Scalar::Real(value) => Err(format!("size {} is not an integer", value)),
+        }
+    }
+
+    fn is_true(self) -> bool {
+        self.as_f64() != 0.0
+    }
+
+    fn from_bool(value: bool) -> Scalar {
+        Scalar::Int(value as i64)
+    }
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scalar::Int(value) => write!(f, "{}", value),
+            Scalar::Real(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// The scalar held by a JSON value: an integer, a real, or one of the strings
+/// CmdStan accepts for non-finite reals.
+pub fn json_scalar(value: &Value) -> Option<Scalar> {
+    match value {
+        Value::Number(number) => number
+            .as_i64()
+            .map(Scalar::Int)
+            .or_else(|| number.as_f64().map(Scalar::Real)),
+        Value::String(text) => match text.as_str() {
+            "NaN" => Some(Scalar::Real(f64::NAN)),
+            "Inf" | "+Inf" | "Infinity" | "+Infinity" => Some(Scalar::Real(f64::INFINITY)),
+            "-Inf" | "-Infinity" => Some(Scalar::Real(f64::NEG_INFINITY)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Evaluate `expr`, reading variables from `data`. Only the integer and real
+/// arithmetic that appears in sizes and bounds is supported: literals,
+/// variables, indexing into arrays, operators, the ternary operator and the
+/// functions `size`, `min`, `max` and `abs`.
+pub fn evaluate(expr: &Expr, data: &Data) -> Result<Scalar, String> {
+    match &expr.kind {
+        ExprKind::IntLiteral(text) => text
+            .parse()
+            .map(Scalar::Int)
+            .map_err(|_| format!("integer {} is too large", text)),
+        ExprKind::RealLiteral(text) => text
+            .parse()
+            .map(Scalar::Real)
+            .map_err(|_| format!("'{}' is not a number", text)),
+        ExprKind::Paren(inner) => evaluate(inner, data),
+        ExprKind::Variable(_) | ExprKind::Index { .. } => {
+            let value = lookup(expr, data)?;
+            json_scalar(value).ok_or_else(|| format!("'{}' is not a scalar", expr))
+        }
+        ExprKind::Unary { op, operand } => {
+            let operand = evaluate(operand, data)?;
+            Ok(match (op, operand) {
+                (UnaryOp::Negate, Scalar::Int(value)) => Scalar::Int(-value),
+                (UnaryOp::Negate, Scalar::Real(value)) => Scalar::Real(-value),
+                (UnaryOp::Plus, operand) => operand,
+                (UnaryOp::Not, operand) => Scalar::from_bool(!operand.is_true()),
+            })
+        }
+        ExprKind::Binary { op, left, right } => {
+            binary(*op, evaluate(left, data)?, evaluate(right, data)?)
+        }
+        ExprKind::Ternary {
+            condition,
+            if_true,
+            if_false,
+        } => {
+            if evaluate(condition, data)?.is_true() {
+                evaluate(if_true, data)
+            } else {
+                evaluate(if_false, data)
+            }
+        }
+        ExprKind::Call { name, args, .. } => call(name, args, data),
+        _ => Err(format!("cannot evaluate '{}'", expr)),
+    }
+}
+
+/// The value in `data` of `expr`, a variable or an element of one, if it is
+/// an array, vector or matrix rather than a scalar.
+pub fn container_value<'a>(expr: &Expr, data: &'a Data) -> Option<&'a Value> {
+    lookup(expr, data).ok().filter(|value| value.is_array())
+}
+
+/// The JSON value of a variable, or of an element of an array variable.
+fn lookup<'a>(expr: &Expr, data: &'a Data) -> Result<&'a Value, String> {
+    match &expr.kind {
+        ExprKind::Variable(name) => data
+            .get(name)
+            .ok_or_else(|| format!("'{}' is not defined", name)),
+        ExprKind::Index { base, indices } => {
+            let mut value = lookup(base, data)?;
+            for index in indices {
+                let Index::Single(position) = index else {
+                    return Err(format!("cannot evaluate '{}'", expr));
+                };
+                let position = evaluate(position, data)?;
+                value = match (value, position) {
+                    (Value::Array(items), Scalar::Int(position)) if position >= 1 => {
+                        items.get(position as usize - 1).ok_or_else(|| {
+                            format!("index {} is out of range in '{}'", position, expr)
+                        })?
+                    }
+                    _ => return Err(format!("cannot evaluate '{}'", expr)),
+                };
+            }
+            Ok(value)
+        }
+        _ => Err(format!("cannot evaluate '{}'", expr)),
+    }
+}
+
+fn binary(op: BinaryOp, left: Scalar, right: Scalar) -> Result<Scalar, String> {
+    use Scalar::{Int, Real};
+
+    let real = |value: f64| Ok(Real(value));
+    match (op, left, right) {
+        (BinaryOp::Or, _, _) => Ok(Scalar::from_bool(left.is_true() || right.is_true())),
+        (BinaryOp::And, _, _) => Ok(Scalar::from_bool(left.is_true() && right.is_true())),
+        (BinaryOp::Equal, _, _) => Ok(Scalar::from_bool(left.as_f64() == right.as_f64())),
+        (BinaryOp::NotEqual, _, _) => Ok(Scalar::from_bool(left.as_f64() != right.as_f64())),
+        (BinaryOp::Less, _, _) => Ok(Scalar::from_bool(left.as_f64() < right.as_f64())),
+        (BinaryOp::LessEqual, _, _) => Ok(Scalar::from_bool(left.as_f64() <= right.as_f64())),
+        (BinaryOp::Greater, _, _) => Ok(Scalar::from_bool(left.as_f64() > right.as_f64())),
+        (BinaryOp::GreaterEqual, _, _) => Ok(Scalar::from_bool(left.as_f64() >= right.as_f64())),
+        (BinaryOp::Add, Int(left), Int(right)) => checked(left.checked_add(right)),
+        (BinaryOp::Subtract, Int(left), Int(right)) => checked(left.checked_sub(right)),
+        (BinaryOp::Multiply, Int(left), Int(right)) => checked(left.checked_mul(right)),
+        (BinaryOp::Divide | BinaryOp::IntDivide, Int(_), Int(0))
+        | (BinaryOp::Modulo, Int(_), Int(0)) => Err("division by zero".to_string()),
+        (BinaryOp::Divide | BinaryOp::IntDivide, Int(left), Int(right)) => Ok(Int(left / right)),
+        (BinaryOp::Modulo, Int(left), Int(right)) => Ok(Int(left % right)),
+        (BinaryOp::Add, _, _) => real(left.as_f64() + right.as_f64()),
+        (BinaryOp::Subtract, _, _) => real(left.as_f64() - right.as_f64()),
+        (BinaryOp::Multiply | BinaryOp::ElementwiseMultiply, _, _) => {
+            real(left.as_f64() * right.as_f64())
+        }
+        (BinaryOp::Divide | BinaryOp::ElementwiseDivide, _, _) => {
+            real(left.as_f64() / right.as_f64())
+        }
+        (BinaryOp::Power | BinaryOp::ElementwisePower, _, _) => {
+            real(left.as_f64().powf(right.as_f64()))
+        }
+        (op, _, _) => Err(format!(
+            "cannot apply '{}' to {} and {}",
+            op.symbol(),
+            left,
+            right
+        )),
+    }
+}
+
+fn checked(value: Option<i64>) -> Result<Scalar, String> {
+    value
+        .map(Scalar::Int)
+        .ok_or_else(|| "integer overflow".to_string())
+}
+
+fn call(name: &str, args: &[Expr], data: &Data) -> Result<Scalar, String> {
+    match (name, args) {
+        ("size", [array]) => match lookup(array, data)? {
+            Value::Array(items) => Ok(Scalar::Int(items.len() as i64)),
+            _ => Err(format!("'{}' is not an array", array)),
+        },
+        ("abs", [value]) => Ok(match evaluate(value, data)? {
+            Scalar::Int(value) => Scalar::Int(value.abs()),
+            Scalar::Real(value) => Scalar::Real(value.abs()),
+        }),
+        ("min" | "max", [left, right]) => {
+            let (left, right) = (evaluate(left, data)?, evaluate(right, data)?);
+            let left_wins = (left.as_f64() < right.as_f64()) == (name == "min");
+            Ok(match (left, right) {
+                (Scalar::Int(_), Scalar::Int(_)) if left_wins => left,
+                (Scalar::Int(_), Scalar::Int(_)) => right,
+                _ if left_wins => Scalar::Real(left.as_f64()),
+                _ => Scalar::Real(right.as_f64()),
+            })
+        }
+        _ => Err(format!("cannot evaluate a call to '{}'", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_expression;
+    use serde_json::json;
+
+    fn eval(source: &str, data: Value) -> Result<Scalar, String> {
+        let Value::Object(data) = data else {
+            panic!("data must be an object");
+        };
+        evaluate(&parse_expression(source).unwrap(), &data)
+    }
+
+    #[test]
+    fn can_evaluate_size_expressions() {
+        let data = json!({"N": 10, "K": 3, "sizes": [4, 5], "y": [[1, 2], [3]]});
+
+        assert_eq!(eval("N * K + 1", data.clone()), Ok(Scalar::Int(31)));
+        assert_eq!(eval("N / 3", data.clone()), Ok(Scalar::Int(3)));
+        assert_eq!(eval("sizes[2] - 1", data.clone()), Ok(Scalar::Int(4)));
+        assert_eq!(eval("max(N, K)", data.clone()), Ok(Scalar::Int(10)));
+        assert_eq!(eval("size(y)", data.clone()), Ok(Scalar::Int(2)));
+        assert_eq!(eval("N > K ? K : N", data.clone()), Ok(Scalar::Int(3)));
+        assert_eq!(eval("-0.5 * N", data), Ok(Scalar::Real(-5.0)));
+    }
+
+    #[test]
+    fn unknown_variables_and_functions_are_reported() {
+        let data = json!({"y": [1.5]});
+
+        assert_eq!(
+            eval("M + 1", data.clone()),
+            Err("'M' is not defined".to_string())
+        );
+        assert_eq!(
+            eval("y", data.clone()),
+            Err("'y' is not a scalar".to_string())
+        );
+        assert_eq!(
+            eval("rows(y)", data),
+            Err("cannot evaluate a call to 'rows'".to_string())
+        );
+    }
+
+    #[test]
+    fn non_finite_strings_are_reals() {
+        assert!(json_scalar(&json!("NaN")).unwrap().as_f64().is_nan());
+        assert_eq!(
+            json_scalar(&json!("-Inf")),
+            Some(Scalar::Real(f64::NEG_INFINITY))
+        );
+        assert_eq!(json_scalar(&json!("inf")), None);
+        assert_eq!(json_scalar(&json!(2.5)), Some(Scalar::Real(2.5)));
+        assert_eq!(json_scalar(&json!("two")), None);
+    }
+}
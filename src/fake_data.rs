@@ -0,0 +1,492 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde_json::{json, Value};
+
+use crate::ast::{BaseType, VarType};
+use crate::eval::Data;
+use crate::interface::{json_shape, nested_array, Bounds, Variable};
+
+/// Random CmdStan JSON data for `variables` that satisfies every
+/// declaration: shapes, int or real elements, `lower`/`upper` bounds and the
+/// structure of constrained types such as `simplex` or `cov_matrix`.
+///
+/// Values in `given`, such as sizes chosen by the caller, are used as they
+/// are. Other ints are drawn from their bounds, or from 1 to 10 when
+/// unbounded, so sizes that are not given are small. The same `seed` always
+/// gives the same data, on every platform and with every release.
+pub fn fake_data(variables: &[Variable], given: &Data, seed: u64) -> Result<Data, String> {
+    let mut generator = Generator {
+        rng: ChaCha8Rng::seed_from_u64(seed),
+    };
+    let mut data = Data::new();
+    for variable in variables {
+        let value = match given.get(&variable.name) {
+            Some(value) => value.clone(),
+            None => generator.value(&variable.ty, &data).map_err(|error| {
+                format!(
+                    "{}: cannot generate '{}': {}",
+                    variable.location, variable.name, error
+                )
+            })?,
+        };
+        data.insert(variable.name.clone(), value);
+    }
+    Ok(data)
+}
+
+struct Generator {
+    rng: ChaCha8Rng,
+}
+
+impl Generator {
+    fn value(&mut self, ty: &VarType, data: &Data) -> Result<Value, String> {
+        let shape = json_shape(ty, data)?;
+        let (array_shape, base_shape) = shape.split_at(ty.array_dims.len());
+        let bounds = Bounds::new(ty, data)?;
+        if let Some(bound) = [bounds.lower, bounds.upper]
+            .into_iter()
+            .flatten()
+            .find(|bound| !bound.is_finite())
+        {
+            return Err(format!("the bound {} is not finite", bound));
+        }
+        let elements = (0..array_shape.iter().product::<usize>())
+            .map(|_| self.base(ty, base_shape, bounds, data))
+            .collect::<Result<Vec<Value>, String>>()?;
+        Ok(nested_array(elements, array_shape))
+    }
+
+    fn base(
+        &mut self,
+        ty: &VarType,
+        shape: &[usize],
+        bounds: Bounds,
+        data: &Data,
+    ) -> Result<Value, String> {
+        Ok(match (&ty.base, shape) {
+            (BaseType::Int, []) => json!(self.int(bounds)?),
+            (BaseType::Real, []) => json!(self.real(bounds)),
+            (BaseType::Complex, []) => self.complex(),
+            (BaseType::Vector | BaseType::RowVector, &[length]) => {
+                json!(self.reals(length, bounds))
+            }
+            (BaseType::Matrix, &[rows, columns]) => {
+                json!(self.matrix(rows, columns, |generator| generator.real(bounds)))
+            }
+            (BaseType::ComplexVector | BaseType::ComplexRowVector, &[length]) => {
+                Value::Array((0..length).map(|_| self.complex()).collect())
+            }
+            (BaseType::ComplexMatrix, &[rows, columns]) => Value::Array(
+                (0..rows)
+                    .map(|_| Value::Array((0..columns).map(|_| self.complex()).collect()))
+                    .collect(),
+            ),
+            (BaseType::Simplex, &[length]) => json!(self.simplex(length)),
+            (BaseType::UnitVector, &[length]) => {
+                let vector = (0..length).map(|_| self.normal()).collect::<Vec<f64>>();
+                let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+                json!(vector.iter().map(|x| x / norm).collect::<Vec<f64>>())
+            }
+            (BaseType::SumToZeroVector, &[length]) => {
+                json!(centered(self.reals(length, UNBOUNDED)))
+            }
+            (BaseType::SumToZeroMatrix, &[rows, columns]) => {
+                json!(self.sum_to_zero_matrix(rows, columns))
+            }
+            (BaseType::Ordered, &[length]) => {
+                let mut vector = self.reals(length, UNBOUNDED);
+                vector.sort_by(f64::total_cmp);
+                json!(vector)
+            }
+            (BaseType::PositiveOrdered, &[length]) => {
+                let vector = (0..length)
+                    .scan(0.0, |total, _| {
+                        *total += self.exponential() + 1e-3;
+                        Some(*total)
+                    })
+                    .collect::<Vec<f64>>();
+                json!(vector)
+            }
+            (BaseType::CholeskyFactorCorr, &[size, _]) => json!(self.cholesky_factor_corr(size)),
+            (BaseType::CholeskyFactorCov, &[rows, columns]) => {
+                if rows < columns {
+                    return Err(format!(
+                        "a Cholesky factor needs at least as many rows as columns, not {} by {}",
+                        rows, columns
+                    ));
+                }
+                json!(self.cholesky_factor_cov(rows, columns))
+            }
+            (BaseType::CorrMatrix, &[size, _]) => {
+                let factor = self.cholesky_factor_corr(size);
+                let mut matrix = multiply_by_transpose(&factor);
+                (0..size).for_each(|i| matrix[i][i] = 1.0);
+                json!(matrix)
+            }
+            (BaseType::CovMatrix, &[size, _]) => {
+                let factor = self.matrix(size, size, Generator::normal);
+                let mut matrix = multiply_by_transpose(&factor);
+                (0..size).for_each(|i| matrix[i][i] += size as f64);
+                json!(matrix)
+            }
+            (BaseType::ColumnStochasticMatrix, &[rows, columns]) => {
+                let columns = (0..columns)
+                    .map(|_| self.simplex(rows))
+                    .collect::<Vec<Vec<f64>>>();
+                json!(transpose(&columns, rows))
+            }
+            (BaseType::RowStochasticMatrix, &[rows, columns]) => json!((0..rows)
+                .map(|_| self.simplex(columns))
+                .collect::<Vec<Vec<f64>>>()),
+            (BaseType::Tuple(items), []) => Value::Object(
+                items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| Ok(((index + 1).to_string(), self.value(item, data)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+            _ => return Err(format!("'{}' has the wrong number of sizes", ty)),
+        })
+    }
+
+    /// An int within `bounds` and within the 32-bit range of Stan's ints.
+    fn int(&mut self, bounds: Bounds) -> Result<i64, String> {
+        const MIN: f64 = i32::MIN as f64;
+        const MAX: f64 = i32::MAX as f64;
+        let (lower, upper) = match (bounds.lower, bounds.upper) {
+            (Some(lower), Some(upper)) => (lower.ceil(), upper.floor()),
+            (Some(lower), None) => (lower.ceil(), (lower.ceil() + 9.0).min(MAX)),
+            (None, Some(upper)) => ((upper.floor() - 9.0).max(MIN), upper.floor()),
+            (None, None) => (1.0, 10.0),
+        };
+        if lower > MAX || upper < MIN {
+            return Err(format!(
+                "the bounds leave out every int from {} to {}",
+                MIN, MAX
+            ));
+        }
+        if lower > upper {
+            return Err(format!("there is no int between {} and {}", lower, upper));
+        }
+        Ok(self
+            .rng
+            .gen_range(lower.max(MIN) as i64..=upper.min(MAX) as i64))
+    }
+
+    /// A real within `bounds`, which must be finite. A draw between two
+    /// bounds weighs each bound separately, so that bounds as far apart as
+    /// `-1e308` and `1e308` do not overflow.
+    fn real(&mut self, bounds: Bounds) -> f64 {
+        match (bounds.lower, bounds.upper) {
+            (Some(lower), Some(upper)) if lower == upper => lower,
+            (Some(lower), Some(upper)) => {
+                let weight = self.rng.gen::<f64>();
+                (lower * (1.0 - weight) + upper * weight).clamp(lower, upper)
+            }
+            (Some(lower), None) => lower + self.exponential(),
+            (None, Some(upper)) => upper - self.exponential(),
+            (None, None) => self.normal(),
+        }
+    }
+
+    fn reals(&mut self, length: usize, bounds: Bounds) -> Vec<f64> {
+        (0..length).map(|_| self.real(bounds)).collect()
+    }
+
+    fn complex(&mut self) -> Value {
+        json!([self.normal(), self.normal()])
+    }
+
+    /// A standard normal draw, by the Box-Muller transform.
+    fn normal(&mut self) -> f64 {
+        let radius = (-2.0 * (1.0 - self.rng.gen::<f64>()).ln()).sqrt();
+        radius * (std::f64::consts::TAU * self.rng.gen::<f64>()).cos()
+    }
+
+    fn exponential(&mut self) -> f64 {
+        -(1.0 - self.rng.gen::<f64>()).ln()
+    }
+
+    fn matrix(
+        &mut self,
+        rows: usize,
+        columns: usize,
+        mut element: impl FnMut(&mut Generator) -> f64,
+    ) -> Vec<Vec<f64>> {
+        (0..rows)
+            .map(|_| (0..columns).map(|_| element(self)).collect())
+            .collect()
+    }
+
+    fn simplex(&mut self, length: usize) -> Vec<f64> {
+        let weights = (0..length)
+            .map(|_| self.exponential() + 1e-3)
+            .collect::<Vec<f64>>();
+        let total = weights.iter().sum::<f64>();
+        weights.iter().map(|weight| weight / total).collect()
+    }
+
+    /// A matrix whose rows and columns all sum to zero.
+    fn sum_to_zero_matrix(&mut self, rows: usize, columns: usize) -> Vec<Vec<f64>> {
+        let matrix = self.matrix(rows, columns, Generator::normal);
+        let rows_centered = matrix.into_iter().map(centered).collect::<Vec<_>>();
+        let columns_centered = transpose(&rows_centered, columns)
+            .into_iter()
+            .map(centered)
+            .collect::<Vec<_>>();
+        transpose(&columns_centered, rows)
+    }
+
+    /// A lower triangular matrix with a positive diagonal and rows of unit
+    /// length.
+    fn cholesky_factor_corr(&mut self, size: usize) -> Vec<Vec<f64>> {
+        (0..size)
+            .map(|i| {
+                let mut row = (0..size)
+                    .map(|j| if j < i { self.normal() } else { 0.0 })
+                    .collect::<Vec<f64>>();
+                row[i] = self.normal().abs() + 0.1;
+                let norm = row.iter().map(|x| x * x).sum::<f64>().sqrt();
+                row.iter().map(|x| x / norm).collect()
+            })
+            .collect()
+    }
+
+    /// A lower trapezoidal matrix with a positive diagonal.
+    fn cholesky_factor_cov(&mut self, rows: usize, columns: usize) -> Vec<Vec<f64>> {
+        (0..rows)
+            .map(|i| {
+                (0..columns)
+                    .map(|j| match j.cmp(&i) {
+                        std::cmp::Ordering::Less => self.normal(),
+                        std::cmp::Ordering::Equal => self.exponential() + 0.1,
+                        std::cmp::Ordering::Greater => 0.0,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+const UNBOUNDED: Bounds = Bounds {
+    lower: None,
+    upper: None,
+    offset: None,
+    multiplier: None,
+};
+
+/// `values` less their mean, so that they sum to zero.
+fn centered(values: Vec<f64>) -> Vec<f64> {
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    values.iter().map(|value| value - mean).collect()
+}
+
+fn transpose(matrix: &[Vec<f64>], rows: usize) -> Vec<Vec<f64>> {
+    (0..rows)
+        .map(|i| matrix.iter().map(|column| column[i]).collect())
+        .collect()
+}
+
+/// `matrix * matrix'`, made exactly symmetric.
+fn multiply_by_transpose(matrix: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let size = matrix.len();
+    let mut product = vec![vec![0.0; size]; size];
+    for i in 0..size {
+        for j in 0..=i {
+            let dot = matrix[i].iter().zip(&matrix[j]).map(|(a, b)| a * b).sum();
+            product[i][j] = dot;
+            product[j][i] = dot;
+        }
+    }
+    product
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_validation::validate_data;
+    use crate::interface::declared_variables;
+    use crate::stan_model_block_type::StanModelBlockType;
+
+    fn variables(declarations: &[&str]) -> Vec<Variable> {
+        declared_variables(StanModelBlockType::Data, declarations)
+    }
+
+    fn given(data: Value) -> Data {
+        data.as_object().cloned().unwrap()
+    }
+
+    fn matrix(value: &Value) -> Vec<Vec<f64>> {
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| {
+                row.as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|x| x.as_f64().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fake_data_passes_validation() {
+        let variables = variables(&[
+            "int<lower=1> N;",
+            "int<lower=0, upper=N> K;",
+            "array[N] int<lower=0, upper=1> y;",
+            "matrix<lower=-1, upper=1>[N, 2] x;",
+            "array[2] vector<lower=0>[K] v;",
+            "real<upper=-3> r;",
+            "complex_vector[2] z;",
+            "tuple(int<lower=5>, array[N] real) t;",
+        ]);
+
+        let data = fake_data(&variables, &given(json!({"N": 50})), 1).unwrap();
+
+        assert_eq!(data["N"], 50);
+        assert_eq!(data["y"].as_array().unwrap().len(), 50);
+        assert_eq!(validate_data(&variables, &data), vec![]);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_data() {
+        let variables = variables(&["int N;", "vector[N] y;"]);
+
+        let data = |seed| fake_data(&variables, &Data::new(), seed).unwrap();
+
+        assert_eq!(data(7), data(7));
+        assert_ne!(data(7), data(8));
+    }
+
+    #[test]
+    fn the_data_for_a_seed_does_not_change() {
+        let variables = variables(&["int<lower=2, upper=4> N;", "vector<lower=0>[N] y;"]);
+
+        let data = fake_data(&variables, &Data::new(), 7).unwrap();
+
+        assert_eq!(
+            Value::Object(data),
+            json!({"N": 2, "y": [0.1839100531129716, 1.2183291250354422]})
+        );
+    }
+
+    #[test]
+    fn constrained_types_are_valid() {
+        let variables = variables(&[
+            "simplex[4] theta;",
+            "ordered[5] cuts;",
+            "positive_ordered[3] p;",
+            "unit_vector[3] u;",
+            "corr_matrix[3] omega;",
+            "cholesky_factor_corr[3] l_omega;",
+            "cov_matrix[3] sigma;",
+            "cholesky_factor_cov[4, 3] l_sigma;",
+            "sum_to_zero_vector[4] s;",
+        ]);
+
+        let data = fake_data(&variables, &Data::new(), 3).unwrap();
+        let vector = |name: &str| {
+            data[name]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x.as_f64().unwrap())
+                .collect::<Vec<f64>>()
+        };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        let theta = vector("theta");
+        assert!(theta.iter().all(|x| *x > 0.0) && close(theta.iter().sum(), 1.0));
+        assert!(vector("cuts").windows(2).all(|pair| pair[0] < pair[1]));
+        let p = vector("p");
+        assert!(p[0] > 0.0 && p.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(close(vector("u").iter().map(|x| x * x).sum(), 1.0));
+        assert!(close(vector("s").iter().sum(), 0.0));
+
+        let omega = matrix(&data["omega"]);
+        for (i, row) in omega.iter().enumerate() {
+            assert_eq!(row[i], 1.0);
+            for (j, value) in row.iter().enumerate() {
+                assert_eq!(*value, omega[j][i]);
+                assert!(value.abs() <= 1.0 + 1e-12);
+            }
+        }
+        let l_omega = matrix(&data["l_omega"]);
+        for (i, row) in l_omega.iter().enumerate() {
+            assert!(row[i] > 0.0 && row[i + 1..].iter().all(|x| *x == 0.0));
+            assert!(close(row.iter().map(|x| x * x).sum(), 1.0));
+        }
+        let sigma = matrix(&data["sigma"]);
+        assert!((0..3).all(|i| sigma[i][i] > 0.0 && sigma[i][(i + 1) % 3] == sigma[(i + 1) % 3][i]));
+        let l_sigma = matrix(&data["l_sigma"]);
+        assert_eq!((l_sigma.len(), l_sigma[0].len()), (4, 3));
+        assert!(
+            (0..3).all(|i| l_sigma[i][i] > 0.0 && l_sigma[i][i + 1..].iter().all(|x| *x == 0.0))
+        );
+    }
+
+    #[test]
+    fn impossible_declarations_are_reported() {
+        let variables = variables(&["real<lower=1, upper=0> x;"]);
+
+        assert_eq!(
+            fake_data(&variables, &Data::new(), 0),
+            Err(
+                "<data>:1:24: cannot generate 'x': the lower bound 1 is above the upper bound 0"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn bounds_far_apart_give_values_between_them() {
+        let variables = variables(&[
+            "real<lower=-1e308, upper=1e308> x;",
+            "vector<lower=-1.7e308, upper=1.7e308>[3] y;",
+            "int<lower=-1e300, upper=-2147483640> n;",
+        ]);
+
+        let data = fake_data(&variables, &Data::new(), 5).unwrap();
+
+        assert!(data["x"].as_f64().unwrap().abs() <= 1e308);
+        assert!(data["y"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|y| y.as_f64().unwrap().is_finite()));
+        assert!(data["n"].as_i64().unwrap() <= -2147483640);
+        assert_eq!(validate_data(&variables, &data), vec![]);
+    }
+
+    #[test]
+    fn bounds_that_no_value_can_meet_are_reported() {
+        let error = |declaration| {
+            fake_data(&variables(&[declaration]), &Data::new(), 0)
+                .unwrap_err()
+                .split_once("': ")
+                .unwrap()
+                .1
+                .to_string()
+        };
+
+        assert_eq!(
+            error("int<lower=1e300> n;"),
+            "the bounds leave out every int from -2147483648 to 2147483647"
+        );
+        assert_eq!(
+            error("real<lower=1e308 * 10> x;"),
+            "the bound inf is not finite"
+        );
+        assert_eq!(
+            error("real<upper=-1e308 * 10> x;"),
+            "the bound -inf is not finite"
+        );
+        assert_eq!(
+            error("real<lower=0, upper=(1e308 * 10) - (1e308 * 10)> x;"),
+            "the bound NaN is not finite"
+        );
+    }
+}
@@ -0,0 +1,622 @@
+use std::path::Path;
+
+use crate::ast::{Expr, FunctionDef, Stmt, StmtKind, Truncation};
+use crate::error::{SourceLocation, StanJamError};
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::parser::parse_statements_with_recovery;
+use crate::source_map::SourceMap;
+use crate::stan_model_block_type::StanModelBlockType;
+use crate::stan_source_parser::block_splitter::{
+    is_program, program_blocks, split_blocks_with_recovery,
+};
+
+/// Layout options for `format_source`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FormatConfig {
+    /// Spaces per level of nesting.
+    pub indent_width: usize,
+    /// Statements longer than this are broken after the commas of their
+    /// outermost argument list, where that helps.
+    pub max_line_length: usize,
+}
+
+impl Default for FormatConfig {
+    fn default() -> FormatConfig {
+        FormatConfig {
+            indent_width: 2,
+            max_line_length: 80,
+        }
+    }
+}
+
+/// Format a Stan program, or a fragment of statements such as an included
+/// file of function definitions.
+///
+/// Blocks are written in canonical order, statements are re-indented, each
+/// declared variable gets its own line and operators are spaced
+/// consistently. Comments are kept: a comment on the same line as the end of
+/// a statement stays there, and comments inside a simple statement move to
+/// the line above it. Single blank lines between statements are kept.
+///
+/// Source that does not parse is not formatted; every structural and syntax
+/// error is returned instead, located in `path`.
+pub fn format_source(
+    source: &str,
+    path: &Path,
+    config: &FormatConfig,
+) -> Result<String, Vec<StanJamError>> {
+    let lines = if is_program(source) {
+        format_program(source, path, config)?
+    } else {
+        format_fragment(source, path, config)?
+    };
+
+    let mut formatted = lines.join("\n");
+    formatted.push('\n');
+    Ok(formatted)
+}
+
+/// Whether `source` is already formatted according to `config`.
+pub fn is_formatted(
+    source: &str,
+    path: &Path,
+    config: &FormatConfig,
+) -> Result<bool, Vec<StanJamError>> {
+    Ok(format_source(source, path, config)? == source)
+}
+
+fn format_fragment(
+    source: &str,
+    path: &Path,
+    config: &FormatConfig,
+) -> Result<Vec<String>, Vec<StanJamError>> {
+    let (statements, errors) = parse_statements_with_recovery(source);
+    if !errors.is_empty() {
+        return Err(errors
+            .into_iter()
+            .map(|error| StanJamError::SyntaxError {
+                location: SourceLocation::at_offset(path, source, error.span.start),
+                message: error.message,
+            })
+            .collect());
+    }
+
+    let mut printer = Printer::new(source, config);
+    printer.statements(&statements, 0);
+    printer.emit_comments_before(source.len(), 0);
+    Ok(printer.lines)
+}
+
+fn format_program(
+    source: &str,
+    path: &Path,
+    config: &FormatConfig,
+) -> Result<Vec<String>, Vec<StanJamError>> {
+    let line_count = source.split('\n').count();
+    let (model, mut errors) =
+        split_blocks_with_recovery(source, &SourceMap::identity(path, line_count));
+    errors.extend(model.syntax_errors());
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let (mut blocks, trailing) = program_blocks(source);
+    blocks.sort_by_key(|block| {
+        StanModelBlockType::ALL
+            .iter()
+            .position(|block_type| *block_type == block.block_type)
+    });
+
+    let mut lines = Vec::new();
+    for block in blocks {
+        lines.extend(
+            block
+                .leading
+                .iter()
+                .flat_map(|comment| comment_lines(source, comment, "")),
+        );
+        lines.push(format!("{} {{", block.block_type.name()));
+
+        let body = &source[block.body_start..block.body_end];
+        let (statements, _) = parse_statements_with_recovery(body);
+        let mut printer = Printer::new(body, config);
+        printer.statements(&statements, 1);
+        printer.emit_comments_before(body.len(), 1);
+        lines.extend(printer.lines);
+
+        lines.push("}".to_string());
+    }
+    lines.extend(
+        trailing
+            .iter()
+            .flat_map(|comment| comment_lines(source, comment, "")),
+    );
+    Ok(lines)
+}
+
+/// The lines of the comment `token`, each prefixed with `indent`.
+/// Continuation lines of a block comment keep their indentation relative to
+/// the start of the comment.
+fn comment_lines(source: &str, token: &Token, indent: &str) -> Vec<String> {
+    let line_start = source[..token.span.start]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let column = token.span.start - line_start;
+    token
+        .text(source)
+        .trim_end()
+        .split('\n')
+        .enumerate()
+        .map(|(index, line)| {
+            if index == 0 {
+                return format!("{}{}", indent, line);
+            }
+            let leading = line.len() - line.trim_start().len();
+            format!("{}{}", indent, &line[leading.min(column)..])
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Writes statements as indented lines, weaving in the comments of the
+/// source they were parsed from.
+struct Printer<'a> {
+    source: &'a str,
+    config: &'a FormatConfig,
+    comments: Vec<Token>,
+    next_comment: usize,
+    lines: Vec<String>,
+}
+
+impl<'a> Printer<'a> {
+    fn new(source: &'a str, config: &'a FormatConfig) -> Printer<'a> {
+        Printer {
+            source,
+            config,
+            comments: tokenize(source)
+                .into_iter()
+                .filter(Token::is_comment)
+                .collect(),
+            next_comment: 0,
+            lines: Vec::new(),
+        }
+    }
+
+    fn indent(&self, level: usize) -> String {
+        " ".repeat(level * self.config.indent_width)
+    }
+
+    fn next_comment_start(&self) -> Option<usize> {
+        self.comments
+            .get(self.next_comment)
+            .map(|comment| comment.span.start)
+    }
+
+    /// Write every comment that starts before `position` on its own lines.
+    fn emit_comments_before(&mut self, position: usize, level: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.span.start >= position {
+                break;
+            }
+            let indent = self.indent(level);
+            self.lines
+                .extend(comment_lines(self.source, comment, &indent));
+            self.next_comment += 1;
+        }
+    }
+
+    /// Keep a single-line comment that follows `end` on the same source line
+    /// at the end of the last written line.
+    fn trailing_comment(&mut self, end: usize) {
+        let Some(comment) = self.comments.get(self.next_comment) else {
+            return;
+        };
+        let text = comment.text(self.source);
+        if comment.span.start < end
+            || self.source[end..comment.span.start].contains('\n')
+            || !self.source[end..comment.span.start].trim().is_empty()
+            || text.contains('\n')
+        {
+            return;
+        }
+        if let Some(line) = self.lines.last_mut() {
+            line.push(' ');
+            line.push_str(text.trim_end());
+            self.next_comment += 1;
+        }
+    }
+
+    fn statements(&mut self, statements: &[Stmt], level: usize) {
+        let mut previous_end = None;
+        for statement in statements {
+            let start = self
+                .next_comment_start()
+                .map_or(statement.span.start, |comment| {
+                    comment.min(statement.span.start)
+                });
+            if let Some(end) = previous_end {
+                if self.source[end..start].matches('\n').count() > 1 {
+                    self.lines.push(String::new());
+                }
+            }
+            self.emit_comments_before(statement.span.start, level);
+            self.statement(statement, level, "");
+            self.trailing_comment(statement.span.end);
+            previous_end = Some(statement.span.end);
+        }
+    }
+
+    /// Write a statement whose first line starts with `prefix`, which is how
+    /// `} else` joins the end of an `if` branch.
+    fn statement(&mut self, statement: &Stmt, level: usize, prefix: &str) {
+        let text = match &statement.kind {
+            StmtKind::Block(statements) => {
+                self.braced(prefix.trim_end(), statements, statement, level);
+                return;
+            }
+            StmtKind::For {
+                variable,
+                lower,
+                upper,
+                body,
+            } => {
+                let header = format!("{}for ({} in {}:{})", prefix, variable, lower, upper);
+                self.body(&header, body, level);
+                return;
+            }
+            StmtKind::ForEach {
+                variable,
+                collection,
+                body,
+            } => {
+                let header = format!("{}for ({} in {})", prefix, variable, collection);
+                self.body(&header, body, level);
+                return;
+            }
+            StmtKind::While { condition, body } => {
+                let header = format!("{}while ({})", prefix, condition);
+                self.body(&header, body, level);
+                return;
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let header = format!("{}if ({})", prefix, condition);
+                let braced = self.body(&header, then_branch, level);
+                if let Some(else_branch) = else_branch {
+                    let prefix = if braced {
+                        self.lines.pop();
+                        "} else"
+                    } else {
+                        "else"
+                    };
+                    match else_branch.kind {
+                        StmtKind::If { .. } => {
+                            self.statement(else_branch, level, &format!("{} ", prefix))
+                        }
+                        _ => {
+                            self.body(prefix, else_branch, level);
+                        }
+                    }
+                }
+                return;
+            }
+            StmtKind::Profile { name, body } => {
+                let header = format!("{}profile(\"{}\")", prefix, name);
+                self.braced(&header, body, statement, level);
+                return;
+            }
+            StmtKind::FunctionDef(function) => {
+                self.function(function, level, prefix);
+                return;
+            }
+            StmtKind::VarDecl(decl) => {
+                self.emit_comments_before(statement.span.end, level);
+                for declarator in &decl.declarators {
+                    let mut line = format!("{}{} {}", prefix, decl.ty, declarator.name);
+                    if !declarator.legacy_array_dims.is_empty() {
+                        let dims = declarator
+                            .legacy_array_dims
+                            .iter()
+                            .map(|dim| dim.to_string())
+                            .collect::<Vec<String>>();
+                        line.push_str(&format!("[{}]", dims.join(", ")));
+                    }
+                    if let Some(init) = &declarator.init {
+                        line.push_str(&format!(" = {}", init));
+                    }
+                    line.push(';');
+                    self.push_code(level, &line);
+                }
+                return;
+            }
+            StmtKind::Assign { target, op, value } => {
+                format!("{} {} {};", target, op.symbol(), value)
+            }
+            StmtKind::Tilde {
+                lhs,
+                distribution,
+                args,
+                truncation,
+            } => {
+                let mut text = format!("{} ~ {}({})", lhs, distribution, join(args));
+                if let Some(Truncation { lower, upper }) = truncation {
+                    text.push_str(&format!(" T[{}, {}]", optional(lower), optional(upper)));
+                }
+                text.push(';');
+                text
+            }
+            StmtKind::TargetPlusAssign(value) => format!("target += {};", value),
+            StmtKind::Break => "break;".to_string(),
+            StmtKind::Continue => "continue;".to_string(),
+            StmtKind::Return(None) => "return;".to_string(),
+            StmtKind::Return(Some(value)) => format!("return {};", value),
+            StmtKind::Print(args) => format!("print({});", join(args)),
+            StmtKind::Reject(args) => format!("reject({});", join(args)),
+            StmtKind::FatalError(args) => format!("fatal_error({});", join(args)),
+            StmtKind::Expr(expr) => format!("{};", expr),
+            StmtKind::Include(path) => format!("#include {}", path),
+            StmtKind::Empty => ";".to_string(),
+        };
+        self.emit_comments_before(statement.span.end, level);
+        self.push_code(level, &format!("{}{}", prefix, text));
+    }
+
+    /// Write `header` followed by a loop or branch body. Returns whether the
+    /// body was braced, in which case the last line written is its `}`.
+    fn body(&mut self, header: &str, body: &Stmt, level: usize) -> bool {
+        match &body.kind {
+            StmtKind::Block(statements) => {
+                self.braced(header, statements, body, level);
+                true
+            }
+            _ => {
+                self.push_code(level, header);
+                self.emit_comments_before(body.span.start, level + 1);
+                self.statement(body, level + 1, "");
+                false
+            }
+        }
+    }
+
+    /// Write `header {`, the statements of `block` one level deeper, and the
+    /// closing brace.
+    fn braced(&mut self, header: &str, statements: &[Stmt], block: &Stmt, level: usize) {
+        let open = if header.is_empty() {
+            "{".to_string()
+        } else {
+            format!("{} {{", header)
+        };
+        self.push_code(level, &open);
+        let text = &self.source[block.span.range()];
+        let brace = tokenize(text)
+            .into_iter()
+            .find(|token| token.kind == TokenKind::Punctuation && token.text(text) == "{");
+        if let Some(brace) = brace {
+            self.trailing_comment(block.span.start + brace.span.end);
+        }
+        self.statements(statements, level + 1);
+        self.emit_comments_before(block.span.end.saturating_sub(1), level + 1);
+        let indent = self.indent(level);
+        self.lines.push(format!("{}}}", indent));
+    }
+
+    fn function(&mut self, function: &FunctionDef, level: usize, prefix: &str) {
+        let return_type = function
+            .return_type
+            .as_ref()
+            .map_or("void".to_string(), |ty| ty.to_string());
+        let params = function
+            .params
+            .iter()
+            .map(|param| param.to_string())
+            .collect::<Vec<String>>();
+        let header = format!(
+            "{}{} {}({})",
+            prefix,
+            return_type,
+            function.name,
+            params.join(", ")
+        );
+        match &function.body {
+            Some(body) => {
+                self.body(&header, body, level);
+            }
+            None => self.push_code(level, &format!("{};", header)),
+        }
+    }
+
+    /// Write a line of code at `level`, wrapping it if it is too long.
+    fn push_code(&mut self, level: usize, text: &str) {
+        let indent = self.indent(level);
+        let line = format!("{}{}", indent, text);
+        if line.chars().count() <= self.config.max_line_length {
+            self.lines.push(line);
+            return;
+        }
+        let continuation = self.indent(level + 1);
+        self.lines.extend(wrap_line(&line, &continuation));
+    }
+}
+
+fn join(items: &[Expr]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn optional(expr: &Option<Expr>) -> String {
+    expr.as_ref().map_or(String::new(), |expr| expr.to_string())
+}
+
+/// Break a long line after the opening bracket of its first argument list
+/// that has more than one element, putting each element on its own line
+/// indented by `continuation`. Lines without such a list are left alone.
+fn wrap_line(line: &str, continuation: &str) -> Vec<String> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut open = None;
+    let mut commas = Vec::new();
+
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '[' | '{' => {
+                if depth == 0 && open.is_none() {
+                    open = Some(index);
+                }
+                depth += 1;
+            }
+            ')' | ']' | '}' => {
+                depth = depth.saturating_sub(1);
+                if let (0, Some(open)) = (depth, open) {
+                    if !commas.is_empty() {
+                        return split_at_commas(line, open, &commas, index, continuation);
+                    }
+                }
+                if depth == 0 {
+                    open = None;
+                }
+            }
+            ',' if depth == 1 && open.is_some() => commas.push(index),
+            _ => {}
+        }
+    }
+    vec![line.to_string()]
+}
+
+fn split_at_commas(
+    line: &str,
+    open: usize,
+    commas: &[usize],
+    close: usize,
+    continuation: &str,
+) -> Vec<String> {
+    let mut lines = vec![line[..=open].to_string()];
+    let mut start = open + 1;
+    for &comma in commas {
+        lines.push(format!("{}{},", continuation, line[start..comma].trim()));
+        start = comma + 1;
+    }
+    lines.push(format!(
+        "{}{}{}",
+        continuation,
+        line[start..close].trim(),
+        &line[close..]
+    ));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(source: &str) -> String {
+        format_source(source, Path::new("model.stan"), &FormatConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn can_format_a_program() {
+        let source = "model {\ny~normal(mu,sigma);\n    for(n in 1:N){target+=-x[n]^2;}\n}\ndata {\nint<lower=0>N;real a,b=2;\n}\n";
+
+        assert_eq!(
+            format(source),
+            "data {\n  int<lower=0> N;\n  real a;\n  real b = 2;\n}\nmodel {\n  y ~ normal(mu, sigma);\n  for (n in 1:N) {\n    target += -x[n]^2;\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let source = "functions {\n  // helpers\n  real f(real x) { if (x > 0) return x; else if (x < 0) { return -x; } else return 0; }\n}\nparameters {\n  real mu; // location\n\n  /* scale\n     parameter */\n  real<lower=0> sigma;\n}\nmodel {\n  mu ~ normal(0, 1) T[, 5];\n  while (1) break;\n  profile(\"lik\") { target += f(mu); }\n}\n// end\n";
+
+        let once = format(source);
+        assert_eq!(format(&once), once);
+        assert!(is_formatted(&once, Path::new("model.stan"), &FormatConfig::default()).unwrap());
+        assert!(!is_formatted(source, Path::new("model.stan"), &FormatConfig::default()).unwrap());
+    }
+
+    #[test]
+    fn comments_are_preserved() {
+        let source = "// leading\nparameters {\n  real mu; // trailing\n\n      /* block\n       * comment */\n  real sigma;\n  // last\n}\nmodel {\n  mu ~ normal(0, /* inner */ 1);\n  for (n in 1:N) { // each\n    { x = 1; } // inner block\n  }\n}\n# legacy\n";
+
+        assert_eq!(
+            format(source),
+            "// leading\nparameters {\n  real mu; // trailing\n\n  /* block\n   * comment */\n  real sigma;\n  // last\n}\nmodel {\n  /* inner */\n  mu ~ normal(0, 1);\n  for (n in 1:N) { // each\n    {\n      x = 1;\n    } // inner block\n  }\n}\n# legacy\n"
+        );
+    }
+
+    #[test]
+    fn if_else_chains_and_unbraced_bodies_are_indented() {
+        let source = "model {\nif (a) x = 1; else if (b) { x = 2; } else { x = 3; }\nfor (i in 1:2)\ny[i] = i;\n}\n";
+
+        assert_eq!(
+            format(source),
+            "model {\n  if (a)\n    x = 1;\n  else if (b) {\n    x = 2;\n  } else {\n    x = 3;\n  }\n  for (i in 1:2)\n    y[i] = i;\n}\n"
+        );
+    }
+
+    #[test]
+    fn blocks_are_written_in_canonical_order() {
+        let source = "generated quantities {\n}\nmodel {\n}\nparameters {\n}\n// about the data\ndata {\n}\n";
+
+        assert_eq!(
+            format(source),
+            "// about the data\ndata {\n}\nparameters {\n}\nmodel {\n}\ngenerated quantities {\n}\n"
+        );
+    }
+
+    #[test]
+    fn fragments_of_functions_can_be_formatted() {
+        assert_eq!(
+            format("real twice(real x){return 2*x;}\nvoid hello();"),
+            "real twice(real x) {\n  return 2 * x;\n}\nvoid hello();\n"
+        );
+    }
+
+    #[test]
+    fn indent_width_and_line_length_are_configurable() {
+        let config = FormatConfig {
+            indent_width: 4,
+            max_line_length: 40,
+        };
+        let formatted = format_source(
+            "model {\n  target += normal_lpdf(y | alpha + beta * x, sigma_total);\n}\n",
+            Path::new("model.stan"),
+            &config,
+        )
+        .unwrap();
+
+        assert_eq!(
+            formatted,
+            "model {\n    target += normal_lpdf(\n        y | alpha + beta * x,\n        sigma_total);\n}\n"
+        );
+    }
+
+    #[test]
+    fn source_that_does_not_parse_is_not_formatted() {
+        let errors = format_source(
+            "data {\n  int N\n}\nmodel {\n  x = ;\n}\n",
+            Path::new("model.stan"),
+            &FormatConfig::default(),
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 2);
+
+        let errors = format_source(
+            "real f() {\n  return 1\n}\n",
+            Path::new("lib.stan"),
+            &FormatConfig::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "lib.stan:3:1: syntax error: expected ';', found '}'"
+        );
+    }
+}
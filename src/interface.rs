@@ -0,0 +1,465 @@
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::ast::{BaseType, Constraints, Expr, StmtKind, VarType};
+use crate::error::{SourceLocation, StanJamError};
+use crate::eval::{evaluate, Data};
+use crate::stan_model_block::StanModelBlock;
+
+/// A variable declared at the top level of a block, such as one of the
+/// inputs of the data block.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Variable {
+    pub name: String,
+    /// The declared type. Dimensions written after the name in the removed
+    /// `real y[N];` syntax are moved into `ty.array_dims`.
+    pub ty: VarType,
+    pub location: SourceLocation,
+}
+
+impl Variable {
+    pub fn base(&self) -> &BaseType {
+        &self.ty.base
+    }
+
+    /// Every dimension of the variable, outermost first: the array
+    /// dimensions followed by the sizes of the base type, so `N`, `M` and `K`
+    /// for `array[N, M] vector[K] x;`.
+    pub fn dims(&self) -> Vec<&Expr> {
+        self.ty.array_dims.iter().chain(&self.ty.sizes).collect()
+    }
+
+    pub fn constraints(&self) -> &Constraints {
+        &self.ty.constraints
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.ty, self.name)
+    }
+}
+
+/// The kind of scalar a value is made of in CmdStan JSON.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ElementType {
+    Int,
+    Real,
+    /// A `[re, im]` pair of reals.
+    Complex,
+    /// An object with the items under the keys `"1"`, `"2"`, ...
+    Tuple,
+}
+
+pub fn element_type(base: &BaseType) -> ElementType {
+    match base {
+        BaseType::Int => ElementType::Int,
+        BaseType::Complex
+        | BaseType::ComplexVector
+        | BaseType::ComplexRowVector
+        | BaseType::ComplexMatrix => ElementType::Complex,
+        BaseType::Tuple(_) => ElementType::Tuple,
+        _ => ElementType::Real,
+    }
+}
+
+/// The expressions for the lengths of the nested JSON arrays holding a value
+/// of type `ty`, outermost first. Square types such as `cov_matrix[K]` are
+/// `K` by `K`, and `cholesky_factor_cov[M]` is short for
+/// `cholesky_factor_cov[M, M]`.
+pub fn shape_exprs(ty: &VarType) -> Vec<&Expr> {
+    let mut shape = ty
+        .array_dims
+        .iter()
+        .chain(&ty.sizes)
+        .collect::<Vec<&Expr>>();
+    if let (
+        BaseType::CholeskyFactorCorr
+        | BaseType::CholeskyFactorCov
+        | BaseType::CorrMatrix
+        | BaseType::CovMatrix,
+        [size],
+    ) = (&ty.base, &ty.sizes[..])
+    {
+        shape.push(size);
+    }
+    shape
+}
+
+/// The lengths of the nested JSON arrays holding a value of type `ty`, with
+/// its sizes evaluated against `data`. Fails if the type has the wrong
+/// number of sizes, such as `vector[2, 3]`.
+pub fn json_shape(ty: &VarType, data: &Data) -> Result<Vec<usize>, String> {
+    ty.base.check_size_count(ty.sizes.len())?;
+    shape_exprs(ty)
+        .into_iter()
+        .map(|expr| {
+            evaluate(expr, data)?
+                .as_size()
+                .map_err(|error| format!("{} in '{}'", error, expr))
+        })
+        .collect()
+}
+
+/// The `lower`, `upper`, `offset` and `multiplier` of a declaration,
+/// evaluated. Infinite bounds are the same as no bound, as in Stan.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Bounds {
+    pub lower: Option<f64>,
+    pub upper: Option<f64>,
+    pub offset: Option<f64>,
+    pub multiplier: Option<f64>,
+}
+
+impl Bounds {
+    /// The bounds of `ty`, evaluated against `data`. Fails if a bound cannot
+    /// be evaluated or no value can satisfy the bounds.
+    pub fn new(ty: &VarType, data: &Data) -> Result<Bounds, String> {
+        let bound = |bound: &Option<_>| {
+            bound
+                .as_ref()
+                .map(|bound| evaluate(bound, data).map(|bound| bound.as_f64()))
+                .transpose()
+        };
+        let bounds = Bounds {
+            lower: bound(&ty.constraints.lower)?.filter(|lower| *lower != f64::NEG_INFINITY),
+            upper: bound(&ty.constraints.upper)?.filter(|upper| *upper != f64::INFINITY),
+            offset: bound(&ty.constraints.offset)?,
+            multiplier: bound(&ty.constraints.multiplier)?,
+        };
+        if let (Some(lower), Some(upper)) = (bounds.lower, bounds.upper) {
+            if lower > upper {
+                return Err(format!(
+                    "the lower bound {} is above the upper bound {}",
+                    lower, upper
+                ));
+            }
+        }
+        if let Some(multiplier) = bounds.multiplier.filter(|multiplier| *multiplier <= 0.0) {
+            return Err(format!("the multiplier {} is not positive", multiplier));
+        }
+        Ok(bounds)
+    }
+}
+
+/// The values nested `shape.len()` arrays deep in `value`, outermost first,
+/// with paths such as `y[2][1]`. An array of the wrong length, or a value
+/// that is not an array, is an error in place of its elements, so every
+/// problem is reported once.
+pub fn array_elements<'a>(
+    value: &'a Value,
+    shape: &[usize],
+    path: String,
+) -> Vec<Result<(&'a Value, String), String>> {
+    let Some((&length, inner)) = shape.split_first() else {
+        return vec![Ok((value, path))];
+    };
+    let items = match value {
+        Value::Array(items) if items.len() == length => items,
+        Value::Array(items) => {
+            return vec![Err(format!(
+                "'{}' should have {} elements, found {}",
+                path,
+                length,
+                items.len()
+            ))]
+        }
+        _ => {
+            return vec![Err(format!(
+                "'{}' should be an array of {} elements, found {}",
+                path, length, value
+            ))]
+        }
+    };
+    items
+        .iter()
+        .enumerate()
+        .flat_map(|(index, item)| array_elements(item, inner, format!("{}[{}]", path, index + 1)))
+        .collect()
+}
+
+/// `elements`, outermost first, grouped into nested arrays of shape
+/// `shape`; the inverse of `array_elements`.
+pub fn nested_array(mut elements: Vec<Value>, shape: &[usize]) -> Value {
+    let Some((&length, inner)) = shape.split_first() else {
+        return elements.pop().expect("a value has one element");
+    };
+    let chunk = inner.iter().product::<usize>();
+    let mut items = Vec::with_capacity(length);
+    for _ in 0..length {
+        let rest = elements.split_off(chunk.min(elements.len()));
+        items.push(nested_array(elements, inner));
+        elements = rest;
+    }
+    Value::Array(items)
+}
+
+/// The number of unconstrained reals that represent a value of type `ty`,
+/// with its sizes evaluated against `data`. For example `simplex[K]` has
+/// `K - 1` and `cholesky_factor_corr[K]` has `K * (K - 1) / 2`.
+pub fn unconstrained_size(ty: &VarType, data: &Data) -> Result<usize, String> {
+    let shape = json_shape(ty, data)?;
+    let (array_shape, base_shape) = shape.split_at(ty.array_dims.len());
+    let triangle = |size: usize| size * size.saturating_sub(1) / 2;
+    let per_element = match (&ty.base, base_shape) {
+        (BaseType::Int, _) => return Err("ints cannot be parameters".to_string()),
+        (BaseType::Tuple(items), _) => items
+            .iter()
+            .map(|item| unconstrained_size(item, data))
+            .sum::<Result<usize, String>>()?,
+        (BaseType::Simplex | BaseType::SumToZeroVector, &[size]) => size.saturating_sub(1),
+        (BaseType::SumToZeroMatrix, &[rows, columns]) => {
+            rows.saturating_sub(1) * columns.saturating_sub(1)
+        }
+        (BaseType::ColumnStochasticMatrix, &[rows, columns]) => rows.saturating_sub(1) * columns,
+        (BaseType::RowStochasticMatrix, &[rows, columns]) => rows * columns.saturating_sub(1),
+        (BaseType::CholeskyFactorCorr | BaseType::CorrMatrix, &[size, _]) => triangle(size),
+        (BaseType::CovMatrix, &[size, _]) => size + triangle(size),
+        (BaseType::CholeskyFactorCov, &[rows, columns]) => {
+            columns + triangle(columns) + rows.saturating_sub(columns) * columns
+        }
+        (base, sizes) => {
+            let reals = sizes.iter().product::<usize>();
+            match element_type(base) {
+                ElementType::Complex => 2 * reals,
+                _ => reals,
+            }
+        }
+    };
+    Ok(array_shape.iter().product::<usize>() * per_element)
+}
+
+/// The shape of a parameter and the size of its unconstrained
+/// representation.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParameterSize {
+    pub name: String,
+    /// The lengths of the nested JSON arrays holding the constrained value,
+    /// as in an inits file.
+    pub shape: Vec<usize>,
+    pub unconstrained_size: usize,
+}
+
+/// The shape and unconstrained size of each of `variables`, with sizes
+/// evaluated against `data`.
+pub fn parameter_sizes(
+    variables: &[Variable],
+    data: &Data,
+) -> Result<Vec<ParameterSize>, StanJamError> {
+    variables
+        .iter()
+        .map(|variable| {
+            let size = || {
+                Ok(ParameterSize {
+                    name: variable.name.clone(),
+                    shape: json_shape(&variable.ty, data)?,
+                    unconstrained_size: unconstrained_size(&variable.ty, data)?,
+                })
+            };
+            size().map_err(|error: String| StanJamError::SizeError {
+                message: format!("cannot size '{}': {}", variable.name, error),
+                location: variable.location.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The variables declared at the top level of `block`, in declaration order.
+/// Fails with the first syntax error in the block.
+pub fn block_variables(block: &StanModelBlock) -> Result<Vec<Variable>, StanJamError> {
+    let (statements, errors) = block.parse_with_recovery();
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error);
+    }
+
+    let mut variables = Vec::new();
+    for statement in statements {
+        let StmtKind::VarDecl(decl) = statement.kind else {
+            continue;
+        };
+        for declarator in decl.declarators {
+            let mut ty = decl.ty.clone();
+            ty.array_dims.extend(declarator.legacy_array_dims);
+            variables.push(Variable {
+                name: declarator.name,
+                ty,
+                location: block.location_of(declarator.span.start),
+            });
+        }
+    }
+    Ok(variables)
+}
+
+/// The variables of a `block_type` block made of `declarations`, one per
+/// line, for tests. Panics on a syntax error.
+#[cfg(test)]
+pub(crate) fn declared_variables(
+    block_type: crate::stan_model_block_type::StanModelBlockType,
+    declarations: &[&str],
+) -> Vec<Variable> {
+    let mut block = StanModelBlock::new(block_type);
+    declarations.iter().for_each(|line| block.add(line));
+    block_variables(&block).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stan_model_block_type::StanModelBlockType;
+    use serde_json::json;
+
+    fn data_variables(declarations: &[&str]) -> Vec<Variable> {
+        declared_variables(StanModelBlockType::Data, declarations)
+    }
+
+    #[test]
+    fn can_list_the_variables_of_a_block_in_order() {
+        let variables = data_variables(&[
+            "int<lower=0> N;",
+            "array[N] vector<lower=0, upper=1>[K] y, z;",
+            "real x[N, 2];",
+            "tuple(int, array[2] real) t;",
+        ]);
+
+        let described = variables
+            .iter()
+            .map(|variable| {
+                format!(
+                    "{}: {} [{}] {}",
+                    variable.name,
+                    variable.base().name(),
+                    variable
+                        .dims()
+                        .iter()
+                        .map(|dim| dim.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                    variable.constraints()
+                )
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            described,
+            vec![
+                "N: int [] <lower=0>",
+                "y: vector [N, K] <lower=0, upper=1>",
+                "z: vector [N, K] <lower=0, upper=1>",
+                "x: real [N, 2] ",
+                "t: tuple [] ",
+            ]
+        );
+        assert_eq!(variables[4].to_string(), "tuple(int, array[2] real) t");
+        assert_eq!(variables[3].location.line, 3);
+    }
+
+    #[test]
+    fn can_compute_unconstrained_sizes() {
+        let variables = data_variables(&[
+            "real mu;",
+            "array[2] vector<lower=0>[K] sigma;",
+            "simplex[K] theta;",
+            "cholesky_factor_corr[K] l_omega;",
+            "cov_matrix[K] cov;",
+            "cholesky_factor_cov[5, K] l_cov;",
+            "array[N] sum_to_zero_vector[K] s;",
+            "complex_matrix[2, K] z;",
+            "tuple(real, unit_vector[K]) t;",
+        ]);
+        let Value::Object(data) = json!({"N": 0, "K": 3}) else {
+            unreachable!();
+        };
+
+        let sizes = parameter_sizes(&variables, &data)
+            .unwrap()
+            .iter()
+            .map(|size| (size.shape.clone(), size.unconstrained_size))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            sizes,
+            vec![
+                (vec![], 1),
+                (vec![2, 3], 6),
+                (vec![3], 2),
+                (vec![3, 3], 3),
+                (vec![3, 3], 6),
+                (vec![5, 3], 12),
+                (vec![0, 3], 0),
+                (vec![2, 3], 12),
+                (vec![], 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn array_elements_are_listed_with_their_paths() {
+        let value = json!([[1, 2, 3], [4, 5, 6]]);
+
+        let elements = array_elements(&value, &[2, 3], "x".to_string());
+
+        assert_eq!(elements.len(), 6);
+        assert_eq!(elements[5], Ok((&json!(6), "x[2][3]".to_string())));
+        assert_eq!(
+            array_elements(&json!([[1], 2]), &[2, 1], "x".to_string()),
+            vec![
+                Ok((&json!(1), "x[1][1]".to_string())),
+                Err("'x[2]' should be an array of 1 elements, found 2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_arrays_invert_array_elements() {
+        let value = json!([[1, 2, 3], [4, 5, 6]]);
+        let elements = array_elements(&value, &[2, 3], "x".to_string())
+            .into_iter()
+            .map(|element| element.unwrap().0.clone())
+            .collect();
+
+        assert_eq!(nested_array(elements, &[2, 3]), value);
+    }
+
+    #[test]
+    fn bounds_are_evaluated_and_checked() {
+        let variables = data_variables(&["real<upper=K> x;", "real<lower=K, upper=0> y;"]);
+        let Value::Object(data) = json!({"K": 2}) else {
+            unreachable!();
+        };
+
+        assert_eq!(
+            Bounds::new(&variables[0].ty, &data),
+            Ok(Bounds {
+                upper: Some(2.0),
+                ..Bounds::default()
+            })
+        );
+        assert_eq!(
+            Bounds::new(&variables[1].ty, &data).unwrap_err(),
+            "the lower bound 2 is above the upper bound 0"
+        );
+    }
+
+    #[test]
+    fn unknown_sizes_are_located() {
+        let variables = data_variables(&["real x;", "vector[K] y;"]);
+
+        let error = parameter_sizes(&variables, &Data::new()).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "<data>:2:11: cannot size 'y': 'K' is not defined"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        let mut block = StanModelBlock::new(StanModelBlockType::Data);
+        block.add("int N");
+
+        assert!(matches!(
+            block_variables(&block),
+            Err(StanJamError::SyntaxError { .. })
+        ));
+    }
+}
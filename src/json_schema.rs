@@ -0,0 +1,248 @@
+use serde_json::{json, Map, Value};
+
+use crate::ast::{BaseType, Expr, VarType};
+use crate::eval::{evaluate, Data, Scalar};
+use crate::interface::{element_type, shape_exprs, ElementType, Variable};
+
+/// The JSON Schema dialect of the generated documents.
+pub const SCHEMA_DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// The strings CmdStan accepts in place of non-finite reals.
+const NON_FINITE: &[&str] = &[
+    "NaN",
+    "Inf",
+    "-Inf",
+    "+Inf",
+    "Infinity",
+    "-Infinity",
+    "+Infinity",
+];
+
+/// A JSON Schema for CmdStan JSON data holding `variables`.
+///
+/// Sizes and bounds that are constant, such as `[3]` or `<lower=0>`, become
+/// `minItems`/`maxItems` and `minimum`/`maximum`. Sizes and bounds that
+/// depend on other data cannot be expressed in a schema, so they are left
+/// out; each variable's Stan declaration is kept as its `description`.
+///
+/// Ints follow the same rule as `validate_data` as far as JSON Schema can
+/// express it: the schema cannot tell `3.0` from `3`, so an int written with
+/// a decimal point passes the schema but is still rejected by CmdStan and by
+/// `validate_data`.
+pub fn data_schema(variables: &[Variable]) -> Value {
+    let properties = variables
+        .iter()
+        .map(|variable| {
+            let mut schema = type_schema(&variable.ty);
+            schema.insert("description".to_string(), json!(variable.to_string()));
+            (variable.name.clone(), Value::Object(schema))
+        })
+        .collect::<Map<String, Value>>();
+    let required = variables
+        .iter()
+        .map(|variable| json!(variable.name))
+        .collect::<Vec<Value>>();
+
+    json!({
+        "$schema": SCHEMA_DIALECT,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// The schema of a value of type `ty`: nested arrays for its dimensions
+/// around the schema of one element.
+fn type_schema(ty: &VarType) -> Map<String, Value> {
+    shape_exprs(ty)
+        .iter()
+        .rev()
+        .fold(element_schema(ty), |items, size| {
+            let mut schema = Map::new();
+            schema.insert("type".to_string(), json!("array"));
+            schema.insert("items".to_string(), Value::Object(items));
+            if let Some(size) = constant(size).and_then(|size| size.as_size().ok()) {
+                schema.insert("minItems".to_string(), json!(size));
+                schema.insert("maxItems".to_string(), json!(size));
+            }
+            schema
+        })
+}
+
+/// The schema of one element. Bounds apply to its numbers; a real accepts the
+/// infinities on its unbounded side, and `"NaN"` only without a declared
+/// bound, as `validate_data` does.
+fn element_schema(ty: &VarType) -> Map<String, Value> {
+    let declared = ty.constraints.lower.is_some() || ty.constraints.upper.is_some();
+    let (lower, upper) = implied_bounds(&ty.base);
+    let lower = ty.constraints.lower.as_ref().and_then(constant).or(lower);
+    let upper = ty.constraints.upper.as_ref().and_then(constant).or(upper);
+    let bounded = |mut schema: Map<String, Value>| {
+        if let Some(lower) = lower {
+            schema.insert("minimum".to_string(), scalar_json(lower));
+        }
+        if let Some(upper) = upper {
+            schema.insert("maximum".to_string(), scalar_json(upper));
+        }
+        Value::Object(schema)
+    };
+    let number = |type_name: &str| {
+        let mut schema = Map::new();
+        schema.insert("type".to_string(), json!(type_name));
+        schema
+    };
+
+    let schema = match element_type(&ty.base) {
+        ElementType::Int => bounded(number("integer")),
+        ElementType::Real => {
+            let non_finite = NON_FINITE
+                .iter()
+                .filter(|name| match **name {
+                    "NaN" => !declared,
+                    name if name.starts_with('-') => lower.is_none(),
+                    _ => upper.is_none(),
+                })
+                .collect::<Vec<_>>();
+            json!({"anyOf": [bounded(number("number")), {"enum": non_finite}]})
+        }
+        ElementType::Complex => json!({
+            "type": "array",
+            "items": {"anyOf": [{"type": "number"}, {"enum": NON_FINITE}]},
+            "minItems": 2,
+            "maxItems": 2,
+        }),
+        ElementType::Tuple => {
+            let BaseType::Tuple(items) = &ty.base else {
+                unreachable!("only tuples have tuple elements");
+            };
+            let properties = items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| ((index + 1).to_string(), Value::Object(type_schema(item))))
+                .collect::<Map<String, Value>>();
+            let required = (1..=items.len())
+                .map(|key| json!(key.to_string()))
+                .collect::<Vec<Value>>();
+            json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            })
+        }
+    };
+    let Value::Object(schema) = schema else {
+        unreachable!("element schemas are objects");
+    };
+    schema
+}
+
+/// The bounds every element of a constrained type lies within.
+fn implied_bounds(base: &BaseType) -> (Option<Scalar>, Option<Scalar>) {
+    match base {
+        BaseType::Simplex | BaseType::ColumnStochasticMatrix | BaseType::RowStochasticMatrix => {
+            (Some(Scalar::Int(0)), Some(Scalar::Int(1)))
+        }
+        BaseType::CorrMatrix | BaseType::CholeskyFactorCorr | BaseType::UnitVector => {
+            (Some(Scalar::Int(-1)), Some(Scalar::Int(1)))
+        }
+        BaseType::PositiveOrdered => (Some(Scalar::Int(0)), None),
+        _ => (None, None),
+    }
+}
+
+/// The value of an expression that does not depend on any data.
+fn constant(expr: &Expr) -> Option<Scalar> {
+    evaluate(expr, &Data::new()).ok()
+}
+
+fn scalar_json(value: Scalar) -> Value {
+    match value {
+        Scalar::Int(value) => json!(value),
+        Scalar::Real(value) => json!(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::declared_variables;
+    use crate::stan_model_block_type::StanModelBlockType;
+
+    fn schema(declarations: &[&str]) -> Value {
+        data_schema(&declared_variables(StanModelBlockType::Data, declarations))
+    }
+
+    #[test]
+    fn can_describe_scalars_and_bounds() {
+        let schema = schema(&[
+            "int<lower=1> N;",
+            "real<lower=-0.5, upper=N> x;",
+            "real<lower=N> w;",
+        ]);
+
+        assert_eq!(
+            schema,
+            json!({
+                "$schema": SCHEMA_DIALECT,
+                "type": "object",
+                "properties": {
+                    "N": {
+                        "type": "integer",
+                        "minimum": 1,
+                        "description": "int<lower=1> N",
+                    },
+                    "x": {
+                        "anyOf": [
+                            {"type": "number", "minimum": -0.5},
+                            {"enum": ["Inf", "+Inf", "Infinity", "+Infinity"]},
+                        ],
+                        "description": "real<lower=-0.5, upper=N> x",
+                    },
+                    "w": {
+                        "anyOf": [
+                            {"type": "number"},
+                            {"enum": ["Inf", "-Inf", "+Inf", "Infinity", "-Infinity", "+Infinity"]},
+                        ],
+                        "description": "real<lower=N> w",
+                    },
+                },
+                "required": ["N", "x", "w"],
+            })
+        );
+    }
+
+    #[test]
+    fn arrays_are_nested_with_constant_sizes() {
+        let schema = schema(&["array[N, 2] vector[3] y;", "cov_matrix[K] sigma;"]);
+
+        let y = &schema["properties"]["y"];
+        assert_eq!(y["type"], "array");
+        assert_eq!(y.get("minItems"), None);
+        assert_eq!(y["items"]["minItems"], 2);
+        assert_eq!(y["items"]["items"]["maxItems"], 3);
+        assert_eq!(y["items"]["items"]["items"]["anyOf"][0]["type"], "number");
+
+        let sigma = &schema["properties"]["sigma"];
+        assert_eq!(sigma["items"]["type"], "array");
+        assert_eq!(sigma["items"]["items"]["anyOf"][0]["type"], "number");
+    }
+
+    #[test]
+    fn can_describe_special_types() {
+        let schema = schema(&["simplex[4] theta;", "complex z;", "tuple(int, real) t;"]);
+
+        let theta = &schema["properties"]["theta"]["items"]["anyOf"];
+        assert_eq!(
+            theta,
+            &json!([{"type": "number", "minimum": 0, "maximum": 1}, {"enum": ["NaN"]}])
+        );
+
+        let z = &schema["properties"]["z"];
+        assert_eq!(z["items"]["anyOf"][1]["enum"], json!(NON_FINITE));
+        assert_eq!((&z["type"], &z["minItems"]), (&json!("array"), &json!(2)));
+
+        let t = &schema["properties"]["t"];
+        assert_eq!(t["required"], json!(["1", "2"]));
+        assert_eq!(t["properties"]["1"]["type"], "integer");
+    }
+}
@@ -0,0 +1,584 @@
+use std::fmt;
+use std::ops::Range;
+
+/// A byte range into the source text.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both spans.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    pub fn range(self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// The byte offset at which every line of a source starts, so the line of
+/// an offset can be found without counting newlines again.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> LineIndex {
+        let newlines = source.match_indices('\n').map(|(offset, _)| offset + 1);
+        LineIndex {
+            starts: std::iter::once(0).chain(newlines).collect(),
+        }
+    }
+
+    /// The 0-based line that byte `offset` is on.
+    pub fn line(&self, offset: usize) -> usize {
+        self.starts.partition_point(|start| *start <= offset) - 1
+    }
+
+    /// The byte offset at which 0-based line `line` starts.
+    pub fn line_start(&self, line: usize) -> usize {
+        self.starts[line]
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TokenKind {
+    Identifier,
+    /// A reserved word, such as `for`, `real` or `target`.
+    Keyword,
+    IntLiteral,
+    RealLiteral,
+    /// An imaginary literal such as `2i` or `1.5e3i`.
+    ImaginaryLiteral,
+    StringLiteral,
+    /// Arithmetic, comparison, logical, assignment and ternary operators,
+    /// plus `~`, `:` and the transpose `'`.
+    Operator,
+    /// `(`, `)`, `[`, `]`, `{`, `}`, `,`, `;` and `|`.
+    Punctuation,
+    /// A `// ...` comment, up to but not including the newline.
+    LineComment,
+    /// A `/* ... */` comment.
+    BlockComment,
+    /// A legacy `# ...` comment.
+    HashComment,
+    /// An `#include ...` directive, up to but not including the newline.
+    Include,
+    /// A character that cannot start a token, or an unterminated string or
+    /// block comment.
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    /// The source text of the token.
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.span.range()]
+    }
+
+    pub fn is_comment(&self) -> bool {
+        matches!(
+            self.kind,
+            TokenKind::LineComment | TokenKind::BlockComment | TokenKind::HashComment
+        )
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TokenKind::Identifier => "identifier",
+            TokenKind::Keyword => "keyword",
+            TokenKind::IntLiteral => "integer literal",
+            TokenKind::RealLiteral => "real literal",
+            TokenKind::ImaginaryLiteral => "imaginary literal",
+            TokenKind::StringLiteral => "string literal",
+            TokenKind::Operator => "operator",
+            TokenKind::Punctuation => "punctuation",
+            TokenKind::LineComment => "line comment",
+            TokenKind::BlockComment => "block comment",
+            TokenKind::HashComment => "comment",
+            TokenKind::Include => "include directive",
+            TokenKind::Error => "invalid token",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Words that cannot be used as identifiers in Stan. `lower`, `upper`,
+/// `offset` and `multiplier` are only special inside a `<...>` constraint, so
+/// they are lexed as identifiers and the parser recognizes them there by text.
+pub const KEYWORDS: &[&str] = &[
+    "functions",
+    "data",
+    "transformed",
+    "parameters",
+    "model",
+    "generated",
+    "quantities",
+    "for",
+    "in",
+    "while",
+    "if",
+    "else",
+    "true",
+    "false",
+    "target",
+    "profile",
+    "return",
+    "break",
+    "continue",
+    "print",
+    "reject",
+    "fatal_error",
+    "void",
+    "int",
+    "real",
+    "complex",
+    "vector",
+    "row_vector",
+    "matrix",
+    "complex_vector",
+    "complex_row_vector",
+    "complex_matrix",
+    "array",
+    "tuple",
+    "simplex",
+    "unit_vector",
+    "sum_to_zero_vector",
+    "sum_to_zero_matrix",
+    "ordered",
+    "positive_ordered",
+    "cholesky_factor_corr",
+    "cholesky_factor_cov",
+    "corr_matrix",
+    "cov_matrix",
+    "column_stochastic_matrix",
+    "row_stochastic_matrix",
+];
+
+/// Operators, longest first so that the lexer always takes the longest match.
+const OPERATORS: &[&str] = &[
+    ".*=", "./=", "%/%", "&&", "||", "==", "!=", "<=", ">=", "+=", "-=", "*=", "/=", ".*", "./",
+    ".^", "+", "-", "*", "/", "%", "\\", "^", "'", "!", "<", ">", "=", "~", "?", ":",
+];
+
+const PUNCTUATION: &[char] = &['(', ')', '[', ']', '{', '}', ',', ';', '|'];
+
+pub fn is_keyword(word: &str) -> bool {
+    KEYWORDS.contains(&word)
+}
+
+/// Split Stan source into tokens. Whitespace is skipped; comments and include
+/// directives are kept so that callers can reproduce the source.
+///
+/// Lexing never fails: characters that cannot start a token, and strings or
+/// block comments that are never closed, become `TokenKind::Error` tokens.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut lexer = Lexer {
+        source,
+        position: 0,
+        tokens: Vec::new(),
+    };
+    lexer.run();
+    lexer.tokens
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    position: usize,
+    tokens: Vec<Token>,
+}
+
+impl Lexer<'_> {
+    fn run(&mut self) {
+        while let Some(c) = self.peek() {
+            let start = self.position;
+            let kind = match c {
+                c if c.is_whitespace() => {
+                    self.bump();
+                    continue;
+                }
+                '/' if self.rest().starts_with("//") => {
+                    self.skip_to_end_of_line();
+                    TokenKind::LineComment
+                }
+                '/' if self.rest().starts_with("/*") => self.block_comment(),
+                '#' => {
+                    let is_include = self
+                        .rest()
+                        .strip_prefix("#include")
+                        .is_some_and(|rest| rest.chars().next().is_none_or(char::is_whitespace));
+                    self.skip_to_end_of_line();
+                    if is_include {
+                        TokenKind::Include
+                    } else {
+                        TokenKind::HashComment
+                    }
+                }
+                '"' => self.string(),
+                c if c.is_ascii_digit() => self.number(),
+                '.' if self.rest()[1..].starts_with(|c: char| c.is_ascii_digit()) => self.number(),
+                c if c.is_alphabetic() || c == '_' => {
+                    while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                        self.bump();
+                    }
+                    if is_keyword(&self.source[start..self.position]) {
+                        TokenKind::Keyword
+                    } else {
+                        TokenKind::Identifier
+                    }
+                }
+                c if PUNCTUATION.contains(&c) && !self.rest().starts_with("||") => {
+                    self.bump();
+                    TokenKind::Punctuation
+                }
+                _ => match OPERATORS.iter().find(|op| self.rest().starts_with(**op)) {
+                    Some(op) => {
+                        self.position += op.len();
+                        TokenKind::Operator
+                    }
+                    None => {
+                        self.bump();
+                        TokenKind::Error
+                    }
+                },
+            };
+            self.tokens.push(Token {
+                kind,
+                span: Span::new(start, self.position),
+            });
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.source[self.position..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.position += c.len_utf8();
+        }
+    }
+
+    fn skip_to_end_of_line(&mut self) {
+        self.position += self.rest().find('\n').unwrap_or(self.rest().len());
+    }
+
+    fn block_comment(&mut self) -> TokenKind {
+        match self.rest()[2..].find("*/") {
+            Some(end) => {
+                self.position += end + 4;
+                TokenKind::BlockComment
+            }
+            None => {
+                self.position = self.source.len();
+                TokenKind::Error
+            }
+        }
+    }
+
+    fn string(&mut self) -> TokenKind {
+        self.bump();
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    self.bump();
+                    return TokenKind::StringLiteral;
+                }
+                '\n' => break,
+                '\\' => {
+                    self.bump();
+                    self.bump();
+                }
+                _ => self.bump(),
+            }
+        }
+        TokenKind::Error
+    }
+
+    /// Integer, real and imaginary literals: `1`, `1.`, `.5`, `1.5e-3`, `2i`.
+    fn number(&mut self) -> TokenKind {
+        let mut is_real = false;
+        self.digits();
+        if self.peek() == Some('.')
+            && !self.rest().starts_with(".*")
+            && !self.rest().starts_with("./")
+            && !self.rest().starts_with(".^")
+        {
+            is_real = true;
+            self.bump();
+            self.digits();
+        }
+        if self.peek().is_some_and(|c| c == 'e' || c == 'E') {
+            let exponent = self.rest()[1..].trim_start_matches(['+', '-']);
+            if exponent.starts_with(|c: char| c.is_ascii_digit()) {
+                is_real = true;
+                self.position = self.source.len() - exponent.len();
+                self.digits();
+            }
+        }
+        let followed_by_word = |rest: &str| {
+            rest.chars()
+                .nth(1)
+                .is_some_and(|c| c.is_alphanumeric() || c == '_')
+        };
+        if self.peek() == Some('i') && !followed_by_word(self.rest()) {
+            self.bump();
+            return TokenKind::ImaginaryLiteral;
+        }
+        if is_real {
+            TokenKind::RealLiteral
+        } else {
+            TokenKind::IntLiteral
+        }
+    }
+
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+    }
+}
+
+/// The significant tokens: everything except comments.
+pub fn significant(tokens: &[Token]) -> Vec<Token> {
+    tokens
+        .iter()
+        .filter(|token| !token.is_comment())
+        .copied()
+        .collect()
+}
+
+/// The 0-based line index of every include directive that is the first token
+/// on its line, given the `tokens` and `lines` of a source. Includes inside
+/// comments and strings are not reported.
+pub fn include_lines(tokens: &[Token], lines: &LineIndex) -> Vec<usize> {
+    let mut previous_line = None;
+    let mut include_lines = Vec::new();
+    for token in tokens {
+        let line = lines.line(token.span.start);
+        if token.kind == TokenKind::Include && previous_line != Some(line) {
+            include_lines.push(line);
+        }
+        previous_line = Some(lines.line(token.span.end));
+    }
+    include_lines
+}
+
+/// The 0-based line index of every `#pragma once` include guard, given the
+/// `tokens` and `lines` of `source`. Guards inside comments and strings are
+/// not reported.
+pub fn include_guard_lines(source: &str, tokens: &[Token], lines: &LineIndex) -> Vec<usize> {
+    tokens
+        .iter()
+        .filter(|token| token.kind == TokenKind::HashComment)
+        .filter(|token| {
+            token
+                .text(source)
+                .split_whitespace()
+                .eq(["#pragma", "once"])
+        })
+        .map(|token| lines.line(token.span.start))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds_and_text(source: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(source)
+            .iter()
+            .map(|token| (token.kind, token.text(source)))
+            .collect()
+    }
+
+    #[test]
+    fn can_tokenize_a_declaration() {
+        assert_eq!(
+            kinds_and_text("vector<lower=0>[N] y;"),
+            vec![
+                (TokenKind::Keyword, "vector"),
+                (TokenKind::Operator, "<"),
+                (TokenKind::Identifier, "lower"),
+                (TokenKind::Operator, "="),
+                (TokenKind::IntLiteral, "0"),
+                (TokenKind::Operator, ">"),
+                (TokenKind::Punctuation, "["),
+                (TokenKind::Identifier, "N"),
+                (TokenKind::Punctuation, "]"),
+                (TokenKind::Identifier, "y"),
+                (TokenKind::Punctuation, ";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn constraint_words_are_identifiers() {
+        assert_eq!(
+            kinds_and_text("vector[N] offset; real lower; real then;"),
+            vec![
+                (TokenKind::Keyword, "vector"),
+                (TokenKind::Punctuation, "["),
+                (TokenKind::Identifier, "N"),
+                (TokenKind::Punctuation, "]"),
+                (TokenKind::Identifier, "offset"),
+                (TokenKind::Punctuation, ";"),
+                (TokenKind::Keyword, "real"),
+                (TokenKind::Identifier, "lower"),
+                (TokenKind::Punctuation, ";"),
+                (TokenKind::Keyword, "real"),
+                (TokenKind::Identifier, "then"),
+                (TokenKind::Punctuation, ";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_carry_byte_spans() {
+        let tokens = tokenize("  mu ~ normal(0, 1);");
+        assert_eq!(tokens[0].span, Span::new(2, 4));
+        assert_eq!(tokens[1].span, Span::new(5, 6));
+        assert_eq!(tokens[2].span, Span::new(7, 13));
+    }
+
+    #[test]
+    fn can_tokenize_numeric_literals() {
+        assert_eq!(
+            kinds_and_text("1 23 1.5 .5 2. 1e10 1.5E-3 2i 1.5e3i 3.*x"),
+            vec![
+                (TokenKind::IntLiteral, "1"),
+                (TokenKind::IntLiteral, "23"),
+                (TokenKind::RealLiteral, "1.5"),
+                (TokenKind::RealLiteral, ".5"),
+                (TokenKind::RealLiteral, "2."),
+                (TokenKind::RealLiteral, "1e10"),
+                (TokenKind::RealLiteral, "1.5E-3"),
+                (TokenKind::ImaginaryLiteral, "2i"),
+                (TokenKind::ImaginaryLiteral, "1.5e3i"),
+                (TokenKind::IntLiteral, "3"),
+                (TokenKind::Operator, ".*"),
+                (TokenKind::Identifier, "x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn operators_take_the_longest_match() {
+        assert_eq!(
+            kinds_and_text("a .*= b %/% c <= d && e' != f || g"),
+            vec![
+                (TokenKind::Identifier, "a"),
+                (TokenKind::Operator, ".*="),
+                (TokenKind::Identifier, "b"),
+                (TokenKind::Operator, "%/%"),
+                (TokenKind::Identifier, "c"),
+                (TokenKind::Operator, "<="),
+                (TokenKind::Identifier, "d"),
+                (TokenKind::Operator, "&&"),
+                (TokenKind::Identifier, "e"),
+                (TokenKind::Operator, "'"),
+                (TokenKind::Operator, "!="),
+                (TokenKind::Identifier, "f"),
+                (TokenKind::Operator, "||"),
+                (TokenKind::Identifier, "g"),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_tokenize_comments_and_strings() {
+        assert_eq!(
+            kinds_and_text("print(\"#include \\\"x\\\"\"); // done\n/* a\n b */ # old\n"),
+            vec![
+                (TokenKind::Keyword, "print"),
+                (TokenKind::Punctuation, "("),
+                (TokenKind::StringLiteral, "\"#include \\\"x\\\"\""),
+                (TokenKind::Punctuation, ")"),
+                (TokenKind::Punctuation, ";"),
+                (TokenKind::LineComment, "// done"),
+                (TokenKind::BlockComment, "/* a\n b */"),
+                (TokenKind::HashComment, "# old"),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_tokenize_include_directives() {
+        assert_eq!(
+            kinds_and_text("#include \"lib.stan\"\n#include <x.stan>\n#includes"),
+            vec![
+                (TokenKind::Include, "#include \"lib.stan\""),
+                (TokenKind::Include, "#include <x.stan>"),
+                (TokenKind::HashComment, "#includes"),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_input_becomes_error_tokens() {
+        assert_eq!(
+            kinds_and_text("x @ \"open\n/* never closed"),
+            vec![
+                (TokenKind::Identifier, "x"),
+                (TokenKind::Error, "@"),
+                (TokenKind::Error, "\"open"),
+                (TokenKind::Error, "/* never closed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_index_finds_the_line_of_an_offset() {
+        let lines = LineIndex::new("data {\n\n  int N;\n}");
+        assert_eq!(lines.line(0), 0);
+        assert_eq!(lines.line(6), 0);
+        assert_eq!(lines.line(7), 1);
+        assert_eq!(lines.line(10), 2);
+        assert_eq!(lines.line(100), 3);
+        assert_eq!(lines.line_start(2), 8);
+    }
+
+    #[test]
+    fn include_lines_skip_commented_and_quoted_includes() {
+        let source = "functions {\n  #include \"a.stan\"\n  // #include \"b.stan\"\n/*\n#include \"c.stan\"\n*/\n  print(\"#include\");\n#include <d.stan>\n}";
+        assert_eq!(
+            include_lines(&tokenize(source), &LineIndex::new(source)),
+            vec![1, 7]
+        );
+    }
+
+    #[test]
+    fn include_guard_lines_skip_commented_guards() {
+        let source =
+            "#pragma once\n// #pragma once\n/*\n#pragma once\n*/\n  #pragma   once\n#pragma twice";
+        assert_eq!(
+            include_guard_lines(source, &tokenize(source), &LineIndex::new(source)),
+            vec![0, 5]
+        );
+    }
+
+    #[test]
+    fn significant_tokens_exclude_comments() {
+        let source = "x = 1; // one";
+        assert_eq!(significant(&tokenize(source)).len(), 4);
+    }
+}
@@ -1,19 +1,21 @@
+pub mod ast;
+pub mod data_validation;
+pub mod error;
+pub mod eval;
+pub mod fake_data;
+pub mod formatter;
+pub mod interface;
+pub mod json_schema;
+pub mod lexer;
+pub mod migration;
+pub mod parser;
+pub mod source_map;
 pub mod stan_model;
 pub mod stan_model_block;
 pub mod stan_model_block_type;
 pub mod stan_source_parser;
-// use pyo3::prelude::*;
+pub mod stanc_messages;
+pub mod transforms;
 
-/* /// Prints a message.
-#[pyfunction]
-fn hello() -> PyResult<String> {
-    Ok("Hello from stan-jammer!".into())
-}
-
-/// A Python module implemented in Rust.
-#[pymodule]
-fn _lowlevel(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(hello, m)?)?;
-    Ok(())
-}
- */
+#[cfg(feature = "python")]
+mod python;
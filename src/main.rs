@@ -0,0 +1,603 @@
+use std::fs::write;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+
+use stanjam::error::StanJamError;
+use stanjam::eval::Data;
+use stanjam::fake_data::fake_data;
+use stanjam::formatter::{format_source, FormatConfig};
+use stanjam::stan_source_parser::block_splitter::split_blocks_with_recovery;
+use stanjam::stan_source_parser::stan_source_parser::{
+    IncludeOnce, SourceParser, StanSourceParser,
+};
+use stanjam::stanc_messages::rewrite_stanc_message;
+
+/// Exit code for files that are missing or cannot be read or written.
+const EXIT_IO_ERROR: u8 = 1;
+/// Exit code for includes that cannot be resolved.
+const EXIT_INCLUDE_ERROR: u8 = 3;
+/// Exit code for programs whose block structure is invalid.
+const EXIT_SYNTAX_ERROR: u8 = 4;
+/// Exit code for `fmt --check` when a file is not formatted.
+const EXIT_UNFORMATTED: u8 = 5;
+/// Exit code for a data block that `fake-data` cannot satisfy.
+const EXIT_INVALID_DATA: u8 = 6;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "stanjam",
+    version,
+    about = "Tools for working with Stan programs"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Resolve every #include in a Stan program and write the flattened program.
+    Bundle {
+        /// The Stan program to bundle.
+        model: PathBuf,
+        /// A folder to search for included files; may be repeated.
+        #[arg(short = 'I', long = "include-path", value_name = "FOLDER")]
+        include_paths: Vec<PathBuf>,
+        /// Where to write the flattened program. Defaults to stdout.
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        /// Which files to inline only the first time they are included:
+        /// guarded, functions or always. Files with a `#pragma once` line are
+        /// always inlined once.
+        #[arg(long, value_name = "MODE", default_value = "functions", value_parser = parse_include_once)]
+        include_once: IncludeOnce,
+    },
+    /// Rewrite stanc errors and warnings for a bundled program, read from
+    /// stdin, to point at the original files and lines.
+    RewriteErrors {
+        /// The Stan program that was bundled and compiled.
+        model: PathBuf,
+        /// A folder to search for included files; may be repeated.
+        #[arg(short = 'I', long = "include-path", value_name = "FOLDER")]
+        include_paths: Vec<PathBuf>,
+        /// The --include-once mode the program was bundled with.
+        #[arg(long, value_name = "MODE", default_value = "functions", value_parser = parse_include_once)]
+        include_once: IncludeOnce,
+    },
+    /// Format Stan files in place.
+    Fmt {
+        /// The files to format.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// List the files that are not formatted instead of rewriting them.
+        #[arg(long)]
+        check: bool,
+        /// Spaces per level of indentation.
+        #[arg(long, value_name = "N", default_value_t = FormatConfig::default().indent_width)]
+        indent_width: usize,
+        /// Break statements longer than this after argument commas.
+        #[arg(long, value_name = "N", default_value_t = FormatConfig::default().max_line_length)]
+        max_line_length: usize,
+    },
+    /// Write random JSON data that satisfies a Stan program's data block.
+    FakeData {
+        /// The Stan program whose data block describes the data.
+        model: PathBuf,
+        /// A value to use instead of a random one, such as `N=50`; the value
+        /// is JSON. May be repeated.
+        #[arg(long = "size", value_name = "NAME=VALUE", value_parser = parse_given_value)]
+        sizes: Vec<(String, serde_json::Value)>,
+        /// The seed of the random number generator.
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// A folder to search for included files; may be repeated.
+        #[arg(short = 'I', long = "include-path", value_name = "FOLDER")]
+        include_paths: Vec<PathBuf>,
+        /// Where to write the data. Defaults to stdout.
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Bundle {
+            model,
+            include_paths,
+            output,
+            include_once,
+        } => bundle(&model, &include_paths, include_once)
+            .and_then(|flattened| emit(&flattened, &output)),
+        Command::RewriteErrors {
+            model,
+            include_paths,
+            include_once,
+        } => rewrite_errors(&model, &include_paths, include_once, io::stdin())
+            .and_then(|message| emit(&message, &None)),
+        Command::Fmt {
+            files,
+            check,
+            indent_width,
+            max_line_length,
+        } => {
+            let config = FormatConfig {
+                indent_width,
+                max_line_length,
+            };
+            format_files(&files, check, &config)
+        }
+        Command::FakeData {
+            model,
+            sizes,
+            seed,
+            include_paths,
+            output,
+        } => generate_data(&model, sizes.into_iter().collect(), seed, &include_paths)
+            .and_then(|data| emit(&data, &output)),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("stanjam: {}", error);
+            ExitCode::from(error.exit_code())
+        }
+    }
+}
+
+/// A failure of one of the subcommands.
+#[derive(Debug)]
+enum CliError {
+    Parse(StanJamError),
+    /// Every problem found in a program that could otherwise be read.
+    Diagnostics(Vec<StanJamError>),
+    /// Files that `fmt --check` found not to be formatted.
+    Unformatted(Vec<PathBuf>),
+    /// A data block that no data can satisfy, or whose sizes are unknown.
+    FakeData(String),
+    Output {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Parse(error) => parse_error_exit_code(error),
+            CliError::Diagnostics(_) => EXIT_SYNTAX_ERROR,
+            CliError::Unformatted(_) => EXIT_UNFORMATTED,
+            CliError::FakeData(_) => EXIT_INVALID_DATA,
+            CliError::Output { .. } => EXIT_IO_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Parse(error) => write!(f, "{}", error),
+            CliError::Diagnostics(diagnostics) => write_problems(f, diagnostics),
+            CliError::FakeData(message) => write!(f, "{}", message),
+            CliError::Unformatted(paths) => {
+                write!(f, "{} file(s) would be reformatted", paths.len())?;
+                for path in paths {
+                    write!(f, "\n{}", path.display())?;
+                }
+                Ok(())
+            }
+            CliError::Output {
+                path: Some(path),
+                source,
+            } => write!(f, "could not write '{}': {}", path.display(), source),
+            CliError::Output { path: None, source } => {
+                write!(f, "could not write to stdout: {}", source)
+            }
+        }
+    }
+}
+
+/// A count of `problems` followed by each problem on its own line.
+fn write_problems<T: std::fmt::Display>(
+    f: &mut std::fmt::Formatter<'_>,
+    problems: &[T],
+) -> std::fmt::Result {
+    let noun = if problems.len() == 1 {
+        "problem"
+    } else {
+        "problems"
+    };
+    write!(f, "found {} {}", problems.len(), noun)?;
+    for problem in problems {
+        write!(f, "\n{}", problem)?;
+    }
+    Ok(())
+}
+
+impl From<StanJamError> for CliError {
+    fn from(error: StanJamError) -> CliError {
+        CliError::Parse(error)
+    }
+}
+
+fn parse_error_exit_code(error: &StanJamError) -> u8 {
+    match error {
+        StanJamError::MissingFile {
+            included_from: None,
+            ..
+        }
+        | StanJamError::UnreadableFile { .. } => EXIT_IO_ERROR,
+        StanJamError::MissingFile { .. }
+        | StanJamError::IncludeNotFound { .. }
+        | StanJamError::MalformedInclude { .. }
+        | StanJamError::IncludeCycle { .. } => EXIT_INCLUDE_ERROR,
+        StanJamError::UnbalancedBraces { .. }
+        | StanJamError::UnknownBlock { .. }
+        | StanJamError::DuplicateBlock { .. }
+        | StanJamError::SyntaxError { .. } => EXIT_SYNTAX_ERROR,
+        StanJamError::SizeError { .. } => EXIT_INVALID_DATA,
+    }
+}
+
+/// A parser for `model` that searches `include_paths` after the folder of
+/// the including file.
+fn source_parser(model: &std::path::Path, include_paths: &[PathBuf]) -> StanSourceParser {
+    let mut parser = StanSourceParser::new(&model.to_string_lossy());
+    include_paths
+        .iter()
+        .for_each(|folder| parser.add_folder(&folder.to_string_lossy()));
+    parser
+}
+
+/// Flatten `model`, failing with every block structure error in the result
+/// rather than only the first. Statements are left for stanc to check.
+fn bundle(
+    model: &std::path::Path,
+    include_paths: &[PathBuf],
+    include_once: IncludeOnce,
+) -> Result<String, CliError> {
+    let mut parser = source_parser(model, include_paths);
+    parser.include_once = include_once;
+    let (flattened, source_map) = parser.flatten_with_source_map()?;
+    let (_, diagnostics) = split_blocks_with_recovery(&flattened, &source_map);
+    if !diagnostics.is_empty() {
+        return Err(CliError::Diagnostics(diagnostics));
+    }
+    Ok(flattened)
+}
+
+/// The stanc output read from `input` for the bundled `model`, rewritten to
+/// point at the original files and lines.
+fn rewrite_errors(
+    model: &std::path::Path,
+    include_paths: &[PathBuf],
+    include_once: IncludeOnce,
+    input: impl io::Read,
+) -> Result<String, CliError> {
+    let message = io::read_to_string(input)
+        .map_err(|error| StanJamError::from_io(std::path::Path::new("<stdin>"), None, error))?;
+    let mut parser = source_parser(model, include_paths);
+    parser.include_once = include_once;
+    let (_, source_map) = parser.flatten_with_source_map()?;
+    Ok(rewrite_stanc_message(&message, &source_map))
+}
+
+fn parse_include_once(argument: &str) -> Result<IncludeOnce, String> {
+    IncludeOnce::from_name(argument).ok_or_else(|| {
+        format!(
+            "expected one of {}, found '{}'",
+            IncludeOnce::ALL
+                .map(|include_once| include_once.name())
+                .join(", "),
+            argument
+        )
+    })
+}
+
+/// Parse a `NAME=VALUE` argument whose value is JSON.
+fn parse_given_value(argument: &str) -> Result<(String, serde_json::Value), String> {
+    let (name, value) = argument
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, found '{}'", argument))?;
+    let value = serde_json::from_str(value)
+        .map_err(|error| format!("the value of '{}' is not JSON: {}", name, error))?;
+    Ok((name.trim().to_string(), value))
+}
+
+/// Random data for `model`, using the `given` values, as JSON text.
+fn generate_data(
+    model: &std::path::Path,
+    given: Data,
+    seed: u64,
+    include_paths: &[PathBuf],
+) -> Result<String, CliError> {
+    let variables = source_parser(model, include_paths)
+        .read_file()?
+        .data_interface()?;
+    let data = fake_data(&variables, &given, seed).map_err(CliError::FakeData)?;
+    Ok(serde_json::to_string_pretty(&data).expect("JSON values always serialize"))
+}
+
+/// Format each file in place or, with `check`, fail listing the files that
+/// would change. Files that do not parse are left untouched.
+fn format_files(files: &[PathBuf], check: bool, config: &FormatConfig) -> Result<(), CliError> {
+    let mut unformatted = Vec::new();
+    for path in files {
+        let source = std::fs::read_to_string(path)
+            .map_err(|error| StanJamError::from_io(path, None, error))?;
+        let formatted = format_source(&source, path, config).map_err(CliError::Diagnostics)?;
+        if formatted == source {
+            continue;
+        }
+        if check {
+            unformatted.push(path.clone());
+        } else {
+            write(path, formatted).map_err(|source| CliError::Output {
+                path: Some(path.clone()),
+                source,
+            })?;
+        }
+    }
+
+    if unformatted.is_empty() {
+        Ok(())
+    } else {
+        Err(CliError::Unformatted(unformatted))
+    }
+}
+
+fn emit(text: &str, output: &Option<PathBuf>) -> Result<(), CliError> {
+    let text = format!("{}\n", text.trim_end());
+    match output {
+        Some(path) => write(path, text).map_err(|source| CliError::Output {
+            path: Some(path.clone()),
+            source,
+        }),
+        None => io::stdout()
+            .write_all(text.as_bytes())
+            .map_err(|source| CliError::Output { path: None, source }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+    use tempfile::tempdir;
+
+    #[test]
+    fn bundle_arguments_are_parsed() {
+        let cli = Cli::parse_from([
+            "stanjam",
+            "bundle",
+            "model.stan",
+            "-I",
+            "lib/",
+            "-I",
+            "shared/",
+            "-o",
+            "flat.stan",
+        ]);
+
+        match cli.command {
+            Command::Bundle {
+                model,
+                include_paths,
+                output,
+                include_once,
+            } => {
+                assert_eq!(model, PathBuf::from("model.stan"));
+                assert_eq!(
+                    include_paths,
+                    vec![PathBuf::from("lib/"), PathBuf::from("shared/")]
+                );
+                assert_eq!(output, Some(PathBuf::from("flat.stan")));
+                assert_eq!(include_once, IncludeOnce::Functions);
+            }
+            other => panic!("expected the bundle command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bundle_writes_the_flattened_model() {
+        let dir = tempdir().unwrap();
+        let lib = dir.path().join("lib");
+        std::fs::create_dir(&lib).unwrap();
+        write(lib.join("f.stan"), "real f(real x) {\n  return x;\n}").unwrap();
+        let model = dir.path().join("model.stan");
+        write(&model, "functions {\n#include \"f.stan\"\n}\nmodel {\n}\n").unwrap();
+        let output = dir.path().join("flat.stan");
+
+        let flattened = bundle(&model, std::slice::from_ref(&lib), IncludeOnce::default()).unwrap();
+        emit(&flattened, &Some(output.clone())).unwrap();
+
+        assert_eq!(
+            read_to_string(output).unwrap(),
+            "functions {\nreal f(real x) {\n  return x;\n}\n}\nmodel {\n}\n"
+        );
+    }
+
+    #[test]
+    fn stanc_errors_are_rewritten_to_the_original_files() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("f.stan"), "real f(real x) {\n  return x\n}").unwrap();
+        let model = dir.path().join("model.stan");
+        write(&model, "functions {\n#include \"f.stan\"\n}\nmodel {\n}\n").unwrap();
+        let stanc = "Syntax error in 'flat.stan', line 3, column 10, parsing error:";
+
+        let rewritten =
+            rewrite_errors(&model, &[], IncludeOnce::default(), stanc.as_bytes()).unwrap();
+
+        assert_eq!(
+            rewritten,
+            format!(
+                "Syntax error in '{}', line 2, column 10, parsing error:",
+                dir.path().join("f.stan").display()
+            )
+        );
+    }
+
+    #[test]
+    fn bundle_reports_the_errors_of_every_block() {
+        let dir = tempdir().unwrap();
+        let model = dir.path().join("model.stan");
+        write(
+            &model,
+            "data {\n  int N;\n}\nmodle {\n}\ndata {\n  int M;\n}\n",
+        )
+        .unwrap();
+
+        let error = bundle(&model, &[], IncludeOnce::default()).unwrap_err();
+
+        assert_eq!(error.exit_code(), EXIT_SYNTAX_ERROR);
+        let path = model.display();
+        assert_eq!(
+            error.to_string(),
+            format!("found 2 problems\n{path}:4:1: unknown block 'modle'\n{path}:6:1: duplicate block 'data'")
+        );
+    }
+
+    #[test]
+    fn bundle_accepts_offset_as_a_data_variable() {
+        let dir = tempdir().unwrap();
+        let model = dir.path().join("model.stan");
+        let source = "data {\n  int N;\n  vector[N] offset;\n  array[N] int y;\n}\nparameters {\n  real beta;\n}\nmodel {\n  y ~ poisson_log(offset + beta);\n}";
+        write(&model, source).unwrap();
+
+        assert_eq!(bundle(&model, &[], IncludeOnce::default()).unwrap(), source);
+    }
+
+    #[test]
+    fn include_and_syntax_errors_have_different_exit_codes() {
+        let dir = tempdir().unwrap();
+        let missing_include = dir.path().join("missing_include.stan");
+        write(&missing_include, "functions {\n#include \"nope.stan\"\n}").unwrap();
+        let bad_syntax = dir.path().join("bad_syntax.stan");
+        write(&bad_syntax, "model {\n").unwrap();
+
+        let exit_code = |path: &std::path::Path| {
+            bundle(path, &[], IncludeOnce::default())
+                .unwrap_err()
+                .exit_code()
+        };
+
+        assert_eq!(exit_code(&missing_include), EXIT_INCLUDE_ERROR);
+        assert_eq!(exit_code(&bad_syntax), EXIT_SYNTAX_ERROR);
+        assert_eq!(exit_code(&dir.path().join("absent.stan")), EXIT_IO_ERROR);
+    }
+
+    #[test]
+    fn fmt_arguments_are_parsed() {
+        let cli = Cli::parse_from([
+            "stanjam",
+            "fmt",
+            "a.stan",
+            "b.stan",
+            "--check",
+            "--indent-width",
+            "4",
+        ]);
+
+        match cli.command {
+            Command::Fmt {
+                files,
+                check,
+                indent_width,
+                max_line_length,
+            } => {
+                assert_eq!(
+                    files,
+                    vec![PathBuf::from("a.stan"), PathBuf::from("b.stan")]
+                );
+                assert!(check);
+                assert_eq!(indent_width, 4);
+                assert_eq!(max_line_length, 80);
+            }
+            other => panic!("expected the fmt command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fmt_check_fails_on_unformatted_files_without_rewriting_them() {
+        let dir = tempdir().unwrap();
+        let formatted = dir.path().join("formatted.stan");
+        write(&formatted, "model {\n  y ~ normal(0, 1);\n}\n").unwrap();
+        let messy = dir.path().join("messy.stan");
+        write(&messy, "model {\ny~normal(0,1);\n}\n").unwrap();
+        let files = vec![formatted.clone(), messy.clone()];
+
+        let error = format_files(&files, true, &FormatConfig::default()).unwrap_err();
+
+        assert_eq!(error.exit_code(), EXIT_UNFORMATTED);
+        assert!(matches!(&error, CliError::Unformatted(paths) if *paths == vec![messy.clone()]));
+        assert_eq!(
+            read_to_string(&messy).unwrap(),
+            "model {\ny~normal(0,1);\n}\n"
+        );
+
+        format_files(&files, false, &FormatConfig::default()).unwrap();
+        assert_eq!(
+            read_to_string(&messy).unwrap(),
+            "model {\n  y ~ normal(0, 1);\n}\n"
+        );
+        assert!(format_files(&files, true, &FormatConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn fake_data_arguments_are_parsed() {
+        let cli = Cli::parse_from([
+            "stanjam",
+            "fake-data",
+            "model.stan",
+            "--size",
+            "N=50",
+            "--size",
+            "x=[1.5, 2]",
+            "--seed",
+            "3",
+        ]);
+
+        match cli.command {
+            Command::FakeData { sizes, seed, .. } => {
+                assert_eq!(
+                    sizes,
+                    vec![
+                        ("N".to_string(), serde_json::json!(50)),
+                        ("x".to_string(), serde_json::json!([1.5, 2])),
+                    ]
+                );
+                assert_eq!(seed, 3);
+            }
+            other => panic!("expected the fake-data command, got {:?}", other),
+        }
+        assert!(parse_given_value("N").is_err());
+    }
+
+    #[test]
+    fn fake_data_is_valid_and_reproducible() {
+        let dir = tempdir().unwrap();
+        let model = dir.path().join("model.stan");
+        write(
+            &model,
+            "data {\n  int<lower=0> N;\n  array[N] real<lower=0> y;\n}\nmodel {\n}\n",
+        )
+        .unwrap();
+        let given = stanjam::data_validation::parse_data(r#"{"N": 5}"#).unwrap();
+
+        let text = generate_data(&model, given.clone(), 1, &[]).unwrap();
+        let data = stanjam::data_validation::parse_data(&text).unwrap();
+
+        assert_eq!(data["y"].as_array().unwrap().len(), 5);
+        assert_eq!(generate_data(&model, given, 1, &[]).unwrap(), text);
+        let problems = source_parser(&model, &[])
+            .read_file()
+            .unwrap()
+            .validate_data(&data)
+            .unwrap();
+        assert_eq!(problems, vec![]);
+    }
+}
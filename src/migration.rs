@@ -0,0 +1,835 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::slice;
+
+use crate::ast::{BaseType, Stmt, StmtKind, VarDecl};
+use crate::error::SourceLocation;
+use crate::lexer::{tokenize, LineIndex, Token, TokenKind};
+use crate::parser::parse_statements_with_recovery;
+use crate::stan_model::StanModel;
+use crate::stan_model_block::StanModelBlock;
+use crate::stan_source_parser::block_splitter::{is_program, program_blocks};
+
+/// The kinds of removed syntax that `migrate` rewrites.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MigrationKind {
+    /// `int y[N];` becomes `array[N] int y;`, and `real[]` in a function
+    /// signature becomes `array[] real`.
+    ArrayDeclaration,
+    /// `x <- 1;` becomes `x = 1;`.
+    ArrowAssignment,
+    /// `increment_log_prob(x);` becomes `target += x;`.
+    IncrementLogProb,
+    /// `get_lp()` becomes `target()`.
+    GetLp,
+    /// `# comment` becomes `// comment`.
+    HashComment,
+    /// `normal_log` becomes `normal_lpdf`, `poisson_log` becomes
+    /// `poisson_lpmf`, `_cdf_log` becomes `_lcdf` and `_ccdf_log` becomes
+    /// `_lccdf`.
+    DensitySuffix,
+    /// `if_else(c, a, b)` becomes `c ? a : b`.
+    IfElse,
+}
+
+impl MigrationKind {
+    pub fn description(&self) -> &'static str {
+        match self {
+            MigrationKind::ArrayDeclaration => "old array syntax",
+            MigrationKind::ArrowAssignment => "'<-' assignment",
+            MigrationKind::IncrementLogProb => "increment_log_prob",
+            MigrationKind::GetLp => "get_lp()",
+            MigrationKind::HashComment => "'#' comment",
+            MigrationKind::DensitySuffix => "deprecated density suffix",
+            MigrationKind::IfElse => "if_else",
+        }
+    }
+}
+
+/// One rewrite made by a migration.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Migration {
+    pub kind: MigrationKind,
+    pub location: SourceLocation,
+    pub original: String,
+    pub replacement: String,
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: '{}' -> '{}'",
+            self.location,
+            self.kind.description(),
+            self.original,
+            self.replacement
+        )
+    }
+}
+
+/// Built-in distributions over continuous outcomes, whose `_log` densities
+/// are now `_lpdf`.
+const CONTINUOUS_DISTRIBUTIONS: &[&str] = &[
+    "normal",
+    "normal_id_glm",
+    "std_normal",
+    "skew_normal",
+    "student_t",
+    "cauchy",
+    "double_exponential",
+    "logistic",
+    "gumbel",
+    "skew_double_exponential",
+    "lognormal",
+    "chi_square",
+    "inv_chi_square",
+    "scaled_inv_chi_square",
+    "exponential",
+    "exp_mod_normal",
+    "gamma",
+    "inv_gamma",
+    "weibull",
+    "frechet",
+    "rayleigh",
+    "pareto",
+    "pareto_type_2",
+    "loglogistic",
+    "wiener",
+    "beta",
+    "beta_proportion",
+    "von_mises",
+    "uniform",
+    "multi_normal",
+    "multi_normal_prec",
+    "multi_normal_cholesky",
+    "multi_gp",
+    "multi_gp_cholesky",
+    "multi_student_t",
+    "multi_student_t_cholesky",
+    "gaussian_dlm_obs",
+    "dirichlet",
+    "lkj_corr",
+    "lkj_corr_cholesky",
+    "lkj_cov",
+    "wishart",
+    "wishart_cholesky",
+    "inv_wishart",
+    "inv_wishart_cholesky",
+];
+
+/// Built-in distributions over discrete outcomes, whose `_log` masses are
+/// now `_lpmf`.
+const DISCRETE_DISTRIBUTIONS: &[&str] = &[
+    "bernoulli",
+    "bernoulli_logit",
+    "bernoulli_logit_glm",
+    "binomial",
+    "binomial_logit",
+    "beta_binomial",
+    "hypergeometric",
+    "categorical",
+    "categorical_logit",
+    "categorical_logit_glm",
+    "discrete_range",
+    "ordered_logistic",
+    "ordered_probit",
+    "neg_binomial",
+    "neg_binomial_2",
+    "neg_binomial_2_log",
+    "neg_binomial_2_log_glm",
+    "poisson",
+    "poisson_log",
+    "poisson_log_glm",
+    "multinomial",
+    "multinomial_logit",
+    "beta_neg_binomial",
+];
+
+/// Rewrite removed syntax in every block of `model`, returning the migrated
+/// model and every change, located through the blocks' line origins.
+///
+/// User-defined densities named `*_log` in the functions block are renamed,
+/// together with their uses, to `_lpmf` when their first argument is an
+/// `int` and to `_lpdf` otherwise.
+pub fn migrate(model: &StanModel) -> (StanModel, Vec<Migration>) {
+    let densities = model
+        .functions
+        .as_ref()
+        .map(|functions| user_densities(&functions.source()))
+        .unwrap_or_default();
+
+    let mut migrations = Vec::new();
+    let migrated = model.map_blocks(|block| {
+        let source = block.source();
+        let whole = 0..source.len();
+        let (text, edits) = migrate_text(&source, slice::from_ref(&whole), &densities);
+        migrations.extend(edits.into_iter().map(|edit| Migration {
+            kind: edit.kind,
+            location: block.location_at(edit.line, edit.column),
+            original: edit.original,
+            replacement: edit.replacement,
+        }));
+        rebuild_block(block, &text)
+    });
+    (migrated, migrations)
+}
+
+/// Rewrite removed syntax in the text of a file, which may be a whole
+/// program or a fragment such as an included file of functions. Comments and
+/// layout outside the rewritten code are kept. Changes are located in
+/// `path`.
+pub fn migrate_source(source: &str, path: &Path) -> (String, Vec<Migration>) {
+    let densities = user_densities(source);
+    let (text, edits) = if is_program(source) {
+        let (blocks, _) = program_blocks(source);
+        let bodies = blocks
+            .iter()
+            .map(|block| block.body_start..block.body_end)
+            .collect::<Vec<Range<usize>>>();
+        migrate_text(source, &bodies, &densities)
+    } else {
+        migrate_text(source, slice::from_ref(&(0..source.len())), &densities)
+    };
+
+    let migrations = edits
+        .into_iter()
+        .map(|edit| Migration {
+            location: SourceLocation::new(path, edit.line + 1, edit.column),
+            kind: edit.kind,
+            original: edit.original,
+            replacement: edit.replacement,
+        })
+        .collect();
+    (text, migrations)
+}
+
+/// A copy of `block` with the migrated `text` of its source, keeping each
+/// line's origin.
+fn rebuild_block(block: &StanModelBlock, text: &str) -> StanModelBlock {
+    let mut rebuilt = StanModelBlock::new(block.get_block_type().clone());
+    let origins = block
+        .get_code_with_origins()
+        .map(|(_, origin)| origin.cloned());
+    for (line, origin) in text.split('\n').zip(origins) {
+        let line = line.trim();
+        if !line.is_empty() {
+            rebuilt.add_with_origin(line, origin);
+        }
+    }
+    rebuilt
+}
+
+/// A rewrite found in one pass, with its 0-based line and 1-based column.
+/// Passes never change the line count, so the line holds in every pass.
+struct AppliedEdit {
+    kind: MigrationKind,
+    line: usize,
+    column: usize,
+    original: String,
+    replacement: String,
+}
+
+struct Edit {
+    kind: MigrationKind,
+    span: Range<usize>,
+    replacement: String,
+}
+
+/// Passes are repeated so that rewrites nested inside other rewrites, such as
+/// `get_lp()` inside `if_else(...)`, are made too.
+const MAX_PASSES: usize = 8;
+
+/// Apply every rewrite to `source` until nothing changes. Old array
+/// declarations are only looked for in the `statement_ranges`.
+fn migrate_text(
+    source: &str,
+    statement_ranges: &[Range<usize>],
+    densities: &HashMap<String, &'static str>,
+) -> (String, Vec<AppliedEdit>) {
+    let mut text = source.to_string();
+    let mut ranges = statement_ranges.to_vec();
+    let mut applied = Vec::new();
+
+    for _ in 0..MAX_PASSES {
+        let mut edits = token_edits(&text, densities);
+        for range in &ranges {
+            edits.extend(declaration_edits(&text, range.clone()));
+        }
+        edits.sort_by_key(|edit| edit.span.start);
+
+        let mut selected: Vec<Edit> = Vec::new();
+        for edit in edits {
+            if selected
+                .last()
+                .is_none_or(|previous| previous.span.end <= edit.span.start)
+            {
+                selected.push(edit);
+            }
+        }
+        if selected.is_empty() {
+            break;
+        }
+
+        let lines = LineIndex::new(&text);
+        for edit in &selected {
+            let line = lines.line(edit.span.start);
+            applied.push(AppliedEdit {
+                kind: edit.kind,
+                line,
+                column: text[lines.line_start(line)..edit.span.start]
+                    .chars()
+                    .count()
+                    + 1,
+                original: text[edit.span.clone()].to_string(),
+                replacement: edit.replacement.clone(),
+            });
+        }
+        for edit in selected.iter().rev() {
+            let replacement = keep_line_count(&text[edit.span.clone()], &edit.replacement);
+            let growth = replacement.len() as isize - edit.span.len() as isize;
+            text.replace_range(edit.span.clone(), &replacement);
+            for range in ranges.iter_mut() {
+                if range.start >= edit.span.end {
+                    range.start = range.start.saturating_add_signed(growth);
+                }
+                if range.end >= edit.span.end {
+                    range.end = range.end.saturating_add_signed(growth);
+                }
+            }
+        }
+    }
+
+    // Line counts never change, so edits from different passes can be put in
+    // source order by their line and column.
+    applied.sort_by_key(|edit| (edit.line, edit.column));
+    (text, applied)
+}
+
+/// Pad `replacement` with newlines so that it spans as many lines as
+/// `original`, which keeps line origins and locations valid.
+fn keep_line_count(original: &str, replacement: &str) -> String {
+    let missing = original
+        .matches('\n')
+        .count()
+        .saturating_sub(replacement.matches('\n').count());
+    format!("{}{}", replacement, "\n".repeat(missing))
+}
+
+/// The user-defined `*_log` densities in `source`, with the suffix each
+/// should now have: `_lpmf` when the first argument is an `int`.
+fn user_densities(source: &str) -> HashMap<String, &'static str> {
+    let tokens = significant_tokens(source);
+    let mut densities = HashMap::new();
+    for window in tokens.windows(4) {
+        let [before, name, open, first] = window else {
+            continue;
+        };
+        let name_text = name.text(source);
+        let is_definition =
+            BaseType::from_keyword(before.text(source)).is_some() || before.text(source) == "]";
+        if name.kind != TokenKind::Identifier
+            || !name_text.ends_with("_log")
+            || open.text(source) != "("
+            || !is_definition
+        {
+            continue;
+        }
+        let first_type = if first.text(source) == "data" {
+            tokens
+                .iter()
+                .skip_while(|token| token.span != first.span)
+                .nth(1)
+                .map(|token| token.text(source))
+        } else {
+            Some(first.text(source))
+        };
+        let suffix = if first_type == Some("int") {
+            "_lpmf"
+        } else {
+            "_lpdf"
+        };
+        densities.insert(name_text.to_string(), suffix);
+    }
+    densities
+}
+
+fn significant_tokens(source: &str) -> Vec<Token> {
+    tokenize(source)
+        .into_iter()
+        .filter(|token| !token.is_comment())
+        .collect()
+}
+
+/// The index of the token closing the bracket opened at `open`.
+fn matching_close(tokens: &[Token], source: &str, open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (index, token) in tokens.iter().enumerate().skip(open) {
+        if token.kind != TokenKind::Punctuation {
+            continue;
+        }
+        match token.text(source) {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Whether the tokens before `index` are a variable, possibly indexed, that
+/// starts a statement, so that a `<-` at `index` is an assignment rather
+/// than a comparison with a negative number, as in `x = y <-1;`.
+fn follows_statement_lvalue(tokens: &[Token], source: &str, index: usize) -> bool {
+    let text = |index: usize| tokens[index].text(source);
+    let mut position = index;
+    while position > 0 && text(position - 1) == "]" {
+        let mut depth = 0usize;
+        loop {
+            position -= 1;
+            match text(position) {
+                "]" => depth += 1,
+                "[" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            if position == 0 {
+                return false;
+            }
+        }
+    }
+    if position == 0 || tokens[position - 1].kind != TokenKind::Identifier {
+        return false;
+    }
+    position -= 1;
+    position == 0 || matches!(text(position - 1), ";" | "{" | "}" | ")" | "else")
+}
+
+fn is_distribution(name: &str) -> bool {
+    CONTINUOUS_DISTRIBUTIONS.contains(&name) || DISCRETE_DISTRIBUTIONS.contains(&name)
+}
+
+/// The new name of a removed density function, if `name` is one.
+fn density_rename(name: &str, densities: &HashMap<String, &'static str>) -> Option<String> {
+    if let Some(base) = name.strip_suffix("_ccdf_log") {
+        return Some(format!("{}_lccdf", base));
+    }
+    if let Some(base) = name.strip_suffix("_cdf_log") {
+        return Some(format!("{}_lcdf", base));
+    }
+    if let Some(suffix) = densities.get(name) {
+        return Some(format!("{}{}", name.strip_suffix("_log")?, suffix));
+    }
+    let base = name.strip_suffix("_log")?;
+    // Names such as `poisson_log` are distributions in their own right.
+    if is_distribution(name) {
+        None
+    } else if CONTINUOUS_DISTRIBUTIONS.contains(&base) {
+        Some(format!("{}_lpdf", base))
+    } else if DISCRETE_DISTRIBUTIONS.contains(&base) {
+        Some(format!("{}_lpmf", base))
+    } else {
+        None
+    }
+}
+
+/// Rewrites that only need the tokens of the source.
+fn token_edits(source: &str, densities: &HashMap<String, &'static str>) -> Vec<Edit> {
+    let mut edits = tokenize(source)
+        .into_iter()
+        .filter(|token| token.kind == TokenKind::HashComment)
+        .map(|token| Edit {
+            kind: MigrationKind::HashComment,
+            span: token.span.range(),
+            replacement: format!("//{}", &token.text(source)[1..]),
+        })
+        .collect::<Vec<Edit>>();
+
+    let tokens = significant_tokens(source);
+    let text = |index: usize| tokens.get(index).map(|token| token.text(source));
+
+    for (index, token) in tokens.iter().enumerate() {
+        let token_text = token.text(source);
+        match (token.kind, token_text) {
+            (TokenKind::Operator, "<")
+                if text(index + 1) == Some("-")
+                    && tokens[index + 1].span.start == token.span.end
+                    && follows_statement_lvalue(&tokens, source, index) =>
+            {
+                edits.push(Edit {
+                    kind: MigrationKind::ArrowAssignment,
+                    span: token.span.start..tokens[index + 1].span.end,
+                    replacement: "=".to_string(),
+                });
+            }
+            (TokenKind::Identifier, name) if text(index + 1) == Some("(") => {
+                let Some(close) = matching_close(&tokens, source, index + 1) else {
+                    continue;
+                };
+                let inner = &source[tokens[index + 1].span.end..tokens[close].span.start];
+                let span = token.span.start..tokens[close].span.end;
+                match name {
+                    "increment_log_prob" if text(close + 1) == Some(";") => edits.push(Edit {
+                        kind: MigrationKind::IncrementLogProb,
+                        span,
+                        replacement: format!("target += {}", inner.trim()),
+                    }),
+                    "get_lp" if close == index + 2 => edits.push(Edit {
+                        kind: MigrationKind::GetLp,
+                        span,
+                        replacement: "target()".to_string(),
+                    }),
+                    "if_else" => {
+                        if let Some(replacement) =
+                            if_else_replacement(&tokens, source, index, close)
+                        {
+                            edits.push(Edit {
+                                kind: MigrationKind::IfElse,
+                                span,
+                                replacement,
+                            });
+                        }
+                    }
+                    // The distribution of a `~` statement is named
+                    // without a suffix.
+                    _ if index > 0 && text(index - 1) == Some("~") => {}
+                    name => {
+                        let Some(renamed) = density_rename(name, densities) else {
+                            continue;
+                        };
+                        let is_definition = index > 0
+                            && text(index - 1).is_some_and(|before| {
+                                BaseType::from_keyword(before).is_some() || before == "]"
+                            });
+                        edits.push(if is_definition {
+                            Edit {
+                                kind: MigrationKind::DensitySuffix,
+                                span: token.span.range(),
+                                replacement: renamed,
+                            }
+                        } else {
+                            Edit {
+                                kind: MigrationKind::DensitySuffix,
+                                span,
+                                replacement: density_call(
+                                    &renamed,
+                                    &call_arguments(&tokens, source, index, close),
+                                ),
+                            }
+                        });
+                    }
+                }
+            }
+            (TokenKind::Keyword, base) if BaseType::from_keyword(base).is_some() => {
+                if let Some(edit) = unsized_array_edit(&tokens, source, index) {
+                    edits.push(edit);
+                }
+            }
+            _ => {}
+        }
+    }
+    edits
+}
+
+/// The source text of each argument of the call spanning tokens
+/// `name..=close`.
+fn call_arguments<'a>(
+    tokens: &[Token],
+    source: &'a str,
+    name: usize,
+    close: usize,
+) -> Vec<&'a str> {
+    let mut args = Vec::new();
+    let mut start = tokens[name + 1].span.end;
+    let mut depth = 0usize;
+    for token in &tokens[name + 2..close] {
+        match (token.kind, token.text(source)) {
+            (TokenKind::Punctuation, "(" | "[" | "{") => depth += 1,
+            (TokenKind::Punctuation, ")" | "]" | "}") => depth -= 1,
+            (TokenKind::Punctuation, ",") if depth == 0 => {
+                args.push(source[start..token.span.start].trim());
+                start = token.span.end;
+            }
+            _ => {}
+        }
+    }
+    let last = source[start..tokens[close].span.start].trim();
+    if !last.is_empty() || !args.is_empty() {
+        args.push(last);
+    }
+    args
+}
+
+/// A call of the density `name`, which now takes its outcome before a `|`.
+fn density_call(name: &str, args: &[&str]) -> String {
+    match args.split_first() {
+        Some((outcome, rest)) if !rest.is_empty() => {
+            format!("{}({} | {})", name, outcome, rest.join(", "))
+        }
+        _ => format!("{}({})", name, args.join(", ")),
+    }
+}
+
+/// `c ? a : b` for the call `if_else(c, a, b)` spanning tokens
+/// `name..=close`, parenthesized unless it is a whole expression.
+fn if_else_replacement(
+    tokens: &[Token],
+    source: &str,
+    name: usize,
+    close: usize,
+) -> Option<String> {
+    let args = call_arguments(tokens, source, name, close);
+    let [condition, if_true, if_false] = args[..] else {
+        return None;
+    };
+    let operand = |arg: &str| {
+        if arg.contains('?') {
+            format!("({})", arg)
+        } else {
+            arg.to_string()
+        }
+    };
+    let ternary = format!(
+        "{} ? {} : {}",
+        operand(condition),
+        operand(if_true),
+        operand(if_false)
+    );
+
+    let before = name.checked_sub(1).map(|index| tokens[index].text(source));
+    let after = tokens.get(close + 1).map(|token| token.text(source));
+    let starts_expression = before.is_none_or(|before| {
+        matches!(
+            before,
+            "=" | "+=" | "-=" | "*=" | "/=" | "(" | "," | "[" | "{" | "return" | "-"
+        )
+    });
+    let ends_expression = after.is_none_or(|after| matches!(after, ";" | "," | ")" | "]" | "}"));
+    if starts_expression && ends_expression {
+        Some(ternary)
+    } else {
+        Some(format!("({})", ternary))
+    }
+}
+
+/// `array[,] real` for the function argument or return type `real[,]`.
+fn unsized_array_edit(tokens: &[Token], source: &str, index: usize) -> Option<Edit> {
+    if tokens.get(index + 1)?.text(source) != "[" {
+        return None;
+    }
+    let mut commas = String::new();
+    let mut close = index + 2;
+    while tokens.get(close)?.text(source) == "," {
+        commas.push(',');
+        close += 1;
+    }
+    if tokens[close].text(source) != "]" {
+        return None;
+    }
+    Some(Edit {
+        kind: MigrationKind::ArrayDeclaration,
+        span: tokens[index].span.start..tokens[close].span.end,
+        replacement: format!("array[{}] {}", commas, tokens[index].text(source)),
+    })
+}
+
+/// Rewrites of declarations such as `real y[N];` among the statements in
+/// `range` of `source`.
+fn declaration_edits(source: &str, range: Range<usize>) -> Vec<Edit> {
+    let body = &source[range.clone()];
+    let (statements, _) = parse_statements_with_recovery(body);
+    let mut edits = Vec::new();
+    for statement in &statements {
+        statement.walk(&mut |statement: &Stmt| {
+            if let StmtKind::VarDecl(decl) = &statement.kind {
+                if let Some(replacement) = modern_declaration(body, decl) {
+                    edits.push(Edit {
+                        kind: MigrationKind::ArrayDeclaration,
+                        span: range.start + statement.span.start..range.start + statement.span.end,
+                        replacement,
+                    });
+                }
+            }
+        });
+    }
+    edits
+}
+
+/// The declaration in current syntax, with one declaration per variable, if
+/// any of its variables uses dimensions after the name.
+fn modern_declaration(source: &str, decl: &VarDecl) -> Option<String> {
+    if decl
+        .declarators
+        .iter()
+        .all(|declarator| declarator.legacy_array_dims.is_empty())
+    {
+        return None;
+    }
+    let ty = &source[decl.ty.span.range()];
+    let declarations = decl
+        .declarators
+        .iter()
+        .map(|declarator| {
+            let mut declaration = String::new();
+            if let (Some(first), Some(last)) = (
+                declarator.legacy_array_dims.first(),
+                declarator.legacy_array_dims.last(),
+            ) {
+                declaration.push_str(&format!(
+                    "array[{}] ",
+                    &source[first.span.start..last.span.end]
+                ));
+            }
+            declaration.push_str(&format!("{} {}", ty, declarator.name));
+            if let Some(init) = &declarator.init {
+                declaration.push_str(&format!(" = {}", &source[init.span.range()]));
+            }
+            declaration.push(';');
+            declaration
+        })
+        .collect::<Vec<String>>();
+    Some(declarations.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stan_source_parser::block_splitter::split_blocks;
+
+    fn migrate_text_only(source: &str) -> String {
+        migrate_source(source, Path::new("model.stan")).0
+    }
+
+    #[test]
+    fn can_migrate_old_array_declarations() {
+        let source = "data {\n  int N;\n  int y[N];\n  real x[N, 2], z;\n  vector[3] v[N] = rep_array(rep_vector(0, 3), N);\n}\n";
+
+        assert_eq!(
+            migrate_text_only(source),
+            "data {\n  int N;\n  array[N] int y;\n  array[N, 2] real x; real z;\n  array[N] vector[3] v = rep_array(rep_vector(0, 3), N);\n}\n"
+        );
+    }
+
+    #[test]
+    fn can_migrate_statements_and_expressions() {
+        let source = "model {\n  # prior\n  mu <- 0;\n  increment_log_prob(normal_log(y, mu, 1));\n  z ~ std_normal();\n  target += std_normal_log(z);\n  lp = get_lp();\n  x = if_else(a, b, c) + 1;\n  y = if_else(a, b, c);\n  p = poisson_cdf_log(n, l) + poisson_ccdf_log(n, l) + poisson_log_log(n, l);\n}\n";
+
+        assert_eq!(
+            migrate_text_only(source),
+            "model {\n  // prior\n  mu = 0;\n  target += normal_lpdf(y | mu, 1);\n  z ~ std_normal();\n  target += std_normal_lpdf(z);\n  lp = target();\n  x = (a ? b : c) + 1;\n  y = a ? b : c;\n  p = poisson_lcdf(n | l) + poisson_lccdf(n | l) + poisson_log_lpmf(n | l);\n}\n"
+        );
+    }
+
+    #[test]
+    fn can_migrate_user_defined_densities() {
+        let source = "functions {\n  real foo_log(real y, real[] theta) {\n    return normal_log(y, theta[1], 1);\n  }\n  real bar_log(int n) {\n    return 0;\n  }\n}\nmodel {\n  target += foo_log(1, {1.0}) + bar_log(2);\n}\n";
+
+        assert_eq!(
+            migrate_text_only(source),
+            "functions {\n  real foo_lpdf(real y, array[] real theta) {\n    return normal_lpdf(y | theta[1], 1);\n  }\n  real bar_lpmf(int n) {\n    return 0;\n  }\n}\nmodel {\n  target += foo_lpdf(1 | {1.0}) + bar_lpmf(2);\n}\n"
+        );
+    }
+
+    #[test]
+    fn reports_every_migration_with_its_location() {
+        let source = "model {\n  # prior\n  mu <- get_lp();\n}\n";
+
+        let migrations = migrate_source(source, Path::new("model.stan"))
+            .1
+            .iter()
+            .map(|migration| migration.to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            migrations,
+            vec![
+                "model.stan:2:3: '#' comment: '# prior' -> '// prior'",
+                "model.stan:3:6: '<-' assignment: '<-' -> '='",
+                "model.stan:3:9: get_lp(): 'get_lp()' -> 'target()'",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_arrows_that_assign_are_rewritten() {
+        let source = "model {\n  x[i, j] <- 1;\n  for (n in 1:N) y[n] <- 2;\n  if (y <-1) z <- 3; else z <- 4;\n  x = y <-1;\n}\n";
+
+        assert_eq!(
+            migrate_text_only(source),
+            "model {\n  x[i, j] = 1;\n  for (n in 1:N) y[n] = 2;\n  if (y <-1) z = 3; else z = 4;\n  x = y <-1;\n}\n"
+        );
+    }
+
+    #[test]
+    fn model_and_source_migrations_agree_on_locations() {
+        let source = "functions {\n  real foo_log(real y) {\n    return 0;\n  }\n}\nmodel {\n    target += foo_log(1);\n}";
+        let path = Path::new("x.stan");
+        let model = split_blocks(source, path).unwrap();
+
+        let locations = |migrations: Vec<Migration>| {
+            migrations
+                .iter()
+                .map(|migration| migration.location.to_string())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            locations(migrate(&model).1),
+            vec!["x.stan:2:8", "x.stan:7:15"]
+        );
+        assert_eq!(
+            locations(migrate_source(source, path).1),
+            vec!["x.stan:2:8", "x.stan:7:15"]
+        );
+    }
+
+    #[test]
+    fn nested_rewrites_are_made_in_later_passes() {
+        let source = "model {\n  x = if_else(c, if_else(d, 1, 2), 3);\n}\n";
+
+        assert_eq!(
+            migrate_text_only(source),
+            "model {\n  x = c ? (d ? 1 : 2) : 3;\n}\n"
+        );
+    }
+
+    #[test]
+    fn current_syntax_is_left_alone() {
+        let source = "data {\n  array[N] int y;\n}\nmodel {\n  // fine\n  y ~ poisson_log(alpha);\n  z ~ normal(0, 1);\n  target += normal_lpdf(x | 0, 1);\n}\n";
+
+        let (migrated, migrations) = migrate_source(source, Path::new("model.stan"));
+
+        assert_eq!(migrated, source);
+        assert!(migrations.is_empty());
+    }
+
+    #[test]
+    fn can_migrate_a_model_keeping_line_origins() {
+        let mut model = StanModel::new();
+        model.add_to_block_with_origin(
+            &crate::stan_model_block_type::StanModelBlockType::Data,
+            "int y[N];",
+            Some(crate::source_map::LineOrigin::new("model.stan", 3)),
+        );
+
+        let (migrated, migrations) = migrate(&model);
+
+        assert_eq!(
+            migrated.data.get_code().collect::<Vec<_>>(),
+            vec!["array[N] int y;"]
+        );
+        assert_eq!(
+            migrations[0].to_string(),
+            "model.stan:3:1: old array syntax: 'int y[N];' -> 'array[N] int y;'"
+        );
+    }
+}
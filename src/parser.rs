@@ -0,0 +1,1261 @@
+use std::fmt;
+
+use crate::ast::{
+    AssignOp, BaseType, BinaryOp, Constraints, Declarator, Expr, ExprKind, FunctionDef, Index,
+    Param, Stmt, StmtKind, Truncation, UnaryOp, UnsizedBaseType, UnsizedType, VarDecl, VarType,
+    UNARY_PRECEDENCE,
+};
+use crate::lexer::{significant, tokenize, Span, Token, TokenKind};
+
+/// A syntax error, with the span of the offending token in the parsed source.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse a sequence of statements, such as the body of a block. Spans are
+/// byte offsets into `source`.
+pub fn parse_statements(source: &str) -> Result<Vec<Stmt>, ParseError> {
+    let (statements, mut errors) = parse_statements_with_recovery(source);
+    if errors.is_empty() {
+        Ok(statements)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+/// Parse a sequence of statements, carrying on past syntax errors.
+///
+/// A statement that fails to parse is skipped up to the next `;` or balanced
+/// `{ ... }` at its own nesting level, or up to the `}` that closes the
+/// enclosing block, and parsing resumes from there. Returns every statement
+/// that parsed and every error, in source order.
+pub fn parse_statements_with_recovery(source: &str) -> (Vec<Stmt>, Vec<ParseError>) {
+    let mut parser = Parser::new(source);
+    let mut statements = Vec::new();
+    while !parser.at_end() {
+        statements.extend(parser.recovering_statement(false));
+    }
+    (statements, parser.errors)
+}
+
+/// Parse a single expression that makes up the whole of `source`.
+pub fn parse_expression(source: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser::new(source);
+    let expr = parser.expression()?;
+    if !parser.at_end() {
+        return Err(parser.unexpected("end of expression"));
+    }
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Parser<'a> {
+        Parser {
+            source,
+            tokens: significant(&tokenize(source)),
+            position: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_text(&self) -> Option<&'a str> {
+        self.peek_text_at(0)
+    }
+
+    fn peek_text_at(&self, offset: usize) -> Option<&'a str> {
+        self.tokens
+            .get(self.position + offset)
+            .map(|token| token.text(self.source))
+    }
+
+    fn peek_kind(&self) -> Option<TokenKind> {
+        self.peek().map(|token| token.kind)
+    }
+
+    /// Whether the next token is the punctuation, operator or keyword `text`.
+    fn check(&self, text: &str) -> bool {
+        self.peek().is_some_and(|token| {
+            token.kind != TokenKind::StringLiteral && token.text(self.source) == text
+        })
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        if self.check(text) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.tokens[self.position];
+        self.position += 1;
+        token
+    }
+
+    /// The span of the next token, or an empty span at the end of the source.
+    fn current_span(&self) -> Span {
+        match self.peek() {
+            Some(token) => token.span,
+            None => Span::new(self.source.len(), self.source.len()),
+        }
+    }
+
+    /// The start of the next token.
+    fn start(&self) -> usize {
+        self.current_span().start
+    }
+
+    /// The span from `start` to the end of the last consumed token.
+    fn span_from(&self, start: usize) -> Span {
+        let end = match self.position {
+            0 => start,
+            position => self.tokens[position - 1].span.end,
+        };
+        Span::new(start, end.max(start))
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let found = match self.peek() {
+            Some(token) if token.kind == TokenKind::Error => {
+                format!("invalid token '{}'", token.text(self.source))
+            }
+            Some(token) => format!("'{}'", token.text(self.source)),
+            None => "end of input".to_string(),
+        };
+        ParseError {
+            message: format!("expected {}, found {}", expected, found),
+            span: self.current_span(),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, ParseError> {
+        if self.check(text) {
+            Ok(self.bump())
+        } else {
+            Err(self.unexpected(&format!("'{}'", text)))
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, ParseError> {
+        match self.peek_kind() {
+            Some(TokenKind::Identifier) => Ok(self.bump().text(self.source).to_string()),
+            _ => Err(self.unexpected("an identifier")),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.start();
+        let kind = self.statement_kind()?;
+        Ok(Stmt {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    fn statement_kind(&mut self) -> Result<StmtKind, ParseError> {
+        if self.peek_kind() == Some(TokenKind::Include) {
+            let text = self.bump().text(self.source);
+            let path = text["#include".len()..].trim().to_string();
+            return Ok(StmtKind::Include(path));
+        }
+
+        match self.peek_text() {
+            Some(";") => {
+                self.bump();
+                Ok(StmtKind::Empty)
+            }
+            Some("{") => Ok(StmtKind::Block(self.braced_statements()?)),
+            Some("for") => self.for_statement(),
+            Some("while") => {
+                self.bump();
+                let condition = self.parenthesized()?;
+                let body = Box::new(self.statement()?);
+                Ok(StmtKind::While { condition, body })
+            }
+            Some("if") => self.if_statement(),
+            Some("profile") => {
+                self.bump();
+                self.expect("(")?;
+                let name = match self.peek_kind() {
+                    Some(TokenKind::StringLiteral) => {
+                        let text = self.bump().text(self.source);
+                        text[1..text.len() - 1].to_string()
+                    }
+                    _ => return Err(self.unexpected("a profile name")),
+                };
+                self.expect(")")?;
+                let body = self.braced_statements()?;
+                Ok(StmtKind::Profile { name, body })
+            }
+            Some("break") => {
+                self.bump();
+                self.expect(";")?;
+                Ok(StmtKind::Break)
+            }
+            Some("continue") => {
+                self.bump();
+                self.expect(";")?;
+                Ok(StmtKind::Continue)
+            }
+            Some("return") => {
+                self.bump();
+                let value = if self.check(";") {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.expect(";")?;
+                Ok(StmtKind::Return(value))
+            }
+            Some(keyword @ ("print" | "reject" | "fatal_error")) => {
+                self.bump();
+                self.expect("(")?;
+                let args = self.expression_list(")")?;
+                self.expect(")")?;
+                self.expect(";")?;
+                Ok(match keyword {
+                    "print" => StmtKind::Print(args),
+                    "reject" => StmtKind::Reject(args),
+                    _ => StmtKind::FatalError(args),
+                })
+            }
+            Some("target") if self.peek_text_at(1) == Some("+=") => {
+                self.position += 2;
+                let value = self.expression()?;
+                self.expect(";")?;
+                Ok(StmtKind::TargetPlusAssign(value))
+            }
+            Some("void") => Ok(StmtKind::FunctionDef(self.function_def()?)),
+            Some(text) if self.starts_type(text) => {
+                if let Some(function) = self.try_function_def()? {
+                    Ok(StmtKind::FunctionDef(function))
+                } else {
+                    Ok(StmtKind::VarDecl(self.var_decl()?))
+                }
+            }
+            _ => self.expression_statement(),
+        }
+    }
+
+    fn starts_type(&self, text: &str) -> bool {
+        self.peek_kind() == Some(TokenKind::Keyword)
+            && (text == "array" || text == "tuple" || BaseType::from_keyword(text).is_some())
+    }
+
+    /// A statement, or `None` if it could not be parsed; the error is
+    /// recorded and the broken statement skipped. `nested` is set inside
+    /// braces, where a stray `}` belongs to the enclosing block.
+    fn recovering_statement(&mut self, nested: bool) -> Option<Stmt> {
+        match self.statement() {
+            Ok(statement) => Some(statement),
+            Err(error) => {
+                self.errors.push(error);
+                self.synchronize(nested);
+                None
+            }
+        }
+    }
+
+    fn synchronize(&mut self, nested: bool) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            let punctuation = match token.kind {
+                TokenKind::Punctuation => token.text(self.source),
+                _ => "",
+            };
+            match punctuation {
+                ";" if depth == 0 => {
+                    self.bump();
+                    return;
+                }
+                "{" => depth += 1,
+                "}" if depth == 0 => {
+                    if !nested {
+                        self.bump();
+                    }
+                    return;
+                }
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.bump();
+                        return;
+                    }
+                }
+                _ => {}
+            }
+            self.bump();
+        }
+    }
+
+    /// `{ statements }`. Broken statements inside are recorded and skipped,
+    /// and a missing `}` at the end of the input is recorded as well.
+    fn braced_statements(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.check("}") {
+            if self.at_end() {
+                let error = self.unexpected("'}'");
+                self.errors.push(error);
+                return Ok(statements);
+            }
+            statements.extend(self.recovering_statement(true));
+        }
+        self.bump();
+        Ok(statements)
+    }
+
+    fn parenthesized(&mut self) -> Result<Expr, ParseError> {
+        self.expect("(")?;
+        let expr = self.expression()?;
+        self.expect(")")?;
+        Ok(expr)
+    }
+
+    fn for_statement(&mut self) -> Result<StmtKind, ParseError> {
+        self.bump();
+        self.expect("(")?;
+        let variable = self.identifier()?;
+        self.expect("in")?;
+        let first = self.expression()?;
+        if self.eat(":") {
+            let upper = self.expression()?;
+            self.expect(")")?;
+            let body = Box::new(self.statement()?);
+            Ok(StmtKind::For {
+                variable,
+                lower: first,
+                upper,
+                body,
+            })
+        } else {
+            self.expect(")")?;
+            let body = Box::new(self.statement()?);
+            Ok(StmtKind::ForEach {
+                variable,
+                collection: first,
+                body,
+            })
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<StmtKind, ParseError> {
+        self.bump();
+        let condition = self.parenthesized()?;
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.eat("else") {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    /// An assignment, a sampling statement or a function call.
+    fn expression_statement(&mut self) -> Result<StmtKind, ParseError> {
+        let lhs = self.expression()?;
+
+        if let Some(op) = self.peek_text().and_then(AssignOp::from_symbol) {
+            self.bump();
+            let value = self.expression()?;
+            self.expect(";")?;
+            return Ok(StmtKind::Assign {
+                target: lhs,
+                op,
+                value,
+            });
+        }
+
+        if self.eat("~") {
+            let distribution = self.identifier()?;
+            self.expect("(")?;
+            let args = self.expression_list(")")?;
+            self.expect(")")?;
+            let truncation = self.truncation()?;
+            self.expect(";")?;
+            return Ok(StmtKind::Tilde {
+                lhs,
+                distribution,
+                args,
+                truncation,
+            });
+        }
+
+        if matches!(lhs.kind, ExprKind::Call { .. }) {
+            self.expect(";")?;
+            return Ok(StmtKind::Expr(lhs));
+        }
+
+        Err(self.unexpected("'=', '~' or ';'"))
+    }
+
+    /// `T[lower, upper]` after a sampling statement; either bound may be
+    /// left out.
+    fn truncation(&mut self) -> Result<Option<Truncation>, ParseError> {
+        if !(self.check("T") && self.peek_text_at(1) == Some("[")) {
+            return Ok(None);
+        }
+        self.position += 2;
+        let lower = if self.check(",") {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect(",")?;
+        let upper = if self.check("]") {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect("]")?;
+        Ok(Some(Truncation { lower, upper }))
+    }
+
+    fn var_decl(&mut self) -> Result<VarDecl, ParseError> {
+        let ty = self.var_type()?;
+        let mut declarators = Vec::new();
+        loop {
+            let start = self.start();
+            let name = self.identifier()?;
+            let legacy_array_dims = if self.eat("[") {
+                let dims = self.expression_list("]")?;
+                self.expect("]")?;
+                dims
+            } else {
+                Vec::new()
+            };
+            let init = if self.eat("=") {
+                Some(self.expression()?)
+            } else {
+                None
+            };
+            declarators.push(Declarator {
+                name,
+                legacy_array_dims,
+                init,
+                span: self.span_from(start),
+            });
+            if !self.eat(",") {
+                break;
+            }
+        }
+        self.expect(";")?;
+        Ok(VarDecl { ty, declarators })
+    }
+
+    fn var_type(&mut self) -> Result<VarType, ParseError> {
+        let start = self.start();
+        let array_dims = if self.eat("array") {
+            self.expect("[")?;
+            let dims = self.expression_list("]")?;
+            self.expect("]")?;
+            dims
+        } else {
+            Vec::new()
+        };
+
+        let base = if self.eat("tuple") {
+            self.expect("(")?;
+            let mut items = vec![self.var_type()?];
+            while self.eat(",") {
+                items.push(self.var_type()?);
+            }
+            self.expect(")")?;
+            BaseType::Tuple(items)
+        } else {
+            match self.peek_text().and_then(BaseType::from_keyword) {
+                Some(base) => {
+                    self.bump();
+                    base
+                }
+                None => return Err(self.unexpected("a type")),
+            }
+        };
+
+        let constraints = self.constraints()?;
+        let sizes = if self.eat("[") {
+            let sizes = self.expression_list("]")?;
+            self.expect("]")?;
+            sizes
+        } else {
+            Vec::new()
+        };
+        base.check_size_count(sizes.len())
+            .map_err(|message| ParseError {
+                message,
+                span: self.span_from(start),
+            })?;
+
+        Ok(VarType {
+            array_dims,
+            base,
+            constraints,
+            sizes,
+            span: self.span_from(start),
+        })
+    }
+
+    /// `<lower=a, upper=b>` or `<offset=a, multiplier=b>`. The bounds are
+    /// parsed without comparison operators so that `>` closes the list.
+    fn constraints(&mut self) -> Result<Constraints, ParseError> {
+        let mut constraints = Constraints::default();
+        if !self.eat("<") {
+            return Ok(constraints);
+        }
+        loop {
+            // The constraint words are ordinary identifiers everywhere else.
+            let name = match self.peek_kind() {
+                Some(TokenKind::Identifier) => self.peek_text(),
+                _ => None,
+            };
+            let slot = match name {
+                Some("lower") => &mut constraints.lower,
+                Some("upper") => &mut constraints.upper,
+                Some("offset") => &mut constraints.offset,
+                Some("multiplier") => &mut constraints.multiplier,
+                _ => return Err(self.unexpected("'lower', 'upper', 'offset' or 'multiplier'")),
+            };
+            if slot.is_some() {
+                return Err(ParseError {
+                    message: format!("duplicate constraint '{}'", name.unwrap_or_default()),
+                    span: self.current_span(),
+                });
+            }
+            self.bump();
+            self.expect("=")?;
+            *slot = Some(self.binary(BinaryOp::Add.precedence())?);
+            if !self.eat(",") {
+                break;
+            }
+        }
+        self.expect(">")?;
+        Ok(constraints)
+    }
+
+    /// Parse a function definition if the upcoming tokens start one, leaving
+    /// the position unchanged otherwise.
+    fn try_function_def(&mut self) -> Result<Option<FunctionDef>, ParseError> {
+        let saved = self.position;
+        let is_function = self.unsized_type().is_ok()
+            && self.peek_kind() == Some(TokenKind::Identifier)
+            && self.peek_text_at(1) == Some("(");
+        self.position = saved;
+        if is_function {
+            self.function_def().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn function_def(&mut self) -> Result<FunctionDef, ParseError> {
+        let return_type = if self.eat("void") {
+            None
+        } else {
+            Some(self.unsized_type()?)
+        };
+        let name = self.identifier()?;
+        self.expect("(")?;
+        let mut params = Vec::new();
+        if !self.check(")") {
+            loop {
+                let ty = self.unsized_type()?;
+                let name = self.identifier()?;
+                params.push(Param { ty, name });
+                if !self.eat(",") {
+                    break;
+                }
+            }
+        }
+        self.expect(")")?;
+        let body = if self.eat(";") {
+            None
+        } else {
+            let start = self.start();
+            let statements = self.braced_statements()?;
+            Some(Box::new(Stmt {
+                kind: StmtKind::Block(statements),
+                span: self.span_from(start),
+            }))
+        };
+        Ok(FunctionDef {
+            return_type,
+            name,
+            params,
+            body,
+        })
+    }
+
+    /// A type in a function signature: `data array[,] real`, `vector`,
+    /// `tuple(real, int)` or the older `real[]`.
+    fn unsized_type(&mut self) -> Result<UnsizedType, ParseError> {
+        let data_only = self.eat("data");
+        let mut array_dims = 0;
+        if self.eat("array") {
+            array_dims = self.unsized_dims()?;
+        }
+        let base = if self.eat("tuple") {
+            self.expect("(")?;
+            let mut items = vec![self.unsized_type()?];
+            while self.eat(",") {
+                items.push(self.unsized_type()?);
+            }
+            self.expect(")")?;
+            UnsizedBaseType::Tuple(items)
+        } else {
+            match self.peek_text().and_then(BaseType::from_keyword) {
+                Some(base) => {
+                    self.bump();
+                    UnsizedBaseType::Named(base)
+                }
+                None => return Err(self.unexpected("a type")),
+            }
+        };
+        if array_dims == 0 && self.check("[") {
+            array_dims = self.unsized_dims()?;
+        }
+        Ok(UnsizedType {
+            data_only,
+            array_dims,
+            base,
+        })
+    }
+
+    /// `[]`, `[,]`, ...: the number of dimensions.
+    fn unsized_dims(&mut self) -> Result<usize, ParseError> {
+        self.expect("[")?;
+        let mut dims = 1;
+        while self.eat(",") {
+            dims += 1;
+        }
+        self.expect("]")?;
+        Ok(dims)
+    }
+
+    /// Comma-separated expressions up to, but not including, `close`.
+    fn expression_list(&mut self, close: &str) -> Result<Vec<Expr>, ParseError> {
+        let mut items = Vec::new();
+        if self.check(close) {
+            return Ok(items);
+        }
+        loop {
+            items.push(self.expression()?);
+            if !self.eat(",") {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, ParseError> {
+        let start = self.start();
+        let condition = self.binary(BinaryOp::Or.precedence())?;
+        if !self.eat("?") {
+            return Ok(condition);
+        }
+        let if_true = self.expression()?;
+        self.expect(":")?;
+        let if_false = self.expression()?;
+        Ok(Expr {
+            kind: ExprKind::Ternary {
+                condition: Box::new(condition),
+                if_true: Box::new(if_true),
+                if_false: Box::new(if_false),
+            },
+            span: self.span_from(start),
+        })
+    }
+
+    /// Precedence climbing over the binary operators that bind at least as
+    /// tightly as `min_precedence`.
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, ParseError> {
+        let start = self.start();
+        let mut left = self.prefix()?;
+        while let Some(op) = self.binary_operator() {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.bump();
+            let next = if op.is_right_associative() {
+                op.precedence()
+            } else {
+                op.precedence() + 1
+            };
+            let right = self.binary(next)?;
+            left = Expr {
+                kind: ExprKind::Binary {
+                    op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                span: self.span_from(start),
+            };
+        }
+        Ok(left)
+    }
+
+    fn binary_operator(&self) -> Option<BinaryOp> {
+        match self.peek_kind() {
+            Some(TokenKind::Operator) => self.peek_text().and_then(BinaryOp::from_symbol),
+            _ => None,
+        }
+    }
+
+    fn prefix(&mut self) -> Result<Expr, ParseError> {
+        let start = self.start();
+        let op = match self.peek_text() {
+            Some("-") => UnaryOp::Negate,
+            Some("+") => UnaryOp::Plus,
+            Some("!") => UnaryOp::Not,
+            _ => return self.postfix(),
+        };
+        self.bump();
+        let operand = self.binary(UNARY_PRECEDENCE + 1)?;
+        Ok(Expr {
+            kind: ExprKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+            span: self.span_from(start),
+        })
+    }
+
+    fn postfix(&mut self) -> Result<Expr, ParseError> {
+        let start = self.start();
+        let mut expr = self.primary()?;
+        loop {
+            let kind = if self.eat("[") {
+                let indices = self.indices()?;
+                self.expect("]")?;
+                ExprKind::Index {
+                    base: Box::new(expr),
+                    indices,
+                }
+            } else if self.eat("'") {
+                ExprKind::Transpose(Box::new(expr))
+            } else if let Some(index) = self.tuple_projection(&expr) {
+                self.bump();
+                ExprKind::TupleProjection {
+                    base: Box::new(expr),
+                    index,
+                }
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr {
+                kind,
+                span: self.span_from(start),
+            };
+        }
+    }
+
+    /// The lexer reads `x.2` as `x` followed by the real literal `.2`.
+    fn tuple_projection(&self, base: &Expr) -> Option<usize> {
+        let token = self.peek()?;
+        let text = token.text(self.source);
+        let digits = text.strip_prefix('.')?;
+        if token.kind != TokenKind::RealLiteral || token.span.start != base.span.end {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
+    fn indices(&mut self) -> Result<Vec<Index>, ParseError> {
+        let mut indices = Vec::new();
+        loop {
+            let index = if self.check(",") || (!indices.is_empty() && self.check("]")) {
+                Index::Omitted
+            } else if self.eat(":") {
+                if self.check(",") || self.check("]") {
+                    Index::All
+                } else {
+                    Index::Range {
+                        lower: None,
+                        upper: Some(self.expression()?),
+                    }
+                }
+            } else {
+                let expr = self.expression()?;
+                if self.eat(":") {
+                    let upper = if self.check(",") || self.check("]") {
+                        None
+                    } else {
+                        Some(self.expression()?)
+                    };
+                    Index::Range {
+                        lower: Some(expr),
+                        upper,
+                    }
+                } else {
+                    Index::Single(expr)
+                }
+            };
+            indices.push(index);
+            if !self.eat(",") {
+                return Ok(indices);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.start();
+        let Some(token) = self.peek().copied() else {
+            return Err(self.unexpected("an expression"));
+        };
+        let text = token.text(self.source).to_string();
+
+        let kind = match token.kind {
+            TokenKind::IntLiteral => {
+                self.bump();
+                ExprKind::IntLiteral(text)
+            }
+            TokenKind::RealLiteral => {
+                self.bump();
+                ExprKind::RealLiteral(text)
+            }
+            TokenKind::ImaginaryLiteral => {
+                self.bump();
+                ExprKind::ImaginaryLiteral(text)
+            }
+            TokenKind::StringLiteral => {
+                self.bump();
+                ExprKind::StringLiteral(text)
+            }
+            TokenKind::Identifier => {
+                self.bump();
+                if self.check("(") {
+                    self.call(text)?
+                } else {
+                    ExprKind::Variable(text)
+                }
+            }
+            TokenKind::Keyword if text == "target" && self.peek_text_at(1) == Some("(") => {
+                self.bump();
+                self.call(text)?
+            }
+            TokenKind::Punctuation if text == "(" => {
+                self.bump();
+                let first = self.expression()?;
+                if self.eat(",") {
+                    let mut items = vec![first];
+                    items.extend(self.expression_list(")")?);
+                    self.expect(")")?;
+                    ExprKind::TupleLiteral(items)
+                } else {
+                    self.expect(")")?;
+                    ExprKind::Paren(Box::new(first))
+                }
+            }
+            TokenKind::Punctuation if text == "{" => {
+                self.bump();
+                let items = self.expression_list("}")?;
+                self.expect("}")?;
+                ExprKind::ArrayLiteral(items)
+            }
+            TokenKind::Punctuation if text == "[" => {
+                self.bump();
+                let items = self.expression_list("]")?;
+                self.expect("]")?;
+                ExprKind::RowVectorLiteral(items)
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+
+        Ok(Expr {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    /// The argument list of a call to `name`, including `f(y | theta)`.
+    fn call(&mut self, name: String) -> Result<ExprKind, ParseError> {
+        self.expect("(")?;
+        let mut args = Vec::new();
+        let mut conditional = false;
+        if !self.check(")") {
+            args.push(self.expression()?);
+            if self.eat("|") {
+                conditional = true;
+                args.extend(self.expression_list(")")?);
+            } else if self.eat(",") {
+                args.extend(self.expression_list(")")?);
+            }
+        }
+        self.expect(")")?;
+        Ok(ExprKind::Call {
+            name,
+            args,
+            conditional,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(source: &str) -> StmtKind {
+        let mut statements = parse_statements(source).unwrap();
+        assert_eq!(statements.len(), 1, "{:?}", statements);
+        statements.remove(0).kind
+    }
+
+    #[test]
+    fn can_parse_operator_precedence() {
+        let cases = [
+            ("a + b * c", "a + b * c"),
+            ("-a ^ b", "-a^b"),
+            ("a ^ b ^ c", "a^b^c"),
+            ("a || b && c == d", "a || b && c == d"),
+            ("c ? a : b ? d : e", "c ? a : b ? d : e"),
+            ("(a + b) * c'", "(a + b) * c'"),
+        ];
+        for (source, printed) in cases {
+            assert_eq!(parse_expression(source).unwrap().to_string(), printed);
+        }
+
+        let expr = parse_expression("-a ^ b").unwrap();
+        let ExprKind::Unary { op, operand } = expr.kind else {
+            panic!("expected a unary expression");
+        };
+        assert_eq!(op, UnaryOp::Negate);
+        assert!(matches!(
+            operand.kind,
+            ExprKind::Binary {
+                op: BinaryOp::Power,
+                ..
+            }
+        ));
+
+        let expr = parse_expression("a - b - c").unwrap();
+        let ExprKind::Binary { left, .. } = expr.kind else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(left.to_string(), "a - b");
+    }
+
+    #[test]
+    fn can_parse_indexing_slicing_and_calls() {
+        let expr = parse_expression("x[i, :, 2:, :n, a:b][1]").unwrap();
+        assert_eq!(expr.to_string(), "x[i, :, 2:, :n, a:b][1]");
+
+        let expr = parse_expression("normal_lpdf(y | mu, sigma)").unwrap();
+        assert!(matches!(
+            expr.kind,
+            ExprKind::Call {
+                conditional: true,
+                ..
+            }
+        ));
+        assert_eq!(expr.to_string(), "normal_lpdf(y | mu, sigma)");
+
+        assert_eq!(
+            parse_expression("{1, 2}[k] + [a, b] * t.2 + target()")
+                .unwrap()
+                .to_string(),
+            "{1, 2}[k] + [a, b] * t.2 + target()"
+        );
+    }
+
+    #[test]
+    fn omitted_indices_are_empty_slots() {
+        let expr = parse_expression("a[, 1]").unwrap();
+        let ExprKind::Index { indices, .. } = &expr.kind else {
+            panic!("expected an index");
+        };
+        assert_eq!(indices[0], Index::Omitted);
+        assert_eq!(expr.to_string(), "a[, 1]");
+        assert_eq!(parse_expression("m[1, ]").unwrap().to_string(), "m[1, ]");
+        assert!(parse_expression("a[]").is_err());
+    }
+
+    #[test]
+    fn expressions_have_spans() {
+        let source = "a + foo(b)";
+        let expr = parse_expression(source).unwrap();
+        assert_eq!(expr.span, Span::new(0, 10));
+        let ExprKind::Binary { right, .. } = expr.kind else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(&source[right.span.range()], "foo(b)");
+    }
+
+    #[test]
+    fn can_parse_declarations() {
+        let StmtKind::VarDecl(decl) =
+            parse_one("array[N] vector<lower=0, upper=ub[1]>[K] theta = rep_array(x, N);")
+        else {
+            panic!("expected a declaration");
+        };
+        assert_eq!(decl.ty.base, BaseType::Vector);
+        assert_eq!(decl.ty.array_dims[0].to_string(), "N");
+        assert_eq!(decl.ty.sizes[0].to_string(), "K");
+        assert_eq!(decl.ty.constraints.lower.as_ref().unwrap().to_string(), "0");
+        assert_eq!(
+            decl.ty.constraints.upper.as_ref().unwrap().to_string(),
+            "ub[1]"
+        );
+        assert_eq!(decl.declarators[0].name, "theta");
+        assert!(decl.declarators[0].init.is_some());
+        assert_eq!(
+            decl.ty.to_string(),
+            "array[N] vector<lower=0, upper=ub[1]>[K]"
+        );
+
+        let StmtKind::VarDecl(decl) = parse_one("real<offset=mu, multiplier=tau> a, b = 2;") else {
+            panic!("expected a declaration");
+        };
+        assert_eq!(decl.declarators.len(), 2);
+        assert_eq!(decl.ty.to_string(), "real<offset=mu, multiplier=tau>");
+
+        let StmtKind::VarDecl(decl) = parse_one("cholesky_factor_cov[M, N] L;") else {
+            panic!("expected a declaration");
+        };
+        assert_eq!(decl.ty.base, BaseType::CholeskyFactorCov);
+        assert_eq!(decl.ty.sizes.len(), 2);
+
+        let StmtKind::VarDecl(decl) = parse_one("tuple(real, array[2] int) pair;") else {
+            panic!("expected a declaration");
+        };
+        assert_eq!(decl.ty.to_string(), "tuple(real, array[2] int)");
+    }
+
+    #[test]
+    fn constraint_words_can_be_variable_names() {
+        let StmtKind::VarDecl(decl) = parse_one("vector[N] offset;") else {
+            panic!("expected a declaration");
+        };
+        assert_eq!(decl.declarators[0].name, "offset");
+
+        let StmtKind::VarDecl(decl) =
+            parse_one("vector<lower=lower, offset=offset, multiplier=upper>[N] multiplier;")
+        else {
+            panic!("expected a declaration");
+        };
+        assert_eq!(decl.declarators[0].name, "multiplier");
+        assert_eq!(
+            decl.ty.constraints.lower.as_ref().unwrap().to_string(),
+            "lower"
+        );
+        assert_eq!(
+            decl.ty.constraints.offset.as_ref().unwrap().to_string(),
+            "offset"
+        );
+
+        let StmtKind::Tilde { args, .. } = parse_one("y ~ poisson_log(offset + beta);") else {
+            panic!("expected a sampling statement");
+        };
+        assert_eq!(args[0].to_string(), "offset + beta");
+        assert!(matches!(
+            parse_one("upper = lower[1] * then;"),
+            StmtKind::Assign { .. }
+        ));
+        assert!(parse_statements("real<size=1> x;").is_err());
+    }
+
+    #[test]
+    fn declarations_need_the_right_number_of_sizes() {
+        for (source, message) in [
+            ("vector[2, 3, 4] x;", "'vector' takes 1 size, found 3"),
+            ("real[3] x;", "'real' takes no sizes, found 1"),
+            ("simplex[2, 3] x;", "'simplex' takes 1 size, found 2"),
+            (
+                "cholesky_factor_corr[2, 3] x;",
+                "'cholesky_factor_corr' takes 1 size, found 2",
+            ),
+            (
+                "sum_to_zero_matrix[3] x;",
+                "'sum_to_zero_matrix' takes 2 sizes, found 1",
+            ),
+            (
+                "cholesky_factor_cov[1, 2, 3] x;",
+                "'cholesky_factor_cov' takes 1 or 2 sizes, found 3",
+            ),
+            ("matrix x;", "'matrix' takes 2 sizes, found 0"),
+        ] {
+            let error = parse_statements(source).unwrap_err();
+            assert_eq!(error.message, message);
+            assert_eq!(error.span.start, 0, "{}", source);
+        }
+        assert!(parse_statements("cholesky_factor_cov[3] L;").is_ok());
+
+        let error = parse_statements("int N;\narray[N] cov_matrix[2, 2] s;").unwrap_err();
+        assert_eq!(error.span, Span::new(7, 32));
+    }
+
+    #[test]
+    fn can_parse_legacy_array_declarations() {
+        let StmtKind::VarDecl(decl) = parse_one("int<lower=0> y[N, 2];") else {
+            panic!("expected a declaration");
+        };
+        assert!(decl.ty.array_dims.is_empty());
+        assert_eq!(decl.declarators[0].legacy_array_dims.len(), 2);
+    }
+
+    #[test]
+    fn can_parse_assignments_and_sampling() {
+        assert!(matches!(
+            parse_one("x[i] .*= 2;"),
+            StmtKind::Assign {
+                op: AssignOp::ElementwiseMultiplyAssign,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_one("target += normal_lpdf(y | 0, 1);"),
+            StmtKind::TargetPlusAssign(_)
+        ));
+
+        let StmtKind::Tilde {
+            lhs,
+            distribution,
+            args,
+            truncation,
+        } = parse_one("y[n] ~ normal(mu, sigma) T[0, ];")
+        else {
+            panic!("expected a sampling statement");
+        };
+        assert_eq!(lhs.to_string(), "y[n]");
+        assert_eq!(distribution, "normal");
+        assert_eq!(args.len(), 2);
+        let truncation = truncation.unwrap();
+        assert!(truncation.lower.is_some() && truncation.upper.is_none());
+    }
+
+    #[test]
+    fn can_parse_control_flow() {
+        let source = "for (n in 1:N) {\n  if (x[n] > 0) y += 1; else if (x[n] < 0) break; else continue;\n}\nwhile (1) { print(\"x=\", x); }\nfor (v in xs) reject(\"bad\");\nprofile(\"lik\") { target += 1; }\n";
+        let statements = parse_statements(source).unwrap();
+        assert_eq!(statements.len(), 4);
+
+        let StmtKind::For { body, .. } = &statements[0].kind else {
+            panic!("expected a for loop");
+        };
+        let StmtKind::Block(body) = &body.kind else {
+            panic!("expected a block");
+        };
+        assert!(matches!(
+            &body[0].kind,
+            StmtKind::If {
+                else_branch: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(statements[1].kind, StmtKind::While { .. }));
+        assert!(matches!(statements[2].kind, StmtKind::ForEach { .. }));
+        assert!(
+            matches!(&statements[3].kind, StmtKind::Profile { name, body } if name == "lik" && body.len() == 1)
+        );
+        assert_eq!(
+            &source[statements[1].span.range()],
+            "while (1) { print(\"x=\", x); }"
+        );
+    }
+
+    #[test]
+    fn can_parse_function_definitions() {
+        let source = "real twice(data real x);\nvector f(array[,] real xs, tuple(int, vector) t) {\n  return xs[1]';\n}\nvoid g_lp(real[] x) { increment_log_prob(x); }\n";
+        let statements = parse_statements(source).unwrap();
+        assert_eq!(statements.len(), 3);
+
+        let StmtKind::FunctionDef(twice) = &statements[0].kind else {
+            panic!("expected a function");
+        };
+        assert!(twice.body.is_none());
+        assert_eq!(twice.params[0].to_string(), "data real x");
+
+        let StmtKind::FunctionDef(f) = &statements[1].kind else {
+            panic!("expected a function");
+        };
+        assert_eq!(f.name, "f");
+        assert_eq!(f.params[0].to_string(), "array[,] real xs");
+        assert_eq!(f.params[1].to_string(), "tuple(int, vector) t");
+
+        let StmtKind::FunctionDef(g) = &statements[2].kind else {
+            panic!("expected a function");
+        };
+        assert!(g.return_type.is_none());
+        assert_eq!(g.params[0].ty.array_dims, 1);
+    }
+
+    #[test]
+    fn can_recover_from_several_errors() {
+        let source = "real x = ;\nfor (n in 1:N) {\n  y[n] = 1\n  z ~ normal(0, 1);\n}\n}\nmu ~ normal(0 1);\nsigma ~ exponential(1);\n";
+        let (statements, errors) = parse_statements_with_recovery(source);
+
+        let messages = errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            messages,
+            vec![
+                "expected an expression, found ';'",
+                "expected ';', found 'z'",
+                "expected an expression, found '}'",
+                "expected ')', found '1'",
+            ]
+        );
+        assert_eq!(statements.len(), 2);
+        assert!(matches!(statements[0].kind, StmtKind::For { .. }));
+        assert_eq!(
+            &source[statements[1].span.range()],
+            "sigma ~ exponential(1);"
+        );
+    }
+
+    #[test]
+    fn unclosed_blocks_keep_their_statements() {
+        let (statements, errors) = parse_statements_with_recovery("while (1) {\n  x = 1;\n");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "expected '}', found end of input");
+        let StmtKind::While { body, .. } = &statements[0].kind else {
+            panic!("expected a while loop");
+        };
+        assert!(matches!(&body.kind, StmtKind::Block(body) if body.len() == 1));
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        let source = "real x = ;";
+        let error = parse_statements(source).unwrap_err();
+        assert_eq!(error.message, "expected an expression, found ';'");
+        assert_eq!(error.span, Span::new(9, 10));
+
+        let error = parse_statements("y ~ normal(0, 1)").unwrap_err();
+        assert_eq!(error.message, "expected ';', found end of input");
+
+        let error = parse_statements("x + 1;").unwrap_err();
+        assert_eq!(error.message, "expected '=', '~' or ';', found ';'");
+    }
+}
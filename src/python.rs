@@ -0,0 +1,428 @@
+//! Python bindings, built by maturin as `stan_jammer._lowlevel`.
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+use crate::interface::Variable;
+use crate::stan_model::StanModel;
+use crate::stan_model_block::StanModelBlock;
+use crate::stan_model_block_type::StanModelBlockType;
+use crate::stan_source_parser::cache::shared_cache;
+use crate::stan_source_parser::resolver::MemoryResolver;
+use crate::stan_source_parser::stan_source_parser::{IncludeOnce, SourceParser, StanSourceParser};
+use crate::stanc_messages::rewrite_stanc_message;
+
+create_exception!(
+    _lowlevel,
+    StanJamError,
+    PyException,
+    "Raised when a Stan program cannot be read, flattened or split into blocks."
+);
+create_exception!(
+    _lowlevel,
+    MissingFileError,
+    StanJamError,
+    "Raised when a Stan file, or a file it includes, does not exist."
+);
+create_exception!(
+    _lowlevel,
+    UnreadableFileError,
+    StanJamError,
+    "Raised when a Stan file exists but cannot be read."
+);
+create_exception!(
+    _lowlevel,
+    IncludeNotFoundError,
+    StanJamError,
+    "Raised when no search folder contains an included file."
+);
+create_exception!(
+    _lowlevel,
+    MalformedIncludeError,
+    StanJamError,
+    "Raised for an `#include` line whose path cannot be parsed."
+);
+create_exception!(
+    _lowlevel,
+    IncludeCycleError,
+    StanJamError,
+    "Raised when a file directly or indirectly includes itself."
+);
+create_exception!(
+    _lowlevel,
+    UnbalancedBracesError,
+    StanJamError,
+    "Raised for a `}` without a matching `{`, or a block left open."
+);
+create_exception!(
+    _lowlevel,
+    UnknownBlockError,
+    StanJamError,
+    "Raised for text at the top level that is not a known block header."
+);
+create_exception!(
+    _lowlevel,
+    DuplicateBlockError,
+    StanJamError,
+    "Raised when a block appears more than once."
+);
+create_exception!(
+    _lowlevel,
+    StanSyntaxError,
+    StanJamError,
+    "Raised for a statement inside a block that does not parse."
+);
+create_exception!(
+    _lowlevel,
+    SizeError,
+    StanJamError,
+    "Raised when the sizes of a declaration cannot be evaluated against the data."
+);
+
+impl From<crate::error::StanJamError> for PyErr {
+    fn from(error: crate::error::StanJamError) -> PyErr {
+        use crate::error::StanJamError as Kind;
+        let message = error.to_string();
+        match error {
+            Kind::MissingFile { .. } => MissingFileError::new_err(message),
+            Kind::UnreadableFile { .. } => UnreadableFileError::new_err(message),
+            Kind::IncludeNotFound { .. } => IncludeNotFoundError::new_err(message),
+            Kind::MalformedInclude { .. } => MalformedIncludeError::new_err(message),
+            Kind::IncludeCycle { .. } => IncludeCycleError::new_err(message),
+            Kind::UnbalancedBraces { .. } => UnbalancedBracesError::new_err(message),
+            Kind::UnknownBlock { .. } => UnknownBlockError::new_err(message),
+            Kind::DuplicateBlock { .. } => DuplicateBlockError::new_err(message),
+            Kind::SyntaxError { .. } => StanSyntaxError::new_err(message),
+            Kind::SizeError { .. } => SizeError::new_err(message),
+        }
+    }
+}
+
+/// Reads a Stan program, resolving `#include` directives through a list of
+/// search folders.
+#[pyclass(name = "StanSourceParser")]
+struct PyStanSourceParser {
+    inner: StanSourceParser,
+}
+
+#[pymethods]
+impl PyStanSourceParser {
+    /// Read `filename` from disk, or from `files`, a dict of paths to
+    /// sources, when it is given.
+    #[new]
+    #[pyo3(signature = (filename, files = None))]
+    fn new(filename: &str, files: Option<std::collections::HashMap<String, String>>) -> Self {
+        let inner = match files {
+            Some(files) => StanSourceParser::with_resolver(
+                filename,
+                std::sync::Arc::new(files.into_iter().collect::<MemoryResolver>()),
+            ),
+            None => StanSourceParser::new(filename),
+        };
+        PyStanSourceParser { inner }
+    }
+
+    /// Read `filename` and its includes from the zip or tar archive at
+    /// `archive`.
+    #[cfg(feature = "archive")]
+    #[staticmethod]
+    fn from_archive(filename: &str, archive: &str) -> PyResult<Self> {
+        let resolver =
+            MemoryResolver::from_archive(std::path::Path::new(archive)).map_err(|error| {
+                StanJamError::new_err(format!("could not read '{}': {}", archive, error))
+            })?;
+        Ok(PyStanSourceParser {
+            inner: StanSourceParser::with_resolver(filename, std::sync::Arc::new(resolver)),
+        })
+    }
+
+    /// Parse `source` as if it had been read from the file `name`, looking
+    /// for its includes next to `name` and in the search folders.
+    #[staticmethod]
+    #[pyo3(signature = (source, name = "<string>"))]
+    fn from_str(source: &str, name: &str) -> Self {
+        PyStanSourceParser {
+            inner: StanSourceParser::from_str(source, name),
+        }
+    }
+
+    #[getter]
+    fn filename(&self) -> String {
+        self.inner.filename.clone()
+    }
+
+    #[getter]
+    fn folders(&self) -> Vec<String> {
+        self.inner.folders.clone()
+    }
+
+    fn add_folder(&mut self, folder: &str) {
+        self.inner.add_folder(folder);
+    }
+
+    /// Which files are inlined only the first time they are included:
+    /// `"guarded"`, `"functions"` or `"always"`.
+    #[getter]
+    fn include_once(&self) -> &'static str {
+        self.inner.include_once.name()
+    }
+
+    #[setter]
+    fn set_include_once(&mut self, include_once: &str) -> PyResult<()> {
+        self.inner.include_once = IncludeOnce::from_name(include_once).ok_or_else(|| {
+            StanJamError::new_err(format!("unknown include-once setting '{}'", include_once))
+        })?;
+        Ok(())
+    }
+
+    /// Whether included files are kept in the process-wide cache once read,
+    /// so parsers sharing a library read and scan it only once.
+    #[getter]
+    fn cached(&self) -> bool {
+        self.inner.cache.is_some()
+    }
+
+    #[setter]
+    fn set_cached(&mut self, cached: bool) {
+        self.inner.cache = cached.then(shared_cache);
+    }
+
+    /// The non-empty, trimmed lines of the file, without resolving includes.
+    fn get_lines(&self) -> PyResult<Vec<String>> {
+        Ok(self.inner.get_lines()?)
+    }
+
+    /// The program with every include recursively inlined.
+    fn flatten(&self) -> PyResult<String> {
+        Ok(self.inner.flatten()?)
+    }
+
+    /// Rewrite every location in stanc error or warning output for the
+    /// flattened program so it points at the original file and line.
+    fn rewrite_stanc_message(&self, message: &str) -> PyResult<String> {
+        let (_, source_map) = self.inner.flatten_with_source_map()?;
+        Ok(rewrite_stanc_message(message, &source_map))
+    }
+
+    /// Flatten the program and split it into blocks.
+    fn read_file(&self) -> PyResult<PyStanModel> {
+        Ok(PyStanModel {
+            inner: self.inner.read_file()?,
+        })
+    }
+
+    /// Read the program as far as possible. Returns the blocks that could be
+    /// read and a message for every structural or syntax error.
+    fn read_file_with_diagnostics(&self) -> PyResult<(PyStanModel, Vec<String>)> {
+        let (model, diagnostics) = self.inner.read_file_with_diagnostics()?;
+        Ok((
+            PyStanModel { inner: model },
+            diagnostics.iter().map(|error| error.to_string()).collect(),
+        ))
+    }
+
+    /// A copy of `model` with the includes in its blocks inlined.
+    fn inline_includes(&self, model: &PyStanModel) -> PyResult<PyStanModel> {
+        Ok(PyStanModel {
+            inner: self.inner.inline_includes(&model.inner)?,
+        })
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "StanSourceParser(filename={:?}, folders={:?})",
+            self.inner.filename, self.inner.folders
+        )
+    }
+}
+
+/// A variable declared in a block, with its type, dimensions and
+/// constraints as Stan source text.
+#[pyclass(name = "Variable", get_all)]
+struct PyVariable {
+    name: String,
+    base_type: String,
+    dims: Vec<String>,
+    lower: Option<String>,
+    upper: Option<String>,
+    offset: Option<String>,
+    multiplier: Option<String>,
+    declaration: String,
+}
+
+impl From<&Variable> for PyVariable {
+    fn from(variable: &Variable) -> PyVariable {
+        let constraints = variable.constraints();
+        let text = |bound: &Option<crate::ast::Expr>| bound.as_ref().map(|expr| expr.to_string());
+        PyVariable {
+            name: variable.name.clone(),
+            base_type: variable.base().name().to_string(),
+            dims: variable.dims().iter().map(|dim| dim.to_string()).collect(),
+            lower: text(&constraints.lower),
+            upper: text(&constraints.upper),
+            offset: text(&constraints.offset),
+            multiplier: text(&constraints.multiplier),
+            declaration: variable.to_string(),
+        }
+    }
+}
+
+#[pymethods]
+impl PyVariable {
+    fn __repr__(&self) -> String {
+        format!("Variable({})", self.declaration)
+    }
+}
+
+/// A Stan program split into its blocks.
+#[pyclass(name = "StanModel")]
+struct PyStanModel {
+    inner: StanModel,
+}
+
+fn block_lines(block: &StanModelBlock) -> Vec<String> {
+    block.get_code().cloned().collect()
+}
+
+#[pymethods]
+impl PyStanModel {
+    #[new]
+    fn new() -> Self {
+        PyStanModel {
+            inner: StanModel::new(),
+        }
+    }
+
+    /// The lines of the named block (e.g. `"transformed data"`), or `None`
+    /// if the block is absent.
+    fn block(&self, name: &str) -> PyResult<Option<Vec<String>>> {
+        let block_type = StanModelBlockType::from_name(name)
+            .ok_or_else(|| StanJamError::new_err(format!("unknown block '{}'", name)))?;
+        Ok(self
+            .inner
+            .blocks()
+            .into_iter()
+            .find(|block| *block.get_block_type() == block_type)
+            .map(block_lines))
+    }
+
+    /// Add a line of Stan code to the named block.
+    fn add(&mut self, name: &str, line: &str) -> PyResult<()> {
+        let block_type = StanModelBlockType::from_name(name)
+            .ok_or_else(|| StanJamError::new_err(format!("unknown block '{}'", name)))?;
+        self.inner.add_to_block(&block_type, line);
+        Ok(())
+    }
+
+    #[getter]
+    fn functions(&self) -> Option<Vec<String>> {
+        self.inner.functions.as_ref().map(block_lines)
+    }
+
+    #[getter]
+    fn data(&self) -> Vec<String> {
+        block_lines(&self.inner.data)
+    }
+
+    #[getter]
+    fn transformed_data(&self) -> Option<Vec<String>> {
+        self.inner.transformed_data.as_ref().map(block_lines)
+    }
+
+    #[getter]
+    fn parameters(&self) -> Vec<String> {
+        block_lines(&self.inner.parameters)
+    }
+
+    #[getter]
+    fn transformed_parameters(&self) -> Option<Vec<String>> {
+        self.inner.transformed_parameters.as_ref().map(block_lines)
+    }
+
+    #[getter]
+    fn model(&self) -> Vec<String> {
+        block_lines(&self.inner.model)
+    }
+
+    #[getter]
+    fn generated_quantities(&self) -> Option<Vec<String>> {
+        self.inner.generated_quantities.as_ref().map(block_lines)
+    }
+
+    fn has_include_directive(&self) -> bool {
+        self.inner.has_include_directive()
+    }
+
+    /// The inputs declared in the data block, in declaration order.
+    fn data_interface(&self) -> PyResult<Vec<PyVariable>> {
+        Ok(self
+            .inner
+            .data_interface()?
+            .iter()
+            .map(PyVariable::from)
+            .collect())
+    }
+
+    /// The model rendered as Stan source.
+    fn __str__(&self) -> String {
+        self.inner.to_string()
+    }
+
+    fn __eq__(&self, other: &PyStanModel) -> bool {
+        self.inner == other.inner
+    }
+}
+
+/// Read `filename`, resolve its includes through `folders` and return the
+/// flattened Stan source.
+#[pyfunction]
+#[pyo3(signature = (filename, folders = Vec::new()))]
+fn flatten(filename: &str, folders: Vec<String>) -> PyResult<String> {
+    let mut parser = StanSourceParser::new(filename);
+    folders.iter().for_each(|folder| parser.add_folder(folder));
+    Ok(parser.flatten()?)
+}
+
+/// The `(hits, misses)` of the process-wide cache of included files.
+#[pyfunction]
+fn cache_stats() -> (u64, u64) {
+    let stats = shared_cache().stats();
+    (stats.hits, stats.misses)
+}
+
+#[pymodule]
+fn _lowlevel(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyStanSourceParser>()?;
+    m.add_class::<PyStanModel>()?;
+    m.add_class::<PyVariable>()?;
+    m.add_function(wrap_pyfunction!(flatten, m)?)?;
+    m.add_function(wrap_pyfunction!(cache_stats, m)?)?;
+    m.add("StanJamError", m.py().get_type::<StanJamError>())?;
+    m.add("MissingFileError", m.py().get_type::<MissingFileError>())?;
+    m.add(
+        "UnreadableFileError",
+        m.py().get_type::<UnreadableFileError>(),
+    )?;
+    m.add(
+        "IncludeNotFoundError",
+        m.py().get_type::<IncludeNotFoundError>(),
+    )?;
+    m.add(
+        "MalformedIncludeError",
+        m.py().get_type::<MalformedIncludeError>(),
+    )?;
+    m.add("IncludeCycleError", m.py().get_type::<IncludeCycleError>())?;
+    m.add(
+        "UnbalancedBracesError",
+        m.py().get_type::<UnbalancedBracesError>(),
+    )?;
+    m.add("UnknownBlockError", m.py().get_type::<UnknownBlockError>())?;
+    m.add(
+        "DuplicateBlockError",
+        m.py().get_type::<DuplicateBlockError>(),
+    )?;
+    m.add("StanSyntaxError", m.py().get_type::<StanSyntaxError>())?;
+    m.add("SizeError", m.py().get_type::<SizeError>())?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+
+/// The file and 1-based line a line of Stan code originally came from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineOrigin {
+    pub path: PathBuf,
+    pub line: usize,
+    /// How many characters of the original line come before the text that
+    /// was kept, such as the indentation trimmed from a block line.
+    pub indent: usize,
+}
+
+impl LineOrigin {
+    pub fn new(path: impl Into<PathBuf>, line: usize) -> LineOrigin {
+        LineOrigin {
+            path: path.into(),
+            line,
+            indent: 0,
+        }
+    }
+
+    /// The same origin for text starting `columns` further into the line.
+    pub fn indented(mut self, columns: usize) -> LineOrigin {
+        self.indent += columns;
+        self
+    }
+}
+
+/// A line of flattened Stan source together with where it came from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLine {
+    pub text: String,
+    pub origin: LineOrigin,
+}
+
+/// Maps each line of generated Stan source back to its origin. Lines that
+/// were synthesized (block headers, closing braces) have no origin.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceMap {
+    origins: Vec<Option<LineOrigin>>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// A map for source that was not generated: every line maps to itself.
+    pub fn identity(path: &Path, line_count: usize) -> SourceMap {
+        SourceMap {
+            origins: (1..=line_count)
+                .map(|line| Some(LineOrigin::new(path, line)))
+                .collect(),
+        }
+    }
+
+    /// Record the origin of the next output line.
+    pub fn push(&mut self, origin: Option<LineOrigin>) {
+        self.origins.push(origin);
+    }
+
+    /// The number of output lines in the map.
+    pub fn len(&self) -> usize {
+        self.origins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+
+    /// The origin of a 1-based output line.
+    pub fn get(&self, line: usize) -> Option<&LineOrigin> {
+        line.checked_sub(1)
+            .and_then(|index| self.origins.get(index))
+            .and_then(|origin| origin.as_ref())
+    }
+
+    /// The originating file and line of a 1-based output line.
+    pub fn origin_of(&self, line: usize) -> Option<(PathBuf, usize)> {
+        self.get(line)
+            .map(|origin| (origin.path.clone(), origin.line))
+    }
+}
+
+impl FromIterator<Option<LineOrigin>> for SourceMap {
+    fn from_iter<I: IntoIterator<Item = Option<LineOrigin>>>(iter: I) -> SourceMap {
+        SourceMap {
+            origins: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<Option<LineOrigin>> for SourceMap {
+    fn extend<I: IntoIterator<Item = Option<LineOrigin>>>(&mut self, iter: I) {
+        self.origins.extend(iter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_look_up_the_origin_of_an_output_line() {
+        let mut map = SourceMap::new();
+        map.push(None);
+        map.push(Some(LineOrigin::new("lib/functions.stan", 4)));
+        map.push(Some(LineOrigin::new("model.stan", 2)));
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.origin_of(0), None);
+        assert_eq!(map.origin_of(1), None);
+        assert_eq!(
+            map.origin_of(2),
+            Some((PathBuf::from("lib/functions.stan"), 4))
+        );
+        assert_eq!(map.origin_of(3), Some((PathBuf::from("model.stan"), 2)));
+        assert_eq!(map.origin_of(4), None);
+    }
+
+    #[test]
+    fn identity_map_points_every_line_at_itself() {
+        let map = SourceMap::identity(Path::new("model.stan"), 2);
+
+        assert_eq!(map.origin_of(1), Some((PathBuf::from("model.stan"), 1)));
+        assert_eq!(map.origin_of(2), Some((PathBuf::from("model.stan"), 2)));
+        assert_eq!(map.origin_of(3), None);
+    }
+}
@@ -1,5 +1,14 @@
+use crate::data_validation::{validate_data, DataProblem};
+use crate::error::StanJamError;
+use crate::eval::Data;
+use crate::interface::{block_variables, parameter_sizes, ParameterSize, Variable};
+use crate::json_schema::data_schema;
+use crate::source_map::{LineOrigin, SourceMap};
 use crate::stan_model_block::StanModelBlock;
 use crate::stan_model_block_type::StanModelBlockType;
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{self, Write};
 
 #[derive(Debug, PartialEq)]
 pub struct StanModel {
@@ -82,6 +91,43 @@ impl StanModel {
         }
     }
 
+    /// Add a line of Stan code to the block of the given type.
+    pub fn add_to_block(&mut self, block_type: &StanModelBlockType, line: &str) {
+        match block_type {
+            StanModelBlockType::Functions => self.add_function(line),
+            StanModelBlockType::Data => self.add_data(line),
+            StanModelBlockType::TransformedData => self.add_transformed_data(line),
+            StanModelBlockType::Parameters => self.add_parameter(line),
+            StanModelBlockType::TransformedParameters => self.add_transformed_parameter(line),
+            StanModelBlockType::Model => self.add_model(line),
+            StanModelBlockType::GeneratedQuantities => self.add_generated_quantities(line),
+        }
+    }
+
+    /// Add a line of Stan code, recording the file and line it came from.
+    pub fn add_to_block_with_origin(
+        &mut self,
+        block_type: &StanModelBlockType,
+        line: &str,
+        origin: Option<LineOrigin>,
+    ) {
+        self.block_mut(block_type).add_with_origin(line, origin);
+    }
+
+    /// The block of the given type, creating it if it is optional and absent.
+    pub fn block_mut(&mut self, block_type: &StanModelBlockType) -> &mut StanModelBlock {
+        let optional = match block_type {
+            StanModelBlockType::Data => return &mut self.data,
+            StanModelBlockType::Parameters => return &mut self.parameters,
+            StanModelBlockType::Model => return &mut self.model,
+            StanModelBlockType::Functions => &mut self.functions,
+            StanModelBlockType::TransformedData => &mut self.transformed_data,
+            StanModelBlockType::TransformedParameters => &mut self.transformed_parameters,
+            StanModelBlockType::GeneratedQuantities => &mut self.generated_quantities,
+        };
+        optional.get_or_insert_with(|| StanModelBlock::new(block_type.clone()))
+    }
+
     fn get_optional_block(
         &self,
         block: &Option<StanModelBlock>,
@@ -141,10 +187,126 @@ impl StanModel {
             .collect::<Vec<String>>()
     }
 
+    /// The blocks that are present, in canonical Stan order. The data,
+    /// parameters and model blocks are always present.
+    pub fn blocks(&self) -> Vec<&StanModelBlock> {
+        [
+            self.functions.as_ref(),
+            Some(&self.data),
+            self.transformed_data.as_ref(),
+            Some(&self.parameters),
+            self.transformed_parameters.as_ref(),
+            Some(&self.model),
+            self.generated_quantities.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Render the model as Stan source along with a map from every rendered
+    /// line back to the file and line it was read from.
+    pub fn render_with_source_map(&self) -> (String, SourceMap) {
+        let (lines, origins): (Vec<String>, SourceMap) = self
+            .blocks()
+            .iter()
+            .flat_map(|block| block.render_lines())
+            .unzip();
+        (
+            lines.iter().map(|line| format!("{}\n", line)).collect(),
+            origins,
+        )
+    }
+
+    /// Write the model as Stan source text.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "{}", self)
+    }
+
+    /// Build a new model by applying `f` to every block that is present.
+    pub fn try_map_blocks<F, E>(&self, mut f: F) -> Result<StanModel, E>
+    where
+        F: FnMut(&StanModelBlock) -> Result<StanModelBlock, E>,
+    {
+        Ok(StanModel {
+            functions: self.functions.as_ref().map(&mut f).transpose()?,
+            data: f(&self.data)?,
+            transformed_data: self.transformed_data.as_ref().map(&mut f).transpose()?,
+            parameters: f(&self.parameters)?,
+            transformed_parameters: self
+                .transformed_parameters
+                .as_ref()
+                .map(&mut f)
+                .transpose()?,
+            model: f(&self.model)?,
+            generated_quantities: self.generated_quantities.as_ref().map(&mut f).transpose()?,
+        })
+    }
+
+    /// Build a new model by applying `f` to every block that is present.
+    pub fn map_blocks<F>(&self, mut f: F) -> StanModel
+    where
+        F: FnMut(&StanModelBlock) -> StanModelBlock,
+    {
+        self.try_map_blocks(|block| Ok::<_, Infallible>(f(block)))
+            .unwrap_or_else(|never| match never {})
+    }
+
+    /// Whether any block contains an `#include` directive outside of
+    /// comments and string literals.
     pub fn has_include_directive(&self) -> bool {
-        self.collect_stan_model_segments()
+        self.blocks()
             .iter()
-            .any(|s| s.contains("#include"))
+            .any(|block| block.has_include_directive())
+    }
+
+    /// The inputs declared in the data block, in declaration order, with
+    /// their types, dimensions and constraints.
+    pub fn data_interface(&self) -> Result<Vec<Variable>, StanJamError> {
+        block_variables(&self.data)
+    }
+
+    /// The parameters declared in the parameters block, in declaration
+    /// order, with their types, dimensions and constraints.
+    pub fn parameter_interface(&self) -> Result<Vec<Variable>, StanJamError> {
+        block_variables(&self.parameters)
+    }
+
+    /// The constrained shape and unconstrained size of every parameter, with
+    /// sizes such as `K` read from `data`.
+    pub fn parameter_sizes(&self, data: &Data) -> Result<Vec<ParameterSize>, StanJamError> {
+        parameter_sizes(&self.parameter_interface()?, data)
+    }
+
+    /// Check CmdStan JSON `data` against the data block and return every
+    /// problem found.
+    pub fn validate_data(&self, data: &Data) -> Result<Vec<DataProblem>, StanJamError> {
+        Ok(validate_data(&self.data_interface()?, data))
+    }
+
+    /// A JSON Schema document describing the CmdStan JSON data the data
+    /// block expects.
+    pub fn data_schema(&self) -> Result<serde_json::Value, StanJamError> {
+        Ok(data_schema(&self.data_interface()?))
+    }
+
+    /// Parse every block, carrying on past errors, and return all of the
+    /// syntax errors in block order.
+    pub fn syntax_errors(&self) -> Vec<StanJamError> {
+        self.blocks()
+            .iter()
+            .flat_map(|block| block.parse_with_recovery().1)
+            .collect()
+    }
+}
+
+impl fmt::Display for StanModel {
+    /// Render every present block, with headers, in canonical order.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for block in self.blocks() {
+            write!(f, "{}", block)?;
+        }
+        Ok(())
     }
 }
 
@@ -291,6 +453,181 @@ mod tests {
         assert!(!noincludes.has_include_directive());
     }
 
+    #[test]
+    fn add_to_block_routes_lines_to_the_matching_block() {
+        let mut routed = StanModel::new();
+        routed.add_to_block(&StanModelBlockType::Data, "int<lower=0> N;");
+        routed.add_to_block(&StanModelBlockType::TransformedParameters, "real mu;");
+        routed.add_to_block(&StanModelBlockType::GeneratedQuantities, "real y_rep;");
+
+        let mut expected = StanModel::new();
+        expected.add_data("int<lower=0> N;");
+        expected.add_transformed_parameter("real mu;");
+        expected.add_generated_quantities("real y_rep;");
+
+        assert_eq!(routed, expected);
+    }
+
+    #[test]
+    fn blocks_are_listed_in_canonical_order() {
+        let mut model = StanModel::new();
+        model.add_generated_quantities("real y_rep;");
+        model.add_function("real f(real x) { return x; }");
+
+        let block_types = model
+            .blocks()
+            .iter()
+            .map(|block| block.get_block_type().clone())
+            .collect::<Vec<StanModelBlockType>>();
+
+        assert_eq!(
+            block_types,
+            vec![
+                StanModelBlockType::Functions,
+                StanModelBlockType::Data,
+                StanModelBlockType::Parameters,
+                StanModelBlockType::Model,
+                StanModelBlockType::GeneratedQuantities,
+            ]
+        );
+    }
+
+    #[test]
+    fn can_render_a_model_as_stan_source() {
+        let mut model = StanModel::new();
+        model.add_generated_quantities("real y_rep = normal_rng(mu, 1);");
+        model.add_model("mu ~ normal(0, 1);");
+        model.add_data("int<lower=0> N;");
+        model.add_parameter("real mu;");
+        model.add_transformed_data("real half = 0.5;");
+
+        let expected = "data {
+  int<lower=0> N;
+}
+transformed data {
+  real half = 0.5;
+}
+parameters {
+  real mu;
+}
+model {
+  mu ~ normal(0, 1);
+}
+generated quantities {
+  real y_rep = normal_rng(mu, 1);
+}
+";
+
+        assert_eq!(model.to_string(), expected);
+
+        let mut written = Vec::new();
+        model.write_to(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), expected);
+    }
+
+    #[test]
+    fn block_mut_creates_optional_blocks_on_demand() {
+        let mut model = StanModel::new();
+        assert_eq!(model.transformed_data, None);
+
+        model
+            .block_mut(&StanModelBlockType::TransformedData)
+            .add("real x = 1;");
+        model.add_to_block_with_origin(
+            &StanModelBlockType::Data,
+            "int N;",
+            Some(LineOrigin::new("model.stan", 2)),
+        );
+
+        let mut expected = StanModel::new();
+        expected.add_transformed_data("real x = 1;");
+        expected.add_data("int N;");
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn rendered_lines_map_back_to_their_origin() {
+        let mut model = StanModel::new();
+        model.add_to_block_with_origin(
+            &StanModelBlockType::Data,
+            "int N;",
+            Some(LineOrigin::new("model.stan", 2)),
+        );
+        model.add_to_block_with_origin(
+            &StanModelBlockType::Model,
+            "target += f(N);",
+            Some(LineOrigin::new("lib/model_body.stan", 1)),
+        );
+
+        let (text, map) = model.render_with_source_map();
+
+        assert_eq!(text, model.to_string());
+        assert_eq!(map.len(), text.lines().count());
+        assert_eq!(map.origin_of(1), None);
+        assert_eq!(map.origin_of(2), Some(("model.stan".into(), 2)));
+        assert_eq!(text.lines().nth(6), Some("  target += f(N);"));
+        assert_eq!(map.origin_of(7), Some(("lib/model_body.stan".into(), 1)));
+    }
+
+    #[test]
+    fn syntax_errors_are_collected_from_every_block() {
+        let mut model = StanModel::new();
+        model.add_data("int N");
+        model.add_parameter("real mu;");
+        model.add_model("mu ~ normal(0, 1;");
+
+        let messages = model
+            .syntax_errors()
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            messages,
+            vec![
+                "<data>:1:6: syntax error: expected ';', found end of input",
+                "<model>:1:17: syntax error: expected ')', found ';'",
+            ]
+        );
+    }
+
+    #[test]
+    fn data_interface_lists_the_data_declarations() {
+        let mut model = StanModel::new();
+        model.add_data("int<lower=1> N;");
+        model.add_data("array[N] real y;");
+        model.add_parameter("real mu;");
+
+        let names = model
+            .data_interface()
+            .unwrap()
+            .iter()
+            .map(|variable| variable.to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(names, vec!["int<lower=1> N", "array[N] real y"]);
+    }
+
+    #[test]
+    fn parameter_sizes_use_the_data() {
+        let mut model = StanModel::new();
+        model.add_data("int<lower=1> K;");
+        model.add_parameter("real mu;");
+        model.add_parameter("simplex[K] theta;");
+        let data = serde_json::json!({"K": 4}).as_object().cloned().unwrap();
+
+        let sizes = model.parameter_sizes(&data).unwrap();
+
+        assert_eq!(
+            sizes
+                .iter()
+                .map(|size| size.unconstrained_size)
+                .collect::<Vec<usize>>(),
+            vec![1, 3]
+        );
+        assert_eq!(sizes[1].shape, vec![4]);
+    }
+
     #[test]
     fn default_model_is_the_same_as_new_model() {
         let default_model = StanModel::default();
@@ -1,10 +1,22 @@
+use std::fmt;
 use std::slice::Iter;
 
+use std::path::PathBuf;
+
+use crate::ast::Stmt;
+use crate::error::{SourceLocation, StanJamError};
+use crate::lexer::{tokenize, LineIndex, Token, TokenKind};
+use crate::parser::{parse_statements, parse_statements_with_recovery, ParseError};
+use crate::source_map::{LineOrigin, SourceMap};
 use crate::stan_model_block_type::StanModelBlockType;
+use crate::stan_source_parser::block_splitter::{BlockEvent, BlockTracker};
 
-#[derive(Debug, PartialEq, Clone)]
+/// The lines of one program block. Each line remembers the file and line it
+/// was read from, when known, so provenance survives edits to the block.
+#[derive(Debug, Clone)]
 pub struct StanModelBlock {
     code: Vec<String>,
+    origins: Vec<Option<LineOrigin>>,
     block_type: StanModelBlockType,
 }
 
@@ -12,29 +24,189 @@ impl StanModelBlock {
     pub fn new(block_type: StanModelBlockType) -> StanModelBlock {
         StanModelBlock {
             code: Vec::new(),
+            origins: Vec::new(),
             block_type,
         }
     }
 
     pub fn add(&mut self, line: &str) {
+        self.add_with_origin(line, None);
+    }
+
+    pub fn add_with_origin(&mut self, line: &str, origin: Option<LineOrigin>) {
         self.code.push(line.to_string());
+        self.origins.push(origin);
     }
 
-    pub fn get_code(&self) -> Iter<String> {
+    pub fn get_code(&self) -> Iter<'_, String> {
         self.code.iter()
     }
+
+    /// The lines of the block paired with where each one came from.
+    pub fn get_code_with_origins(
+        &self,
+    ) -> impl Iterator<Item = (&String, Option<&LineOrigin>)> + '_ {
+        self.code
+            .iter()
+            .zip(self.origins.iter().map(|origin| origin.as_ref()))
+    }
+
+    pub fn get_block_type(&self) -> &StanModelBlockType {
+        &self.block_type
+    }
+
+    /// The lines of the block joined into a single source string.
+    pub fn source(&self) -> String {
+        self.code.join("\n")
+    }
+
+    /// Whether the block contains an `#include` directive. Includes inside
+    /// comments and string literals do not count.
+    pub fn has_include_directive(&self) -> bool {
+        tokenize(&self.source())
+            .iter()
+            .any(|token| token.kind == TokenKind::Include)
+    }
+
+    /// Parse the block into statements. Spans are byte offsets into
+    /// `self.source()`.
+    pub fn parse(&self) -> Result<Vec<Stmt>, ParseError> {
+        parse_statements(&self.source())
+    }
+
+    /// Parse the block, carrying on past syntax errors. Returns the
+    /// statements that parsed and every error located in its original file.
+    pub fn parse_with_recovery(&self) -> (Vec<Stmt>, Vec<StanJamError>) {
+        let (statements, errors) = parse_statements_with_recovery(&self.source());
+        let errors = errors
+            .into_iter()
+            .map(|error| StanJamError::SyntaxError {
+                location: self.location_of(error.span.start),
+                message: error.message,
+            })
+            .collect();
+        (statements, errors)
+    }
+
+    /// Where byte `offset` of `self.source()` was read from. Lines without a
+    /// recorded origin are located at `<block name>:line:column`.
+    pub fn location_of(&self, offset: usize) -> SourceLocation {
+        let source = self.source();
+        let offset = offset.min(source.len());
+        let index = source[..offset].matches('\n').count();
+        let line_start = source[..offset]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1);
+        self.location_at(index, source[line_start..offset].chars().count() + 1)
+    }
+
+    /// The location of 1-based `column` on 0-based line `index` of the
+    /// block's source. Columns of lines with an origin count from the start
+    /// of the original line, indentation included.
+    pub fn location_at(&self, index: usize, column: usize) -> SourceLocation {
+        match self.origins.get(index).and_then(|origin| origin.as_ref()) {
+            Some(origin) => SourceLocation::new(&origin.path, origin.line, origin.indent + column),
+            None => SourceLocation::new(
+                PathBuf::from(format!("<{}>", self.block_type.name())),
+                index + 1,
+                column,
+            ),
+        }
+    }
+
+    /// The rendered lines of the block and the origin of each. The header
+    /// and closing brace have no origin.
+    pub fn render_lines(&self) -> Vec<(String, Option<LineOrigin>)> {
+        let mut rendered = vec![(format!("{} {{", self.block_type.name()), None)];
+        let mut tracker = BlockTracker::inside(self.block_type.clone());
+        // Tokenize the whole block so that comments spanning lines are seen
+        // as one token.
+        let source = self.source();
+        let line_index = LineIndex::new(&source);
+        let tokens = tokenize(&source);
+        let mut tokens = tokens.iter().peekable();
+        for (index, (line, origin)) in self.get_code_with_origins().enumerate() {
+            let line = line.trim();
+            let depth = tracker.depth();
+            let line_tokens = std::iter::from_fn(|| {
+                tokens.next_if(|token| line_index.line(token.span.start) == index)
+            });
+            let leading_closes = follow_line(line_tokens, &source, &self.block_type, &mut tracker);
+            let indent = depth.saturating_sub(leading_closes);
+            rendered.push((
+                format!("{}{}", INDENT.repeat(indent), line),
+                origin.cloned(),
+            ));
+        }
+        rendered.push(("}".to_string(), None));
+        rendered
+    }
+
+    /// Render the block together with a source map for the rendered lines.
+    pub fn render_with_source_map(&self) -> (String, SourceMap) {
+        let (lines, origins): (Vec<String>, SourceMap) = self.render_lines().into_iter().unzip();
+        (
+            lines.iter().map(|line| format!("{}\n", line)).collect(),
+            origins,
+        )
+    }
 }
 
-#[cfg(test)]
+/// Blocks compare by type and code; line origins are provenance, not content.
+impl PartialEq for StanModelBlock {
+    fn eq(&self, other: &StanModelBlock) -> bool {
+        self.block_type == other.block_type && self.code == other.code
+    }
+}
+
+/// Indentation used for each level of nesting when rendering a block.
+pub const INDENT: &str = "  ";
+
+impl fmt::Display for StanModelBlock {
+    /// Render the block with its header, re-indenting the body by brace depth.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (line, _) in self.render_lines() {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
 
+/// Follow the tokens of a line of a block with `tracker`, which never leaves
+/// the block, and return the number of `}` the line starts with. Braces
+/// inside string literals and comments are ignored.
+fn follow_line<'a>(
+    tokens: impl Iterator<Item = &'a Token>,
+    source: &str,
+    block_type: &StanModelBlockType,
+    tracker: &mut BlockTracker,
+) -> usize {
+    let mut leading_closes = 0;
+    let mut leading = true;
+    for token in tokens {
+        let event = tracker.step(token, source);
+        leading &= matches!(event, BlockEvent::Close | BlockEvent::Body(-1));
+        if leading {
+            leading_closes += 1;
+        }
+        if tracker.depth() == 0 {
+            *tracker = BlockTracker::inside(block_type.clone());
+        }
+    }
+    leading_closes
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ast::StmtKind;
 
     #[test]
     fn test_new() {
         let model1 = StanModelBlock::new(StanModelBlockType::Data);
         let model2 = StanModelBlock {
             code: Vec::new(),
+            origins: Vec::new(),
             block_type: StanModelBlockType::Data,
         };
 
@@ -69,4 +241,180 @@ mod tests {
             code_vec.collect::<Vec<&String>>()
         );
     }
+
+    #[test]
+    fn can_parse_the_block_into_statements() {
+        let mut block = StanModelBlock::new(StanModelBlockType::Model);
+        block.add("mu ~ normal(0, 1); // prior");
+        block.add("for (n in 1:N)");
+        block.add("  target += normal_lpdf(y[n] | mu, 1);");
+
+        let statements = block.parse().unwrap();
+
+        assert_eq!(statements.len(), 2);
+        assert!(matches!(statements[0].kind, StmtKind::Tilde { .. }));
+        assert_eq!(
+            &block.source()[statements[1].span.range()],
+            "for (n in 1:N)\n  target += normal_lpdf(y[n] | mu, 1);"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_are_located_in_the_original_file() {
+        let mut block = StanModelBlock::new(StanModelBlockType::Model);
+        block.add_with_origin("mu ~ normal(0, 1)", Some(LineOrigin::new("model.stan", 12)));
+        block.add_with_origin(
+            "sigma ~ exponential(1);",
+            Some(LineOrigin::new("model.stan", 13)),
+        );
+        block.add("y ~ normal(mu sigma);");
+
+        let (statements, errors) = block.parse_with_recovery();
+
+        assert_eq!(statements.len(), 0);
+        let messages = errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            messages,
+            vec![
+                "model.stan:13:1: syntax error: expected ';', found 'sigma'",
+                "<model>:3:15: syntax error: expected ')', found 'sigma'",
+            ]
+        );
+    }
+
+    #[test]
+    fn display_renders_the_header_and_indents_by_brace_depth() {
+        let mut block = StanModelBlock::new(StanModelBlockType::TransformedParameters);
+        block.add("vector[N] mu;");
+        block.add("for (n in 1:N) {");
+        block.add("if (n > 1) {");
+        block.add("mu[n] = mu[n - 1];");
+        block.add("} else {");
+        block.add("mu[n] = 0; // {");
+        block.add("}");
+        block.add("}");
+
+        assert_eq!(
+            block.to_string(),
+            "transformed parameters {
+  vector[N] mu;
+  for (n in 1:N) {
+    if (n > 1) {
+      mu[n] = mu[n - 1];
+    } else {
+      mu[n] = 0; // {
+    }
+  }
+}
+"
+        );
+    }
+
+    #[test]
+    fn braces_in_comments_spanning_lines_do_not_change_the_indentation() {
+        let mut block = StanModelBlock::new(StanModelBlockType::Model);
+        block.add("/* disabled {");
+        block.add("y ~ normal(0, 1);");
+        block.add("} */");
+        block.add("if (N > 0) {");
+        block.add("/* }");
+        block.add("*/ z ~ std_normal();");
+        block.add("}");
+
+        assert_eq!(
+            block.to_string(),
+            "model {
+  /* disabled {
+  y ~ normal(0, 1);
+  } */
+  if (N > 0) {
+    /* }
+    */ z ~ std_normal();
+  }
+}
+"
+        );
+    }
+
+    #[test]
+    fn lines_keep_their_origin() {
+        let mut block = StanModelBlock::new(StanModelBlockType::Functions);
+        block.add_with_origin("real f(real x) {", Some(LineOrigin::new("lib/f.stan", 3)));
+        block.add_with_origin("return x;", Some(LineOrigin::new("lib/f.stan", 4)));
+        block.add("}");
+
+        assert_eq!(
+            block.get_code_with_origins().collect::<Vec<_>>(),
+            vec![
+                (
+                    &"real f(real x) {".to_string(),
+                    Some(&LineOrigin::new("lib/f.stan", 3))
+                ),
+                (
+                    &"return x;".to_string(),
+                    Some(&LineOrigin::new("lib/f.stan", 4))
+                ),
+                (&"}".to_string(), None),
+            ]
+        );
+
+        let mut without_origins = StanModelBlock::new(StanModelBlockType::Functions);
+        without_origins.add("real f(real x) {");
+        without_origins.add("return x;");
+        without_origins.add("}");
+        assert_eq!(block, without_origins);
+    }
+
+    #[test]
+    fn rendering_produces_a_source_map() {
+        let mut block = StanModelBlock::new(StanModelBlockType::Model);
+        block.add_with_origin("y ~ normal(0, 1);", Some(LineOrigin::new("model.stan", 7)));
+
+        let (text, map) = block.render_with_source_map();
+
+        assert_eq!(text, "model {\n  y ~ normal(0, 1);\n}\n");
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.origin_of(1), None);
+        assert_eq!(map.get(2), Some(&LineOrigin::new("model.stan", 7)));
+        assert_eq!(map.origin_of(3), None);
+    }
+
+    #[test]
+    fn include_detection_ignores_comments_and_strings() {
+        let mut block = StanModelBlock::new(StanModelBlockType::Model);
+        block.add("// #include \"old.stan\"");
+        block.add("print(\"#include\");");
+        block.add("/*");
+        block.add("#include \"commented.stan\"");
+        block.add("*/");
+        assert!(!block.has_include_directive());
+
+        block.add("#include \"model_body.stan\"");
+        assert!(block.has_include_directive());
+    }
+
+    #[test]
+    fn display_renders_an_empty_block() {
+        let block = StanModelBlock::new(StanModelBlockType::Data);
+        assert_eq!(block.to_string(), "data {\n}\n");
+    }
+
+    #[test]
+    fn following_lines_ignores_strings_and_comments() {
+        let block_type = StanModelBlockType::Model;
+        let mut tracker = BlockTracker::inside(block_type.clone());
+        let follow = |line: &str, tracker: &mut BlockTracker| {
+            follow_line(tokenize(line).iter(), line, &block_type, tracker)
+        };
+
+        assert_eq!(follow("print(\"{\"); /* } */ {", &mut tracker), 0);
+        assert_eq!(tracker.depth(), 2);
+        assert_eq!(follow("} else {", &mut tracker), 1);
+        assert_eq!(tracker.depth(), 2);
+        assert_eq!(follow("} } // {", &mut tracker), 2);
+        assert_eq!(tracker.depth(), 1);
+    }
 }
@@ -8,3 +8,63 @@ pub enum StanModelBlockType {
     Model,
     GeneratedQuantities,
 }
+
+impl StanModelBlockType {
+    /// All block types, in the order Stan requires them to appear.
+    pub const ALL: [StanModelBlockType; 7] = [
+        StanModelBlockType::Functions,
+        StanModelBlockType::Data,
+        StanModelBlockType::TransformedData,
+        StanModelBlockType::Parameters,
+        StanModelBlockType::TransformedParameters,
+        StanModelBlockType::Model,
+        StanModelBlockType::GeneratedQuantities,
+    ];
+
+    /// The block name as written in a block header, e.g. `transformed data`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StanModelBlockType::Functions => "functions",
+            StanModelBlockType::Data => "data",
+            StanModelBlockType::TransformedData => "transformed data",
+            StanModelBlockType::Parameters => "parameters",
+            StanModelBlockType::TransformedParameters => "transformed parameters",
+            StanModelBlockType::Model => "model",
+            StanModelBlockType::GeneratedQuantities => "generated quantities",
+        }
+    }
+
+    /// Look up a block type from its header name. Runs of whitespace between
+    /// the words of a two-word name are accepted.
+    pub fn from_name(name: &str) -> Option<StanModelBlockType> {
+        let name = name.split_whitespace().collect::<Vec<&str>>().join(" ");
+        StanModelBlockType::ALL
+            .into_iter()
+            .find(|block_type| block_type.name() == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_names_round_trip() {
+        for block_type in StanModelBlockType::ALL {
+            assert_eq!(
+                StanModelBlockType::from_name(block_type.name()),
+                Some(block_type.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn from_name_accepts_extra_whitespace_and_rejects_unknown_names() {
+        assert_eq!(
+            StanModelBlockType::from_name("  generated \t quantities "),
+            Some(StanModelBlockType::GeneratedQuantities)
+        );
+        assert_eq!(StanModelBlockType::from_name("transformed"), None);
+        assert_eq!(StanModelBlockType::from_name("Data"), None);
+    }
+}
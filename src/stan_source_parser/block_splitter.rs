@@ -0,0 +1,724 @@
+use crate::error::{SourceLocation, StanJamError};
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::source_map::{LineOrigin, SourceMap};
+use crate::stan_model::StanModel;
+use crate::stan_model_block_type::StanModelBlockType;
+use std::path::Path;
+
+/// Split Stan source text into its program blocks.
+///
+/// Every block header (`data {`, `transformed parameters {`, ...) is matched
+/// at brace depth zero, and the lines of its body are routed into the
+/// corresponding block of the returned `StanModel`. Braces inside comments
+/// and string literals are ignored when tracking depth. Every line, and any
+/// error, is located in `path`.
+pub fn split_blocks(source: &str, path: &Path) -> Result<StanModel, StanJamError> {
+    let line_count = source.split('\n').count();
+    split_blocks_with_source_map(source, &SourceMap::identity(path, line_count))
+}
+
+/// Split generated Stan source (such as the output of include flattening)
+/// into its program blocks. Block lines, and any error, are located through
+/// `source_map`.
+pub fn split_blocks_with_source_map(
+    source: &str,
+    source_map: &SourceMap,
+) -> Result<StanModel, StanJamError> {
+    let (model, mut errors) = split_blocks_with_recovery(source, source_map);
+    if errors.is_empty() {
+        Ok(model)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+/// Split Stan source into its program blocks, carrying on past structural
+/// errors. Returns the blocks that could be read and every error, in source
+/// order.
+///
+/// The body of an unknown or repeated block is skipped, a stray `}` at the
+/// top level is ignored, and a block left open at the end of the file keeps
+/// the lines read so far.
+pub fn split_blocks_with_recovery(
+    source: &str,
+    source_map: &SourceMap,
+) -> (StanModel, Vec<StanJamError>) {
+    let mut splitter = BlockSplitter {
+        source_map,
+        model: StanModel::new(),
+        errors: Vec::new(),
+        tracker: BlockTracker::default(),
+        line: 0,
+        column: 0,
+        header: String::new(),
+        header_location: None,
+        current_block: None,
+        current_line: String::new(),
+        current_line_origin: None,
+        seen_blocks: Vec::new(),
+    };
+    let mut position = 0;
+    for token in tokenize(source) {
+        splitter.push_whitespace(&source[position..token.span.start]);
+        position = token.span.end;
+        let text = token.text(source);
+        match splitter.tracker.step(&token, source) {
+            BlockEvent::Open(block_type) => splitter.open_block(block_type),
+            BlockEvent::Close => splitter.close_block(),
+            BlockEvent::StrayClose => splitter.stray_close(),
+            _ if token.is_comment() || text.starts_with("/*") => {
+                for (index, line) in text.split('\n').enumerate() {
+                    if index > 0 {
+                        splitter.newline();
+                    }
+                    splitter.push_comment(line);
+                }
+            }
+            _ => splitter.push_text(text),
+        }
+    }
+    splitter.push_whitespace(&source[position..]);
+
+    splitter.finish()
+}
+
+/// Words that start a program block header.
+const BLOCK_KEYWORDS: &[&str] = &[
+    "functions",
+    "data",
+    "transformed",
+    "parameters",
+    "model",
+    "generated",
+];
+
+/// Whether `source` is a program made of blocks, rather than a fragment of
+/// statements such as an included file of functions.
+pub(crate) fn is_program(source: &str) -> bool {
+    tokenize(source)
+        .iter()
+        .find(|token| !token.is_comment() && token.kind != TokenKind::Include)
+        .is_some_and(|token| BLOCK_KEYWORDS.contains(&token.text(source)))
+}
+
+/// Follows the program blocks of a stream of Stan source token by token,
+/// such as the lines flattened so far or the lines of one block body.
+/// Comments, strings and include directives never change the depth.
+#[derive(Debug, Default)]
+pub(crate) struct BlockTracker {
+    depth: usize,
+    header: Vec<String>,
+    block: Option<StanModelBlockType>,
+}
+
+/// What a token did to a `BlockTracker`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum BlockEvent {
+    /// A comment or include directive outside of any block.
+    Outside,
+    /// Part of the header of the next block.
+    Header,
+    /// The `{` opening a block, with the type its header names, if any.
+    Open(Option<StanModelBlockType>),
+    /// The `}` closing the current block.
+    Close,
+    /// A `}` outside of any block.
+    StrayClose,
+    /// Any other token inside a block, with its change in brace depth.
+    Body(isize),
+}
+
+impl BlockTracker {
+    /// A tracker already inside the body of a block of type `block_type`.
+    pub(crate) fn inside(block_type: StanModelBlockType) -> BlockTracker {
+        BlockTracker {
+            depth: 1,
+            header: Vec::new(),
+            block: Some(block_type),
+        }
+    }
+
+    /// Follow one token of `source`.
+    pub(crate) fn step(&mut self, token: &Token, source: &str) -> BlockEvent {
+        let text = token.text(source);
+        let is_brace = token.kind == TokenKind::Punctuation;
+        match (self.depth, is_brace, text) {
+            (0, _, _) if token.is_comment() || token.kind == TokenKind::Include => {
+                BlockEvent::Outside
+            }
+            (0, true, "{") => {
+                self.block = StanModelBlockType::from_name(&self.header.join(" "));
+                self.header.clear();
+                self.depth = 1;
+                BlockEvent::Open(self.block.clone())
+            }
+            (0, true, "}") => BlockEvent::StrayClose,
+            (0, _, _) => {
+                self.header.push(text.to_string());
+                BlockEvent::Header
+            }
+            (1, true, "}") => {
+                self.block = None;
+                self.depth = 0;
+                BlockEvent::Close
+            }
+            (_, true, "{") => {
+                self.depth += 1;
+                BlockEvent::Body(1)
+            }
+            (_, true, "}") => {
+                self.depth -= 1;
+                BlockEvent::Body(-1)
+            }
+            _ => BlockEvent::Body(0),
+        }
+    }
+
+    /// Follow every token of `source`, which must not split a token.
+    pub(crate) fn push(&mut self, source: &str) {
+        for token in tokenize(source) {
+            self.step(&token, source);
+        }
+    }
+
+    /// The block the source followed so far ends in, if any.
+    pub(crate) fn block(&self) -> Option<&StanModelBlockType> {
+        self.block.as_ref()
+    }
+
+    /// The brace depth, counting the braces of the block itself.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+/// A block of a program: the comments before its header, its type and the
+/// byte range of its body.
+pub(crate) struct BlockSource {
+    pub leading: Vec<Token>,
+    pub block_type: StanModelBlockType,
+    pub body_start: usize,
+    pub body_end: usize,
+}
+
+/// Find the blocks of a program, in source order, and the comments after the
+/// last block. Blocks with unknown headers are skipped.
+pub(crate) fn program_blocks(source: &str) -> (Vec<BlockSource>, Vec<Token>) {
+    let mut blocks = Vec::new();
+    let mut leading = Vec::new();
+    let mut tracker = BlockTracker::default();
+    let mut open = None;
+
+    for token in tokenize(source) {
+        match tracker.step(&token, source) {
+            BlockEvent::Outside => leading.push(token),
+            BlockEvent::Open(block_type) => open = Some((block_type, token.span.end)),
+            BlockEvent::Close => {
+                if let Some((Some(block_type), body_start)) = open.take() {
+                    blocks.push(BlockSource {
+                        leading: std::mem::take(&mut leading),
+                        block_type,
+                        body_start,
+                        body_end: token.span.start,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    (blocks, leading)
+}
+
+#[derive(Debug)]
+struct BlockSplitter<'a> {
+    source_map: &'a SourceMap,
+    model: StanModel,
+    errors: Vec<StanJamError>,
+    tracker: BlockTracker,
+    line: usize,
+    column: usize,
+    header: String,
+    header_location: Option<SourceLocation>,
+    current_block: Option<StanModelBlockType>,
+    current_line: String,
+    current_line_origin: Option<LineOrigin>,
+    seen_blocks: Vec<StanModelBlockType>,
+}
+
+impl BlockSplitter<'_> {
+    fn push_text(&mut self, text: &str) {
+        if self.tracker.depth() == 0 {
+            if self.header_location.is_none() && !text.trim().is_empty() {
+                self.header_location = Some(self.location());
+            }
+            self.header.push_str(text);
+        } else {
+            self.append(text);
+        }
+        self.column += text.chars().count();
+    }
+
+    /// The whitespace between two tokens.
+    fn push_whitespace(&mut self, whitespace: &str) {
+        for (index, line) in whitespace.split('\n').enumerate() {
+            if index > 0 {
+                self.newline();
+            }
+            self.push_text(line);
+        }
+    }
+
+    fn push_comment(&mut self, comment: &str) {
+        if self.tracker.depth() > 0 {
+            self.append(comment);
+        }
+        self.column += comment.chars().count();
+    }
+
+    /// Append text to the current body line, noting where the line starts.
+    fn append(&mut self, text: &str) {
+        if self.current_line.trim().is_empty() && !text.trim().is_empty() {
+            let indent = self.column + text.chars().count() - text.trim_start().chars().count();
+            self.current_line_origin = self
+                .source_map
+                .get(self.line + 1)
+                .map(|origin| origin.clone().indented(indent));
+        }
+        self.current_line.push_str(text);
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.column = 0;
+        if self.tracker.depth() > 0 {
+            self.flush_line();
+        } else {
+            self.header.push(' ');
+        }
+    }
+
+    /// Open a block. The lines of a block that is unknown or repeated are
+    /// not kept.
+    fn open_block(&mut self, block_type: Option<StanModelBlockType>) {
+        let location = self
+            .header_location
+            .take()
+            .unwrap_or_else(|| self.location());
+        self.current_block = match block_type {
+            None => {
+                self.errors.push(StanJamError::UnknownBlock {
+                    name: self.header.trim().to_string(),
+                    location,
+                });
+                None
+            }
+            Some(block_type) if self.seen_blocks.contains(&block_type) => {
+                self.errors.push(StanJamError::DuplicateBlock {
+                    name: block_type.name().to_string(),
+                    location,
+                });
+                None
+            }
+            Some(block_type) => {
+                self.seen_blocks.push(block_type.clone());
+                Some(block_type)
+            }
+        };
+        self.header.clear();
+        self.column += 1;
+    }
+
+    fn close_block(&mut self) {
+        self.flush_line();
+        self.current_block = None;
+        self.column += 1;
+    }
+
+    fn stray_close(&mut self) {
+        self.errors.push(StanJamError::UnbalancedBraces {
+            message: "unexpected '}' outside of a block".to_string(),
+            location: self.location(),
+        });
+        self.column += 1;
+    }
+
+    fn flush_line(&mut self) {
+        let line = self.current_line.trim();
+        if let Some(block_type) = &self.current_block {
+            if !line.is_empty() {
+                self.model.add_to_block_with_origin(
+                    block_type,
+                    line,
+                    self.current_line_origin.take(),
+                );
+            }
+        }
+        self.current_line.clear();
+        self.current_line_origin = None;
+    }
+
+    fn finish(mut self) -> (StanModel, Vec<StanJamError>) {
+        if self.tracker.depth() > 0 {
+            self.errors.push(StanJamError::UnbalancedBraces {
+                message: "missing '}' at end of file".to_string(),
+                location: self.location(),
+            });
+        }
+        if let Some(location) = self.header_location.take() {
+            self.errors.push(StanJamError::UnknownBlock {
+                name: self.header.trim().to_string(),
+                location,
+            });
+        }
+        self.flush_line();
+        (self.model, self.errors)
+    }
+
+    /// The location of the next character to be consumed, mapped back to
+    /// its original file.
+    fn location(&self) -> SourceLocation {
+        match self.source_map.get(self.line + 1) {
+            Some(origin) => SourceLocation::new(&origin.path, origin.line, self.column + 1),
+            None => SourceLocation::new("<unknown>", self.line + 1, self.column + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_split_all_blocks_into_a_model() {
+        let source = r#"
+            functions {
+                real twice(real x) {
+                    return 2 * x;
+                }
+            }
+            data {
+                int<lower=0> N;
+                vector[N] y;
+            }
+            transformed data {
+                real y_mean = mean(y);
+            }
+            parameters {
+                real mu;
+                real<lower=0> sigma;
+            }
+            transformed parameters {
+                real mu2 = twice(mu);
+            }
+            model {
+                for (n in 1:N) {
+                    if (n > 1) {
+                        y[n] ~ normal(mu, sigma);
+                    }
+                }
+            }
+            generated quantities {
+                real y_rep = normal_rng(mu, sigma);
+            }
+        "#;
+
+        let model = split_blocks(source, Path::new("model.stan")).unwrap();
+
+        let mut expected = StanModel::new();
+        expected.add_function("real twice(real x) {");
+        expected.add_function("return 2 * x;");
+        expected.add_function("}");
+        expected.add_data("int<lower=0> N;");
+        expected.add_data("vector[N] y;");
+        expected.add_transformed_data("real y_mean = mean(y);");
+        expected.add_parameter("real mu;");
+        expected.add_parameter("real<lower=0> sigma;");
+        expected.add_transformed_parameter("real mu2 = twice(mu);");
+        expected.add_model("for (n in 1:N) {");
+        expected.add_model("if (n > 1) {");
+        expected.add_model("y[n] ~ normal(mu, sigma);");
+        expected.add_model("}");
+        expected.add_model("}");
+        expected.add_generated_quantities("real y_rep = normal_rng(mu, sigma);");
+
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn can_track_the_block_a_stream_of_source_ends_in() {
+        let mut tracker = BlockTracker::default();
+        assert_eq!(tracker.block(), None);
+
+        tracker.push("functions {\n  real f(real x) {\n");
+        assert_eq!(tracker.block(), Some(&StanModelBlockType::Functions));
+        tracker.push("    return x; // }\n  }\n");
+        assert_eq!(tracker.block(), Some(&StanModelBlockType::Functions));
+        tracker.push("}\n/* data { */ transformed\n  data {");
+        assert_eq!(tracker.block(), Some(&StanModelBlockType::TransformedData));
+        tracker.push("}");
+        assert_eq!(tracker.block(), None);
+    }
+
+    #[test]
+    fn the_tracker_reports_what_each_token_does() {
+        let source = "// note\ndata { { } } }";
+        let mut tracker = BlockTracker::default();
+
+        let events = tokenize(source)
+            .iter()
+            .map(|token| tracker.step(token, source))
+            .collect::<Vec<BlockEvent>>();
+
+        assert_eq!(
+            events,
+            vec![
+                BlockEvent::Outside,
+                BlockEvent::Header,
+                BlockEvent::Open(Some(StanModelBlockType::Data)),
+                BlockEvent::Body(1),
+                BlockEvent::Body(-1),
+                BlockEvent::Close,
+                BlockEvent::StrayClose,
+            ]
+        );
+    }
+
+    #[test]
+    fn rendering_and_splitting_a_model_round_trips() {
+        let mut model = StanModel::new();
+        model.add_function("real twice(real x) {");
+        model.add_function("return 2 * x;");
+        model.add_function("}");
+        model.add_data("int<lower=0> N;");
+        model.add_model("target += twice(1);");
+
+        let rendered = model.to_string();
+
+        assert_eq!(
+            split_blocks(&rendered, Path::new("model.stan")).unwrap(),
+            model
+        );
+    }
+
+    #[test]
+    fn block_lines_remember_where_they_came_from() {
+        let source =
+            "functions {\nreal f(real x) {\n  return x;\n}\n}\nmodel {\n  target += f(1);\n}";
+        let source_map = [
+            Some(LineOrigin::new("model.stan", 1)),
+            Some(LineOrigin::new("lib/f.stan", 1)),
+            Some(LineOrigin::new("lib/f.stan", 2)),
+            Some(LineOrigin::new("lib/f.stan", 3)),
+            Some(LineOrigin::new("model.stan", 3)),
+            Some(LineOrigin::new("model.stan", 4)),
+            Some(LineOrigin::new("model.stan", 5)),
+            Some(LineOrigin::new("model.stan", 6)),
+        ]
+        .into_iter()
+        .collect::<SourceMap>();
+
+        let model = split_blocks_with_source_map(source, &source_map).unwrap();
+
+        let functions = model
+            .functions
+            .as_ref()
+            .unwrap()
+            .get_code_with_origins()
+            .map(|(_, origin)| origin.cloned())
+            .collect::<Vec<Option<LineOrigin>>>();
+        assert_eq!(
+            functions,
+            vec![
+                Some(LineOrigin::new("lib/f.stan", 1)),
+                Some(LineOrigin::new("lib/f.stan", 2).indented(2)),
+                Some(LineOrigin::new("lib/f.stan", 3)),
+            ]
+        );
+        assert_eq!(
+            model.model.get_code_with_origins().next(),
+            Some((
+                &"target += f(1);".to_string(),
+                Some(&LineOrigin::new("model.stan", 5).indented(2))
+            ))
+        );
+    }
+
+    #[test]
+    fn errors_are_located_through_the_source_map() {
+        let source_map = [
+            Some(LineOrigin::new("model.stan", 1)),
+            Some(LineOrigin::new("lib/broken.stan", 9)),
+        ]
+        .into_iter()
+        .collect::<SourceMap>();
+
+        let error = split_blocks_with_source_map("model {\n}}", &source_map).unwrap_err();
+
+        assert_eq!(
+            error.location(),
+            Some(&SourceLocation::new("lib/broken.stan", 9, 2))
+        );
+    }
+
+    #[test]
+    fn syntax_errors_count_columns_from_the_original_line() {
+        let model = split_blocks(
+            "model {\n    mu ~ normal(0 1);\n}\ndata { int N }",
+            Path::new("m.stan"),
+        )
+        .unwrap();
+
+        let messages = model
+            .syntax_errors()
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            messages,
+            vec![
+                "m.stan:4:13: syntax error: expected ';', found end of input",
+                "m.stan:2:19: syntax error: expected ')', found '1'",
+            ]
+        );
+    }
+
+    #[test]
+    fn can_split_blocks_with_braces_on_the_same_line() {
+        let model = split_blocks(
+            "data { int N; } parameters { real mu; }\nmodel { mu ~ std_normal(); }",
+            Path::new("model.stan"),
+        )
+        .unwrap();
+
+        let mut expected = StanModel::new();
+        expected.add_data("int N;");
+        expected.add_parameter("real mu;");
+        expected.add_model("mu ~ std_normal();");
+
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn can_split_blocks_with_headers_spread_over_lines() {
+        let model = split_blocks(
+            "transformed\n  data\n{\n  int N = 1;\n}",
+            Path::new("model.stan"),
+        )
+        .unwrap();
+
+        let mut expected = StanModel::new();
+        expected.add_transformed_data("int N = 1;");
+
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn braces_in_comments_and_strings_do_not_change_depth() {
+        let source = r#"
+            // a comment } outside of a block
+            /* a { block comment */
+            model {
+                print("}"); // }
+                /* { */
+            }
+        "#;
+
+        let model = split_blocks(source, Path::new("model.stan")).unwrap();
+
+        let mut expected = StanModel::new();
+        expected.add_model("print(\"}\"); // }");
+        expected.add_model("/* { */");
+
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn braces_in_strings_with_escaped_quotes_do_not_change_depth() {
+        let source =
+            "model {\n  print(\"say \\\"}\\\" now\");\n  print(\"{\");\n}\ndata {\n  int N;\n}";
+
+        let model = split_blocks(source, Path::new("model.stan")).unwrap();
+
+        let mut expected = StanModel::new();
+        expected.add_model(r#"print("say \"}\" now");"#);
+        expected.add_model(r#"print("{");"#);
+        expected.add_data("int N;");
+        assert_eq!(model, expected);
+    }
+
+    fn split_error(source: &str) -> StanJamError {
+        split_blocks(source, Path::new("model.stan")).unwrap_err()
+    }
+
+    #[test]
+    fn unknown_blocks_are_an_error() {
+        match split_error("data {\n}\n  modle {\n}") {
+            StanJamError::UnknownBlock { name, location } => {
+                assert_eq!(name, "modle");
+                assert_eq!(location, SourceLocation::new("model.stan", 3, 3));
+            }
+            other => panic!("expected an unknown block error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_blocks_are_an_error() {
+        let error = split_error("data { int N; }\ndata { int M; }");
+        assert_eq!(error.to_string(), "model.stan:2:1: duplicate block 'data'");
+    }
+
+    #[test]
+    fn unbalanced_braces_are_an_error() {
+        let missing_close = split_error("model {\n  for (n in 1:N) {\n}");
+        assert!(matches!(
+            missing_close,
+            StanJamError::UnbalancedBraces { .. }
+        ));
+        assert_eq!(
+            missing_close.to_string(),
+            "model.stan:3:2: unbalanced braces: missing '}' at end of file"
+        );
+
+        let extra_close = split_error("model {\n}\n}");
+        assert_eq!(
+            extra_close.location(),
+            Some(&SourceLocation::new("model.stan", 3, 1))
+        );
+    }
+
+    #[test]
+    fn recovery_reports_every_block_error_and_keeps_good_blocks() {
+        let source = "data {\n  int N;\n}\nmodle {\n  x ~ normal(0, 1);\n}\n}\ndata { int M; }\nmodel {\n  y ~ normal(0, 1);\n";
+        let (model, errors) =
+            split_blocks_with_recovery(source, &SourceMap::identity(Path::new("model.stan"), 11));
+
+        let messages = errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            messages,
+            vec![
+                "model.stan:4:1: unknown block 'modle'",
+                "model.stan:7:1: unbalanced braces: unexpected '}' outside of a block",
+                "model.stan:8:1: duplicate block 'data'",
+                "model.stan:11:1: unbalanced braces: missing '}' at end of file",
+            ]
+        );
+
+        let mut expected = StanModel::new();
+        expected.add_data("int N;");
+        expected.add_model("y ~ normal(0, 1);");
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn trailing_text_outside_a_block_is_an_error() {
+        let error = split_error("model {\n} /* done */ real x;");
+        assert_eq!(
+            error.to_string(),
+            "model.stan:2:14: unknown block 'real x;'"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! A cache of source files read and scanned for include directives, shared
+//! between `StanSourceParser`s so a library included by many models is only
+//! read and scanned once.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+
+use crate::lexer::{include_guard_lines, include_lines, tokenize, LineIndex};
+use crate::stan_source_parser::resolver::{FileResolver, FileStamp};
+
+/// The contents of a source file and the lines of its include directives
+/// and `#pragma once` guards.
+#[derive(Debug, PartialEq)]
+pub struct ScannedSource {
+    pub contents: String,
+    pub directives: Vec<usize>,
+    pub guards: Vec<usize>,
+}
+
+impl ScannedSource {
+    pub fn scan(contents: String) -> ScannedSource {
+        let tokens = tokenize(&contents);
+        let lines = LineIndex::new(&contents);
+        ScannedSource {
+            directives: include_lines(&tokens, &lines),
+            guards: include_guard_lines(&contents, &tokens, &lines),
+            contents,
+        }
+    }
+}
+
+/// How often a `SourceCache` could reuse a file it held (`hits`) and how
+/// often it had to scan one (`misses`).
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    stamp: Option<FileStamp>,
+    hash: u64,
+    source: Arc<ScannedSource>,
+}
+
+/// Scanned source files keyed by canonical path. A file is reused without
+/// reading it again while the resolver reports the same `FileStamp` for it,
+/// and without scanning it again while its contents hash the same.
+#[derive(Debug, Default)]
+pub struct SourceCache {
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// The cache shared by every parser in the process that opts into it.
+pub fn shared_cache() -> Arc<SourceCache> {
+    static SHARED: OnceLock<Arc<SourceCache>> = OnceLock::new();
+    SHARED.get_or_init(Default::default).clone()
+}
+
+impl SourceCache {
+    pub fn new() -> SourceCache {
+        SourceCache::default()
+    }
+
+    /// The file at `path`, from the cache when it is still current there,
+    /// otherwise read through `resolver` and scanned.
+    pub fn get(&self, resolver: &dyn FileResolver, path: &Path) -> io::Result<Arc<ScannedSource>> {
+        let canonical = resolver.canonical(path);
+        let stamp = resolver.stamp(path);
+        if let Some(entry) = self
+            .entries()
+            .get(&canonical)
+            .filter(|entry| stamp.is_some() && entry.stamp == stamp)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(entry.source.clone());
+        }
+
+        let contents = resolver.read(path)?;
+        let hash = content_hash(&contents);
+        if let Some(entry) = self
+            .entries()
+            .get_mut(&canonical)
+            .filter(|entry| entry.hash == hash)
+        {
+            entry.stamp = stamp;
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(entry.source.clone());
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let source = Arc::new(ScannedSource::scan(contents));
+        self.entries().insert(
+            canonical,
+            CacheEntry {
+                stamp,
+                hash,
+                source: source.clone(),
+            },
+        );
+        Ok(source)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The number of files held.
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every file and reset the statistics.
+    pub fn clear(&self) {
+        self.entries().clear();
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    /// The entries, even if another thread panicked while holding them:
+    /// every update leaves them consistent.
+    fn entries(&self) -> MutexGuard<'_, HashMap<PathBuf, CacheEntry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn content_hash(contents: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stan_source_parser::resolver::{file_system, MemoryResolver};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::SystemTime;
+
+    /// Counts the reads of an in-memory file that reports a fixed stamp.
+    #[derive(Debug)]
+    struct CountingResolver {
+        files: MemoryResolver,
+        reads: AtomicUsize,
+    }
+
+    impl FileResolver for CountingResolver {
+        fn is_file(&self, path: &Path) -> bool {
+            self.files.is_file(path)
+        }
+
+        fn read(&self, path: &Path) -> io::Result<String> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.files.read(path)
+        }
+
+        fn files_in_folder(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+            self.files.files_in_folder(folder)
+        }
+
+        fn stamp(&self, _path: &Path) -> Option<FileStamp> {
+            Some(FileStamp {
+                modified: SystemTime::UNIX_EPOCH,
+                len: 0,
+            })
+        }
+    }
+
+    #[test]
+    fn current_files_are_not_read_again() {
+        let resolver = CountingResolver {
+            files: [("lib.stan", "#pragma once\n#include \"other.stan\"")]
+                .into_iter()
+                .collect(),
+            reads: AtomicUsize::new(0),
+        };
+        let cache = SourceCache::new();
+
+        let first = cache.get(&resolver, Path::new("lib.stan")).unwrap();
+        let second = cache.get(&resolver, Path::new("./lib.stan")).unwrap();
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(
+            (first.directives.clone(), first.guards.clone()),
+            (vec![1], vec![0])
+        );
+        assert_eq!(resolver.reads.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+        assert!(cache.get(&resolver, Path::new("missing.stan")).is_err());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn files_without_stamps_are_reused_while_their_contents_match() {
+        let mut resolver: MemoryResolver = [("lib.stan", "real x;")].into_iter().collect();
+        let cache = SourceCache::new();
+
+        cache.get(&resolver, Path::new("lib.stan")).unwrap();
+        cache.get(&resolver, Path::new("lib.stan")).unwrap();
+        resolver.add_file("lib.stan", "real y;");
+        let changed = cache.get(&resolver, Path::new("lib.stan")).unwrap();
+
+        assert_eq!(changed.contents, "real y;");
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn changed_files_on_disk_are_read_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lib.stan");
+        std::fs::write(&path, "real x;").unwrap();
+        let cache = SourceCache::new();
+
+        cache.get(&*file_system(), &path).unwrap();
+        cache.get(&*file_system(), &path).unwrap();
+        std::fs::write(&path, "real longer;").unwrap();
+        let changed = cache.get(&*file_system(), &path).unwrap();
+
+        assert_eq!(changed.contents, "real longer;");
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+    }
+}
@@ -1,2 +1,7 @@
+pub mod block_splitter;
+pub mod cache;
+#[cfg(test)]
 mod dir_for_tests;
+pub mod resolver;
+#[allow(clippy::module_inception)]
 pub mod stan_source_parser;
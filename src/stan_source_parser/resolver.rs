@@ -0,0 +1,318 @@
+//! Where a `StanSourceParser` reads its source files from: the real
+//! filesystem, an in-memory map of paths to sources, or a zip or tar archive
+//! of a model library.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{read_dir, read_to_string};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+/// A source of Stan files. Paths are the ones the parser builds from its
+/// filename, its search folders and the include directives it reads.
+pub trait FileResolver: fmt::Debug + Send + Sync {
+    /// Whether `path` names a file that can be read.
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// The contents of the file at `path`. A missing file is an error of
+    /// kind `io::ErrorKind::NotFound`.
+    fn read(&self, path: &Path) -> io::Result<String>;
+
+    /// The files directly inside `folder`, sorted by path.
+    fn files_in_folder(&self, folder: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// A path that names the same file as `path` and only that file, used to
+    /// recognise a file reached through different paths.
+    fn canonical(&self, path: &Path) -> PathBuf {
+        normalize(path)
+    }
+
+    /// When the file at `path` was last changed, for resolvers that can
+    /// tell without reading it.
+    fn stamp(&self, _path: &Path) -> Option<FileStamp> {
+        None
+    }
+}
+
+/// The modification time and length of a file, which change whenever its
+/// contents do.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FileStamp {
+    pub modified: SystemTime,
+    pub len: u64,
+}
+
+/// The shared resolver for the real filesystem.
+pub fn file_system() -> Arc<dyn FileResolver> {
+    static FILE_SYSTEM: OnceLock<Arc<dyn FileResolver>> = OnceLock::new();
+    FILE_SYSTEM
+        .get_or_init(|| Arc::new(FileSystemResolver))
+        .clone()
+}
+
+/// Reads files from disk, with paths relative to the working directory.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileSystemResolver;
+
+impl FileResolver for FileSystemResolver {
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<String> {
+        read_to_string(path)
+    }
+
+    fn files_in_folder(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = read_dir(folder)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect::<Vec<PathBuf>>();
+        files.sort();
+        Ok(files)
+    }
+
+    fn canonical(&self, path: &Path) -> PathBuf {
+        path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    fn stamp(&self, path: &Path) -> Option<FileStamp> {
+        let metadata = path.metadata().ok()?;
+        Some(FileStamp {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+/// Files held in memory, such as model sources received over an API or
+/// read from an archive. Paths are compared after removing `.` and
+/// resolving `..`, so `./lib/../model.stan` names `model.stan`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MemoryResolver {
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl MemoryResolver {
+    pub fn new() -> MemoryResolver {
+        MemoryResolver::default()
+    }
+
+    /// Add the file `path` with the given source, replacing any file already
+    /// at that path.
+    pub fn add_file(&mut self, path: impl AsRef<Path>, source: impl Into<String>) {
+        self.files.insert(normalize(path.as_ref()), source.into());
+    }
+
+    /// The paths of every file, sorted.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+
+    /// The files of a zip archive. Folders are skipped, and every file must
+    /// be UTF-8 text.
+    #[cfg(feature = "archive")]
+    pub fn from_zip<R: io::Read + io::Seek>(reader: R) -> io::Result<MemoryResolver> {
+        let mut archive = zip::ZipArchive::new(reader).map_err(io::Error::other)?;
+        let mut resolver = MemoryResolver::new();
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index).map_err(io::Error::other)?;
+            if !file.is_file() {
+                continue;
+            }
+            let path = file.enclosed_name().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsafe path '{}' in the archive", file.name()),
+                )
+            })?;
+            let mut source = String::new();
+            io::Read::read_to_string(&mut file, &mut source)?;
+            resolver.add_file(path, source);
+        }
+        Ok(resolver)
+    }
+
+    /// The regular files of an uncompressed tar archive, which must be
+    /// UTF-8 text.
+    #[cfg(feature = "archive")]
+    pub fn from_tar<R: io::Read>(reader: R) -> io::Result<MemoryResolver> {
+        let mut archive = tar::Archive::new(reader);
+        let mut resolver = MemoryResolver::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.into_owned();
+            let mut source = String::new();
+            io::Read::read_to_string(&mut entry, &mut source)?;
+            resolver.add_file(path, source);
+        }
+        Ok(resolver)
+    }
+
+    /// The files of the zip (`.zip`) or tar (`.tar`) archive at `path`.
+    #[cfg(feature = "archive")]
+    pub fn from_archive(path: &Path) -> io::Result<MemoryResolver> {
+        let file = std::fs::File::open(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("zip") => MemoryResolver::from_zip(io::BufReader::new(file)),
+            Some("tar") => MemoryResolver::from_tar(io::BufReader::new(file)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' is not a .zip or .tar archive", path.display()),
+            )),
+        }
+    }
+}
+
+impl<P: AsRef<Path>, S: Into<String>> FromIterator<(P, S)> for MemoryResolver {
+    fn from_iter<I: IntoIterator<Item = (P, S)>>(files: I) -> MemoryResolver {
+        let mut resolver = MemoryResolver::new();
+        files
+            .into_iter()
+            .for_each(|(path, source)| resolver.add_file(path, source));
+        resolver
+    }
+}
+
+impl FileResolver for MemoryResolver {
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<String> {
+        self.files.get(&normalize(path)).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not in memory", path.display()),
+            )
+        })
+    }
+
+    fn files_in_folder(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        let folder = normalize(folder);
+        let files = self
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(folder.as_path()))
+            .cloned()
+            .collect::<Vec<PathBuf>>();
+        match files.is_empty() && !self.files.keys().any(|path| path.starts_with(&folder)) {
+            true => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no folder '{}' in memory", folder.display()),
+            )),
+            false => Ok(files),
+        }
+    }
+}
+
+/// `path` without `.` components and with `..` components applied, without
+/// touching the filesystem.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> MemoryResolver {
+        [
+            ("model.stan", "functions {\n#include <lib/helpers.stan>\n}"),
+            (
+                "./lib/helpers.stan",
+                "real helper(real x) {\n  return x;\n}",
+            ),
+            ("lib/extra/more.stan", "real more(real x) {\n  return x;\n}"),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(
+            normalize(Path::new("./lib/../model.stan")),
+            Path::new("model.stan")
+        );
+        assert_eq!(normalize(Path::new("../a/./b")), Path::new("../a/b"));
+        assert_eq!(normalize(Path::new("/a/../../b")), Path::new("/b"));
+        assert_eq!(normalize(Path::new(".")), Path::new(""));
+    }
+
+    #[test]
+    fn can_read_files_from_memory() {
+        let resolver = library();
+
+        assert!(resolver.is_file(Path::new("lib/helpers.stan")));
+        assert!(resolver.is_file(Path::new("./lib/extra/../helpers.stan")));
+        assert!(!resolver.is_file(Path::new("lib")));
+        assert_eq!(
+            resolver.read(Path::new("lib/helpers.stan")).unwrap(),
+            "real helper(real x) {\n  return x;\n}"
+        );
+        assert_eq!(
+            resolver.read(Path::new("missing.stan")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            resolver.files_in_folder(Path::new("./lib")).unwrap(),
+            vec![PathBuf::from("lib/helpers.stan")]
+        );
+        assert_eq!(
+            resolver.files_in_folder(Path::new(".")).unwrap(),
+            vec![PathBuf::from("model.stan")]
+        );
+        assert!(resolver.files_in_folder(Path::new("nowhere")).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn can_read_files_from_archives() {
+        let mut tar = tar::Builder::new(Vec::new());
+        let source = b"real helper(real x) {\n  return x;\n}";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(source.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "lib/helpers.stan", &source[..])
+            .unwrap();
+        let tar = tar.into_inner().unwrap();
+
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.add_directory("lib/", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.start_file("lib/helpers.stan", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        io::Write::write_all(&mut zip, source).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+
+        let from_tar = MemoryResolver::from_tar(&tar[..]).unwrap();
+        let from_zip = MemoryResolver::from_zip(io::Cursor::new(zip)).unwrap();
+
+        assert_eq!(
+            from_tar.paths().collect::<Vec<&Path>>(),
+            vec![Path::new("lib/helpers.stan")]
+        );
+        assert_eq!(from_zip, from_tar);
+    }
+}
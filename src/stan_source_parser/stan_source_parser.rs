@@ -1,7 +1,17 @@
+use crate::error::{SourceLocation, StanJamError};
+use crate::source_map::{LineOrigin, SourceLine, SourceMap};
 use crate::stan_model::StanModel;
-use std::fs::read_to_string;
-use std::io::Error;
-use tempfile::tempdir;
+use crate::stan_model_block::StanModelBlock;
+use crate::stan_model_block_type::StanModelBlockType;
+use crate::stan_source_parser::block_splitter::{
+    split_blocks_with_recovery, split_blocks_with_source_map, BlockTracker,
+};
+use crate::stan_source_parser::cache::{ScannedSource, SourceCache};
+use crate::stan_source_parser::resolver::{file_system, FileResolver};
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 pub type FolderList = Vec<String>;
 pub type FileList = Vec<String>;
@@ -11,63 +21,530 @@ pub trait SourceParser {
     fn get_files_in_folder(&self, folder_index: usize) -> Option<FileList>;
     fn get_files_in_folders(&self) -> Option<FileList>;
     fn find_file_in_folders(&self) -> Option<String>;
-    fn read_file(&self) -> Result<StanModel, Error>;
+    fn read_file(&self) -> Result<StanModel, StanJamError>;
+
+    /// Where files are read from; the real filesystem unless a parser says
+    /// otherwise.
+    fn resolver(&self) -> Arc<dyn FileResolver> {
+        file_system()
+    }
+}
+
+/// Which included files are inlined only the first time they are included
+/// into a model. A file with a `#pragma once` guard line always is, whatever
+/// the setting.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum IncludeOnce {
+    /// Only files with a guard.
+    Guarded,
+    /// Files included into the `functions` block, where a second copy would
+    /// redefine every function in it.
+    #[default]
+    Functions,
+    /// Every file.
+    Always,
+}
+
+impl IncludeOnce {
+    pub const ALL: [IncludeOnce; 3] = [
+        IncludeOnce::Guarded,
+        IncludeOnce::Functions,
+        IncludeOnce::Always,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IncludeOnce::Guarded => "guarded",
+            IncludeOnce::Functions => "functions",
+            IncludeOnce::Always => "always",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<IncludeOnce> {
+        IncludeOnce::ALL
+            .into_iter()
+            .find(|include_once| include_once.name() == name)
+    }
+
+    /// Whether an unguarded file included into `block` is inlined only once.
+    fn applies_to(&self, block: Option<&StanModelBlockType>) -> bool {
+        match self {
+            IncludeOnce::Guarded => false,
+            IncludeOnce::Functions => block == Some(&StanModelBlockType::Functions),
+            IncludeOnce::Always => true,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct StanSourceParser {
     pub filename: String,
     pub folders: FolderList,
+    /// Where the file and everything it includes are read from.
+    pub resolver: Arc<dyn FileResolver>,
+    /// The text of the file, when it was given rather than read; includes
+    /// are still read through the resolver.
+    pub source: Option<String>,
+    pub include_once: IncludeOnce,
+    /// Where included files are kept once read, such as the `shared_cache`
+    /// of the process. Files are read afresh every time without one.
+    pub cache: Option<Arc<SourceCache>>,
+}
+
+impl PartialEq for StanSourceParser {
+    /// Parsers are equal when they read the same file, or the same given
+    /// source, through the same folders from the same resolver and cache.
+    fn eq(&self, other: &StanSourceParser) -> bool {
+        self.filename == other.filename
+            && self.folders == other.folders
+            && Arc::ptr_eq(&self.resolver, &other.resolver)
+            && self.source == other.source
+            && self.include_once == other.include_once
+            && match (&self.cache, &other.cache) {
+                (Some(cache), Some(other)) => Arc::ptr_eq(cache, other),
+                (cache, other) => cache.is_none() && other.is_none(),
+            }
+    }
 }
 
 impl StanSourceParser {
     pub fn new(filename: &str) -> StanSourceParser {
+        StanSourceParser::with_resolver(filename, file_system())
+    }
+
+    /// A parser that reads `filename` and its includes through `resolver`
+    /// instead of from disk, such as from a `MemoryResolver`.
+    pub fn with_resolver(filename: &str, resolver: Arc<dyn FileResolver>) -> StanSourceParser {
         StanSourceParser {
             filename: filename.to_string(),
-            folders: vec![".".to_string()],
+            folders: Vec::new(),
+            resolver,
+            source: None,
+            include_once: IncludeOnce::default(),
+            cache: None,
         }
     }
 
+    /// A parser for Stan `source` that is not read from a file. Errors are
+    /// located in `virtual_name`, and includes are looked for next to it
+    /// and in the search folders, as if the source had been read from
+    /// `virtual_name`.
+    pub fn from_str(source: &str, virtual_name: &str) -> StanSourceParser {
+        StanSourceParser {
+            source: Some(source.to_string()),
+            ..StanSourceParser::new(virtual_name)
+        }
+    }
+
+    /// Like `from_str`, with the source read from `reader`.
+    pub fn from_reader<R: Read>(
+        mut reader: R,
+        virtual_name: &str,
+    ) -> Result<StanSourceParser, StanJamError> {
+        let mut source = String::new();
+        reader
+            .read_to_string(&mut source)
+            .map_err(|error| StanJamError::from_io(Path::new(virtual_name), None, error))?;
+        Ok(StanSourceParser::from_str(&source, virtual_name))
+    }
+
     pub fn add_folder(&mut self, folder: &str) {
         self.folders.push(folder.to_string());
     }
 
-    pub fn read_file_contents(&self) -> Result<String, Error> {
-        read_to_string(&self.filename)
+    pub fn read_file_contents(&self) -> Result<String, StanJamError> {
+        self.read_source(Path::new(&self.filename), None)
     }
 
-    pub fn get_lines(&self) -> Vec<String> {
-        let file_contents = self.read_file_contents().unwrap();
-        file_contents
+    pub fn get_lines(&self) -> Result<Vec<String>, StanJamError> {
+        let file_contents = self.read_file_contents()?;
+        Ok(file_contents
             .lines()
             .map(|s| s.to_string().trim().to_string())
             .filter(|s| !s.is_empty())
-            .collect()
+            .collect())
+    }
+
+    /// Find the first folder in the search list that contains `include`.
+    pub fn find_include(&self, include: &str) -> Option<PathBuf> {
+        self.resolve_include(include, None)
+    }
+
+    /// Every path `include` is looked for at, in order, as stanc does: next
+    /// to the including file `included_from`, then in each search folder that
+    /// was added. The current folder is not searched unless it was added.
+    /// `#include "file"` and `#include <file>` are looked up the same way.
+    pub fn include_candidates(&self, include: &str, included_from: Option<&Path>) -> Vec<PathBuf> {
+        let next_to_includer =
+            included_from.map(|file| file.parent().unwrap_or(Path::new("")).join(include));
+        let in_folders = self
+            .folders
+            .iter()
+            .map(|folder| Path::new(folder).join(include));
+        let mut candidates = Vec::new();
+        for candidate in next_to_includer.into_iter().chain(in_folders) {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+        candidates
+    }
+
+    /// The first of the `include_candidates` that is a file.
+    pub fn resolve_include(&self, include: &str, included_from: Option<&Path>) -> Option<PathBuf> {
+        self.include_candidates(include, included_from)
+            .into_iter()
+            .find(|path| self.resolver.is_file(path))
+    }
+
+    /// Read the file, recursively replacing every `#include` line with the
+    /// contents of the included file.
+    pub fn resolve_includes(&self) -> Result<Vec<String>, StanJamError> {
+        Ok(self
+            .resolve_includes_with_origins()?
+            .into_iter()
+            .map(|line| line.text)
+            .collect())
+    }
+
+    /// Like `resolve_includes`, but every line records the file and line it
+    /// was read from.
+    pub fn resolve_includes_with_origins(&self) -> Result<Vec<SourceLine>, StanJamError> {
+        let mut inlining = Inlining::default();
+        self.inline_file(
+            Path::new(&self.filename),
+            &self.filename,
+            None,
+            None,
+            &mut inlining,
+        )?;
+        Ok(inlining.lines)
+    }
+
+    /// The fully flattened Stan source, ready to hand to stanc.
+    pub fn flatten(&self) -> Result<String, StanJamError> {
+        Ok(self.resolve_includes()?.join("\n"))
+    }
+
+    /// The flattened Stan source and a map from each of its lines back to
+    /// the file and line it came from.
+    pub fn flatten_with_source_map(&self) -> Result<(String, SourceMap), StanJamError> {
+        let (lines, source_map): (Vec<String>, SourceMap) = self
+            .resolve_includes_with_origins()?
+            .into_iter()
+            .map(|line| (line.text, Some(line.origin)))
+            .unzip();
+        Ok((lines.join("\n"), source_map))
     }
 
-    /*     pub fn parse_includes(&self) -> Vec<String> {
-        let mut includes = Vec::new();
-        let model = StanModel::new();
-        if let Some(functions) = &model.functions {
-            for line in functions {
-                if line.starts_with("#include") {
-                    let include = line.split_whitespace().last().unwrap();
-                    includes.push(include.to_string());
+    /// Flatten and parse the program as far as possible. Include and file
+    /// errors are fatal; block structure errors, then the syntax errors of
+    /// each block, are returned alongside the blocks that could be read.
+    pub fn read_file_with_diagnostics(
+        &self,
+    ) -> Result<(StanModel, Vec<StanJamError>), StanJamError> {
+        let (source, source_map) = self.flatten_with_source_map()?;
+        let (model, mut diagnostics) = split_blocks_with_recovery(&source, &source_map);
+        diagnostics.extend(model.syntax_errors());
+        Ok((model, diagnostics))
+    }
+
+    /// Return a copy of `model` with every `#include` line in its blocks
+    /// replaced by the (recursively resolved) contents of the included file.
+    ///
+    /// Errors in the model's own lines are located at `<block name>:line:column`.
+    pub fn inline_includes(&self, model: &StanModel) -> Result<StanModel, StanJamError> {
+        let mut inlining = Inlining::default();
+        model.try_map_blocks(|block| {
+            let block_name = format!("<{}>", block.get_block_type().name());
+            let mut inlined = StanModelBlock::new(block.get_block_type().clone());
+            for (index, (line, origin)) in block.get_code_with_origins().enumerate() {
+                let location = match origin {
+                    Some(origin) => directive_location(&origin.path, origin.line - 1, line),
+                    None => directive_location(Path::new(&block_name), index, line),
+                };
+                let included_from = origin.map(|origin| origin.path.as_path());
+                match self.include_in_line(line, included_from, &location)? {
+                    Some((include, path)) => {
+                        self.inline_file(
+                            &path,
+                            &include,
+                            Some(location),
+                            Some(block.get_block_type()),
+                            &mut inlining,
+                        )?;
+                        inlining
+                            .lines
+                            .drain(..)
+                            .filter(|line| !line.text.trim().is_empty())
+                            .for_each(|line| {
+                                let text = line.text.trim();
+                                let indent =
+                                    line.text.trim_end().chars().count() - text.chars().count();
+                                inlined.add_with_origin(text, Some(line.origin.indented(indent)))
+                            });
+                    }
+                    None => inlined.add_with_origin(line, origin.cloned()),
                 }
             }
+            Ok(inlined)
+        })
+    }
+
+    /// Parse an include directive on `line`, read from `included_from`, and
+    /// locate the file it names.
+    fn include_in_line(
+        &self,
+        line: &str,
+        included_from: Option<&Path>,
+        location: &SourceLocation,
+    ) -> Result<Option<(String, PathBuf)>, StanJamError> {
+        if !is_include_directive(line) {
+            return Ok(None);
         }
-        includes
-    } */
+        let include =
+            parse_include_directive(line).ok_or_else(|| StanJamError::MalformedInclude {
+                directive: line.trim().to_string(),
+                location: location.clone(),
+            })?;
+        let path = self
+            .resolve_include(&include, included_from)
+            .ok_or_else(|| StanJamError::IncludeNotFound {
+                searched: self.include_candidates(&include, included_from),
+                include: include.clone(),
+                location: location.clone(),
+            })?;
+        Ok(Some((include, path)))
+    }
+
+    /// Inline the file at `path`, included into `block` when that is known
+    /// rather than followed from the lines inlined so far. A file that was
+    /// already inlined is skipped when it is to be included only once.
+    fn inline_file(
+        &self,
+        path: &Path,
+        name: &str,
+        included_from: Option<SourceLocation>,
+        block: Option<&StanModelBlockType>,
+        inlining: &mut Inlining,
+    ) -> Result<(), StanJamError> {
+        let source = self.scan_source(path, included_from)?;
+        let canonical = self.resolver.canonical(path);
+        let once = !source.guards.is_empty() || self.include_once.applies_to(block);
+        if once && !inlining.inlined.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        let mut text = String::new();
+        inlining.stack.push(canonical, name);
+        for (index, line) in source.contents.lines().enumerate() {
+            if source.guards.contains(&index) {
+                continue;
+            }
+            let location = directive_location(path, index, line);
+            let directive = match source.directives.contains(&index) {
+                true => self.include_in_line(line, Some(path), &location)?,
+                false => None,
+            };
+            match directive {
+                Some((include, included)) => {
+                    let canonical = self.resolver.canonical(&included);
+                    inlining.stack.check(&canonical, &include, &location)?;
+                    inlining.blocks.push(&std::mem::take(&mut text));
+                    let into = block.or(inlining.blocks.block()).cloned();
+                    self.inline_file(&included, &include, Some(location), into.as_ref(), inlining)?
+                }
+                None => {
+                    text.push_str(line);
+                    text.push('\n');
+                    inlining.lines.push(SourceLine {
+                        text: line.to_string(),
+                        origin: LineOrigin::new(path, index + 1),
+                    })
+                }
+            }
+        }
+        inlining.blocks.push(&text);
+        inlining.stack.pop();
+        Ok(())
+    }
+
+    /// Read and scan the file at `path`, through the cache if there is one.
+    fn scan_source(
+        &self,
+        path: &Path,
+        included_from: Option<SourceLocation>,
+    ) -> Result<Arc<ScannedSource>, StanJamError> {
+        match &self.cache {
+            Some(cache) if self.source.is_none() || path != Path::new(&self.filename) => cache
+                .get(self.resolver.as_ref(), path)
+                .map_err(|error| StanJamError::from_io(path, included_from, error)),
+            _ => Ok(Arc::new(ScannedSource::scan(
+                self.read_source(path, included_from)?,
+            ))),
+        }
+    }
+
+    fn read_source(
+        &self,
+        path: &Path,
+        included_from: Option<SourceLocation>,
+    ) -> Result<String, StanJamError> {
+        if let Some(source) = self
+            .source
+            .as_ref()
+            .filter(|_| path == Path::new(&self.filename))
+        {
+            return Ok(source.clone());
+        }
+        self.resolver
+            .read(path)
+            .map_err(|error| StanJamError::from_io(path, included_from, error))
+    }
+}
+
+impl SourceParser for StanSourceParser {
+    fn get_folders(&self) -> Option<FolderList> {
+        Some(self.folders.clone())
+    }
+
+    /// The `.stan` files directly inside the folder, sorted by path.
+    fn get_files_in_folder(&self, folder_index: usize) -> Option<FileList> {
+        let folder = self.folders.get(folder_index)?;
+        let files = self
+            .resolver
+            .files_in_folder(Path::new(folder))
+            .ok()?
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "stan"))
+            .map(|path| path.to_string_lossy().to_string())
+            .collect::<FileList>();
+        Some(files)
+    }
+
+    fn get_files_in_folders(&self) -> Option<FileList> {
+        let files = (0..self.folders.len())
+            .filter_map(|index| self.get_files_in_folder(index))
+            .flatten()
+            .collect::<FileList>();
+        Some(files)
+    }
+
+    fn find_file_in_folders(&self) -> Option<String> {
+        self.find_include(&self.filename)
+            .map(|path| path.to_string_lossy().to_string())
+    }
+
+    /// Resolve all includes and split the flattened source into blocks.
+    /// Every block line keeps the file and line it was read from.
+    fn read_file(&self) -> Result<StanModel, StanJamError> {
+        let (source, source_map) = self.flatten_with_source_map()?;
+        split_blocks_with_source_map(&source, &source_map)
+    }
+
+    fn resolver(&self) -> Arc<dyn FileResolver> {
+        self.resolver.clone()
+    }
+}
+
+/// The location of the first non-whitespace character of a line.
+fn directive_location(path: &Path, index: usize, line: &str) -> SourceLocation {
+    let column = line.len() - line.trim_start().len() + 1;
+    SourceLocation::new(path, index + 1, column)
+}
+
+/// The progress of resolving the includes of one model.
+#[derive(Debug, Default)]
+struct Inlining {
+    stack: IncludeStack,
+    /// The canonical path of every file inlined only once so far.
+    inlined: HashSet<PathBuf>,
+    /// The block the lines inlined so far end in.
+    blocks: BlockTracker,
+    lines: Vec<SourceLine>,
+}
+
+/// The chain of files currently being inlined, outermost first.
+#[derive(Debug, Default)]
+struct IncludeStack {
+    entries: Vec<(PathBuf, String)>,
+}
+
+impl IncludeStack {
+    /// Push the file with the canonical path `path`.
+    fn push(&mut self, path: PathBuf, name: &str) {
+        self.entries.push((path, name.to_string()));
+    }
+
+    fn pop(&mut self) {
+        self.entries.pop();
+    }
+
+    /// Fail if the file with the canonical path `path` is already being
+    /// inlined further up the chain.
+    fn check(
+        &self,
+        path: &Path,
+        name: &str,
+        location: &SourceLocation,
+    ) -> Result<(), StanJamError> {
+        if self.entries.iter().any(|(entry, _)| entry == path) {
+            return Err(StanJamError::IncludeCycle {
+                chain: self
+                    .entries
+                    .iter()
+                    .map(|(_, name)| name.clone())
+                    .chain(std::iter::once(name.to_string()))
+                    .collect(),
+                location: location.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether the line is an `#include` directive, well-formed or not.
+pub fn is_include_directive(line: &str) -> bool {
+    line.trim()
+        .strip_prefix("#include")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+/// Extract the path from an `#include "path"` or `#include <path>` line.
+/// The bare `#include path` form accepted by stanc is supported as well.
+/// Returns `None` for lines that are not well-formed include directives.
+pub fn parse_include_directive(line: &str) -> Option<String> {
+    if !is_include_directive(line) {
+        return None;
+    }
+
+    let rest = line.trim().strip_prefix("#include")?.trim();
+    let path = match rest.chars().next()? {
+        '"' => rest.strip_prefix('"')?.strip_suffix('"')?,
+        '<' => rest.strip_prefix('<')?.strip_suffix('>')?,
+        _ => match rest.split_whitespace().collect::<Vec<&str>>()[..] {
+            [path] => path,
+            _ => return None,
+        },
+    };
+
+    if path.is_empty() || path.contains(['"', '<', '>']) {
+        None
+    } else {
+        Some(path.to_string())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::write;
+    use std::fs::{create_dir_all, write};
     use std::path::PathBuf;
 
     use tempfile::TempDir;
 
+    use crate::stan_source_parser::cache::CacheStats;
     use crate::stan_source_parser::dir_for_tests::create_temp_directory_structure;
+    use crate::stan_source_parser::resolver::MemoryResolver;
     use crate::{stan_model_block::StanModelBlock, stan_model_block_type::StanModelBlockType};
 
     use super::*;
@@ -115,7 +592,7 @@ mod tests {
             Some("test.stan".to_string())
         }
 
-        fn read_file(&self) -> Result<StanModel, Error> {
+        fn read_file(&self) -> Result<StanModel, StanJamError> {
             let mut model = StanModel::new();
             model.add_function("#include <functions.stan>");
             model.add_data("int<lower=0> N;");
@@ -175,7 +652,11 @@ mod tests {
 
         let parser2 = StanSourceParser {
             filename: "test.stan".to_string(),
-            folders: vec![".".to_string()],
+            folders: Vec::new(),
+            resolver: file_system(),
+            source: None,
+            include_once: IncludeOnce::Functions,
+            cache: None,
         };
 
         assert_eq!(parser1, parser2);
@@ -189,11 +670,7 @@ mod tests {
 
         assert_eq!(
             parser.folders,
-            vec![
-                ".".to_string(),
-                "folder1".to_string(),
-                "folder2".to_string()
-            ]
+            vec!["folder1".to_string(), "folder2".to_string()]
         );
     }
 
@@ -249,17 +726,17 @@ mod tests {
             }
             "#;
 
-       (temp_dir, test_file, test_file_contents.to_owned())
+        (temp_dir, test_file, test_file_contents.to_owned())
     }
 
     #[test]
     fn can_split_a_file_into_a_vec_of_lines() {
-        let (temp_dir, test_file, test_file_contents) = setup_test_dir();
+        let (_temp_dir, test_file, test_file_contents) = setup_test_dir();
 
         write(&test_file, test_file_contents).unwrap();
 
         let parser = StanSourceParser::new(test_file.to_str().unwrap());
-        let lines = parser.get_lines();
+        let lines = parser.get_lines().unwrap();
 
         assert_eq!(lines[0], "functions {");
         assert_eq!(lines[1], "#include \"functions/functions.stan\"");
@@ -276,7 +753,673 @@ mod tests {
 
     #[test]
     fn can_find_lines_with_an_include_directive() {
-        let parse
+        assert_eq!(
+            parse_include_directive("#include \"functions/functions.stan\""),
+            Some("functions/functions.stan".to_string())
+        );
+        assert_eq!(
+            parse_include_directive("    #include <helpers.stan>"),
+            Some("helpers.stan".to_string())
+        );
+        assert_eq!(
+            parse_include_directive("#include data.stan"),
+            Some("data.stan".to_string())
+        );
+        assert_eq!(parse_include_directive("y ~ normal(0, 1);"), None);
+        assert_eq!(parse_include_directive("#includes \"a.stan\""), None);
+        assert_eq!(parse_include_directive("#include"), None);
+        assert_eq!(parse_include_directive("#include \"a.stan"), None);
+        assert_eq!(parse_include_directive("#include <a.stan\""), None);
+        assert_eq!(parse_include_directive("#include a.stan b.stan"), None);
+
+        assert!(is_include_directive("  #include \"a.stan"));
+        assert!(is_include_directive("#include"));
+        assert!(!is_include_directive("#includes \"a.stan\""));
+    }
+
+    #[test]
+    fn reading_a_missing_file_is_an_error() {
+        let parser = StanSourceParser::new("does/not/exist.stan");
+
+        assert!(matches!(
+            parser.get_lines(),
+            Err(StanJamError::MissingFile {
+                included_from: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parser.read_file(),
+            Err(StanJamError::MissingFile { .. })
+        ));
+    }
+
+    #[test]
+    fn a_malformed_include_is_an_error() {
+        let temp_dir = create_temp_directory_structure();
+        let model_file = temp_dir.path().join("malformed.stan");
+        write(&model_file, "functions {\n  #include \"helpers.stan\n}").unwrap();
+
+        let parser = StanSourceParser::new(model_file.to_str().unwrap());
+
+        match parser.resolve_includes() {
+            Err(StanJamError::MalformedInclude {
+                directive,
+                location,
+            }) => {
+                assert_eq!(directive, "#include \"helpers.stan");
+                assert_eq!(location, SourceLocation::new(&model_file, 2, 3));
+            }
+            other => panic!("expected a malformed include error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn block_errors_are_reported_against_the_model_file() {
+        let temp_dir = create_temp_directory_structure();
+        let model_file = temp_dir.path().join("blocks.stan");
+        write(&model_file, "data {\n}\ndata {\n}").unwrap();
+
+        let parser = StanSourceParser::new(model_file.to_str().unwrap());
+
+        match parser.read_file() {
+            Err(StanJamError::DuplicateBlock { name, location }) => {
+                assert_eq!(name, "data");
+                assert_eq!(location, SourceLocation::new(&model_file, 3, 1));
+            }
+            other => panic!("expected a duplicate block error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn can_read_a_broken_file_with_every_diagnostic() {
+        let temp_dir = create_temp_directory_structure();
+        let lib = temp_dir.path().join("lib.stan");
+        write(&lib, "real f(real x) {\n  return x\n}").unwrap();
+        let model_file = temp_dir.path().join("broken.stan");
+        write(
+            &model_file,
+            "functions {\n#include \"lib.stan\"\n}\ndata {\n  int N;\n}\nmodel {\n  y ~ normal(0 1);\n}\nmodel {\n}",
+        )
+        .unwrap();
+
+        let mut parser = StanSourceParser::new(model_file.to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+        let (model, diagnostics) = parser.read_file_with_diagnostics().unwrap();
+
+        assert_eq!(model.data.get_code().collect::<Vec<_>>(), vec!["int N;"]);
+        let locations = diagnostics
+            .iter()
+            .map(|error| error.location().unwrap().clone())
+            .collect::<Vec<SourceLocation>>();
+        assert_eq!(
+            locations,
+            vec![
+                SourceLocation::new(&model_file, 10, 1),
+                SourceLocation::new(&lib, 3, 1),
+                SourceLocation::new(&model_file, 8, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_find_an_included_file_in_the_folder_list() {
+        let temp_dir = create_temp_directory_structure();
+        let root = temp_dir.path().to_str().unwrap().to_string();
+
+        let mut parser = StanSourceParser::new("test_model.stan");
+        parser.add_folder(&root);
+        parser.add_folder(temp_dir.path().join("data").to_str().unwrap());
+
+        assert_eq!(
+            parser.find_include("helpers.stan"),
+            Some(temp_dir.path().join("helpers.stan"))
+        );
+        assert_eq!(
+            parser.find_include("data.stan"),
+            Some(temp_dir.path().join("data").join("data.stan"))
+        );
+        assert_eq!(parser.find_include("missing.stan"), None);
+    }
+
+    #[test]
+    fn can_flatten_a_file_with_includes() {
+        let temp_dir = create_temp_directory_structure();
+        let test_file = temp_dir.path().join("test_model.stan");
+
+        let mut parser = StanSourceParser::new(test_file.to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let lines = parser
+            .resolve_includes()
+            .unwrap()
+            .iter()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                "functions {",
+                "real functions_file_function(real x) {",
+                "return x;",
+                "}",
+                "real helpers_file_function(real x) {",
+                "return x;",
+                "}",
+                "}",
+                "data {",
+                "int<lower=0> data_file_datum;",
+                "}",
+                "model {",
+                "y ~ normal(0, 1);",
+                "}",
+            ]
+        );
+        assert!(!parser.flatten().unwrap().contains("#include"));
+    }
+
+    #[test]
+    fn commented_out_includes_are_not_resolved() {
+        let temp_dir = create_temp_directory_structure();
+        let model_file = temp_dir.path().join("commented.stan");
+        let source = "functions {\n/*\n#include \"missing.stan\"\n*/\n}";
+        write(&model_file, source).unwrap();
+
+        let parser = StanSourceParser::new(model_file.to_str().unwrap());
+
+        assert_eq!(parser.flatten().unwrap(), source);
+    }
+
+    #[test]
+    fn can_resolve_nested_includes() {
+        let temp_dir = create_temp_directory_structure();
+        write(
+            temp_dir.path().join("helpers.stan"),
+            "#include \"functions/functions.stan\"\nreal helper(real x) {\n  return x;\n}",
+        )
+        .unwrap();
+        write(
+            temp_dir.path().join("nested.stan"),
+            "functions {\n#include <helpers.stan>\n}",
+        )
+        .unwrap();
+
+        let mut parser =
+            StanSourceParser::new(temp_dir.path().join("nested.stan").to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        assert_eq!(
+            parser.flatten().unwrap(),
+            "functions {\nreal functions_file_function(real x) {\n  return x;\n}\nreal helper(real x) {\n  return x;\n}\n}"
+        );
+    }
+
+    #[test]
+    fn resolving_a_missing_include_is_an_error() {
+        let temp_dir = create_temp_directory_structure();
+        let test_file = temp_dir.path().join("test_model.stan");
+        write(
+            &test_file,
+            "functions {\n            #include \"functions/missing.stan\"\n}",
+        )
+        .unwrap();
+
+        let mut parser = StanSourceParser::new(test_file.to_str().unwrap());
+        parser.add_folder(temp_dir.path().join("data").to_str().unwrap());
+
+        match parser.resolve_includes() {
+            Err(StanJamError::IncludeNotFound {
+                include,
+                searched,
+                location,
+            }) => {
+                assert_eq!(include, "functions/missing.stan");
+                assert_eq!(
+                    searched,
+                    vec![
+                        temp_dir.path().join("functions/missing.stan"),
+                        temp_dir.path().join("data").join("functions/missing.stan"),
+                    ]
+                );
+                assert_eq!(location, SourceLocation::new(&test_file, 2, 13));
+            }
+            other => panic!("expected an include not found error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn includes_are_looked_for_next_to_the_including_file_first() {
+        let temp_dir = create_temp_directory_structure();
+        let lib = temp_dir.path().join("lib");
+        create_dir_all(&lib).unwrap();
+        write(
+            lib.join("helpers.stan"),
+            "real lib_helper(real x) {\n  return x;\n}",
+        )
+        .unwrap();
+        write(
+            lib.join("all.stan"),
+            "#include \"helpers.stan\"\n#include <data/data.stan>",
+        )
+        .unwrap();
+        let model_file = temp_dir.path().join("uses_lib.stan");
+        write(&model_file, "functions {\n#include <lib/all.stan>\n}").unwrap();
+
+        let mut parser = StanSourceParser::new(model_file.to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let flattened = parser.flatten().unwrap();
+        assert!(flattened.contains("lib_helper"));
+        assert!(!flattened.contains("helpers_file_function"));
+        assert!(flattened.contains("data_file_datum"));
+        assert_eq!(
+            parser.include_candidates("helpers.stan", Some(&lib.join("all.stan"))),
+            [
+                lib.join("helpers.stan"),
+                temp_dir.path().join("helpers.stan")
+            ]
+        );
+    }
+
+    #[test]
+    fn the_current_folder_is_only_searched_when_added() {
+        let mut parser = StanSourceParser::new("models/model.stan");
+        let included_from = Path::new("models/model.stan");
+
+        assert_eq!(
+            parser.include_candidates("lib.stan", Some(included_from)),
+            [PathBuf::from("models/lib.stan")]
+        );
+        parser.add_folder(".");
+        assert_eq!(
+            parser.include_candidates("lib.stan", Some(included_from)),
+            [
+                PathBuf::from("models/lib.stan"),
+                Path::new(".").join("lib.stan")
+            ]
+        );
+    }
+
+    #[test]
+    fn can_read_a_model_and_its_includes_from_memory() {
+        let files = [
+            ("models/model.stan", "functions {\n#include \"helpers.stan\"\n#include <shared.stan>\n}\nmodel {\n#include \"missing.stan\"\n}"),
+            ("models/helpers.stan", "real helper(real x) {\n  return x;\n}"),
+            ("lib/shared.stan", "real shared(real x) {\n  return x;\n}"),
+        ];
+        let resolver = Arc::new(files.into_iter().collect::<MemoryResolver>());
+
+        let mut parser = StanSourceParser::with_resolver("models/model.stan", resolver.clone());
+        parser.add_folder("lib");
+
+        match parser.flatten() {
+            Err(StanJamError::IncludeNotFound { searched, .. }) => assert_eq!(
+                searched,
+                vec![
+                    PathBuf::from("models/missing.stan"),
+                    PathBuf::from("lib/missing.stan"),
+                ]
+            ),
+            other => panic!("expected an include not found error, got {:?}", other),
+        }
+
+        let mut files = (*resolver).clone();
+        files.add_file("lib/missing.stan", "target += 0;");
+        parser.resolver = Arc::new(files);
+        let model = parser.read_file().unwrap();
+        assert_eq!(
+            model.functions.unwrap().get_code().collect::<Vec<_>>(),
+            vec![
+                "real helper(real x) {",
+                "return x;",
+                "}",
+                "real shared(real x) {",
+                "return x;",
+                "}",
+            ]
+        );
+        assert_eq!(
+            parser.get_files_in_folder(0),
+            Some(vec![
+                "lib/missing.stan".to_string(),
+                "lib/shared.stan".to_string()
+            ])
+        );
+        assert_ne!(parser, StanSourceParser::new("models/model.stan"));
+    }
+
+    #[test]
+    fn can_parse_source_that_is_not_in_a_file() {
+        let temp_dir = create_temp_directory_structure();
+        let source = "functions {\n#include \"helpers.stan\"\n}\ndata {\n  int N\n}\nmodel {\n}";
+
+        let mut parser = StanSourceParser::from_str(source, "<generated>");
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        assert_eq!(parser.read_file_contents().unwrap(), source);
+        let (model, diagnostics) = parser.read_file_with_diagnostics().unwrap();
+        assert_eq!(
+            model
+                .functions
+                .unwrap()
+                .get_code()
+                .next()
+                .map(String::as_str),
+            Some("real helpers_file_function(real x) {")
+        );
+        assert_eq!(
+            diagnostics[0].location(),
+            Some(&SourceLocation::new("<generated>", 5, 8))
+        );
+
+        let from_reader =
+            StanSourceParser::from_reader(std::io::Cursor::new(source), "<generated>").unwrap();
+        assert_eq!(
+            from_reader,
+            StanSourceParser::from_str(source, "<generated>")
+        );
+        assert_ne!(from_reader, StanSourceParser::new("<generated>"));
+    }
+
+    #[test]
+    fn includes_of_given_source_are_found_next_to_its_name() {
+        let temp_dir = create_temp_directory_structure();
+        let name = temp_dir.path().join("generated.stan");
+
+        let parser = StanSourceParser::from_str(
+            "data {\n#include <data/data.stan>\n}",
+            name.to_str().unwrap(),
+        );
+
+        assert_eq!(
+            parser.flatten().unwrap(),
+            "data {\nint<lower=0> data_file_datum;\n}"
+        );
+        assert!(!name.exists());
+    }
+
+    #[test]
+    fn an_include_cycle_is_reported_with_the_full_chain() {
+        let temp_dir = create_temp_directory_structure();
+        write(temp_dir.path().join("a.stan"), "#include \"b.stan\"").unwrap();
+        write(temp_dir.path().join("b.stan"), "#include \"a.stan\"").unwrap();
+        write(temp_dir.path().join("self.stan"), "#include <self.stan>").unwrap();
+
+        let mut parser = StanSourceParser::new("a.stan");
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+        parser.filename = parser
+            .find_include("a.stan")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        match parser.resolve_includes() {
+            Err(StanJamError::IncludeCycle { chain, location }) => {
+                assert_eq!(chain, vec![parser.filename.as_str(), "b.stan", "a.stan"]);
+                assert_eq!(location.path, temp_dir.path().join("b.stan"));
+                assert_eq!((location.line, location.column), (1, 1));
+            }
+            other => panic!("expected an include cycle error, got {:?}", other),
+        }
+
+        let mut model = StanModel::new();
+        model.add_function("#include \"self.stan\"");
+        let error = parser.inline_includes(&model).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("self.stan:1:1: include cycle detected: self.stan -> self.stan"));
+    }
+
+    #[test]
+    fn including_the_same_file_twice_is_not_a_cycle() {
+        let temp_dir = create_temp_directory_structure();
+        write(
+            temp_dir.path().join("twice.stan"),
+            "functions {\n#include \"helpers.stan\"\n#include \"helpers.stan\"\n}",
+        )
+        .unwrap();
+
+        let mut parser =
+            StanSourceParser::new(temp_dir.path().join("twice.stan").to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let flattened = parser.flatten().unwrap();
+        assert_eq!(flattened.matches("helpers_file_function").count(), 1);
+
+        parser.include_once = IncludeOnce::Guarded;
+        let flattened = parser.flatten().unwrap();
+        assert_eq!(flattened.matches("helpers_file_function").count(), 2);
+    }
+
+    #[test]
+    fn only_files_included_into_functions_are_included_once_by_default() {
+        let temp_dir = create_temp_directory_structure();
+        let source = "functions {\n#include \"helpers.stan\"\n#include \"helpers.stan\"\n}\ndata {\n#include <data/data.stan>\n}\nparameters {\n#include <data/data.stan>\n}";
+        let mut parser = StanSourceParser::from_str(source, "<generated>");
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let flattened = parser.flatten().unwrap();
+        assert_eq!(flattened.matches("helpers_file_function").count(), 1);
+        assert_eq!(flattened.matches("data_file_datum").count(), 2);
+
+        parser.include_once = IncludeOnce::Always;
+        let flattened = parser.flatten().unwrap();
+        assert_eq!(flattened.matches("data_file_datum").count(), 1);
+
+        let mut model = StanModel::new();
+        model.add_function("#include \"helpers.stan\"");
+        model.add_function("#include \"helpers.stan\"");
+        model.add_data("#include <data/data.stan>");
+        model.add_parameter("#include <data/data.stan>");
+        parser.include_once = IncludeOnce::Functions;
+        let inlined = parser.inline_includes(&model).unwrap();
+        assert_eq!(inlined.functions.unwrap().get_code().count(), 3);
+        assert_eq!(inlined.parameters.get_code().count(), 1);
+    }
+
+    #[test]
+    fn guarded_files_are_included_once_and_lose_the_guard() {
+        let temp_dir = create_temp_directory_structure();
+        write(
+            temp_dir.path().join("guarded.stan"),
+            "#pragma once\nint<lower=0> guarded_datum;",
+        )
+        .unwrap();
+        write(
+            temp_dir.path().join("also_guarded.stan"),
+            "#include \"guarded.stan\"",
+        )
+        .unwrap();
+        let source = "data {\n#include \"guarded.stan\"\n#include \"also_guarded.stan\"\n}";
+        let mut parser = StanSourceParser::from_str(source, "<generated>");
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+        parser.include_once = IncludeOnce::Guarded;
+
+        assert_eq!(
+            parser.flatten().unwrap(),
+            "data {\nint<lower=0> guarded_datum;\n}"
+        );
+    }
+
+    #[test]
+    fn parsers_sharing_a_cache_read_each_include_once() {
+        let temp_dir = create_temp_directory_structure();
+        let cache = Arc::new(SourceCache::new());
+        let parser = |source: &str| {
+            let mut parser = StanSourceParser::from_str(source, "<generated>");
+            parser.add_folder(temp_dir.path().to_str().unwrap());
+            parser.cache = Some(cache.clone());
+            parser
+        };
+
+        let first = parser("functions {\n#include \"helpers.stan\"\n}\nmodel {\n}");
+        let second = parser("functions {\n#include \"helpers.stan\"\n}\ndata {\n}");
+        assert_eq!(
+            first.flatten().unwrap(),
+            "functions {\nreal helpers_file_function(real x) {\n  return x;\n}\n}\nmodel {\n}"
+        );
+        second.flatten().unwrap();
+
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+        assert_eq!(cache.len(), 1);
+        assert_ne!(
+            first,
+            StanSourceParser::from_str(&first.source.clone().unwrap(), "<generated>")
+        );
+    }
+
+    #[test]
+    fn include_once_settings_have_names() {
+        for include_once in IncludeOnce::ALL {
+            assert_eq!(
+                IncludeOnce::from_name(include_once.name()),
+                Some(include_once)
+            );
+        }
+        assert_eq!(IncludeOnce::from_name("never"), None);
+    }
+
+    #[test]
+    fn can_read_a_file_into_a_model() {
+        let temp_dir = create_temp_directory_structure();
+        let test_file = temp_dir.path().join("test_model.stan");
+
+        let mut parser = StanSourceParser::new(test_file.to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let model = parser.read_file().unwrap();
+
+        let mut expected = StanModel::new();
+        expected.add_function("real functions_file_function(real x) {");
+        expected.add_function("return x;");
+        expected.add_function("}");
+        expected.add_function("real helpers_file_function(real x) {");
+        expected.add_function("return x;");
+        expected.add_function("}");
+        expected.add_data("int<lower=0> data_file_datum;");
+        expected.add_model("y ~ normal(0, 1);");
+
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn flattened_lines_map_back_to_the_included_files() {
+        let temp_dir = create_temp_directory_structure();
+        let test_file = temp_dir.path().join("test_model.stan");
+
+        let mut parser = StanSourceParser::new(test_file.to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let (flattened, source_map) = parser.flatten_with_source_map().unwrap();
+        let functions_file = temp_dir.path().join("functions/functions.stan");
+
+        assert_eq!(source_map.len(), flattened.lines().count());
+        assert_eq!(
+            flattened.lines().nth(2),
+            Some("real functions_file_function(real x) {")
+        );
+        assert_eq!(source_map.origin_of(1), Some((test_file.clone(), 1)));
+        assert_eq!(source_map.origin_of(3), Some((functions_file.clone(), 1)));
+        assert_eq!(source_map.origin_of(5), Some((functions_file, 3)));
+        assert_eq!(
+            source_map.origin_of(6),
+            Some((temp_dir.path().join("helpers.stan"), 1))
+        );
+        assert_eq!(source_map.origin_of(9), Some((test_file, 5)));
+    }
+
+    #[test]
+    fn read_models_keep_line_provenance() {
+        let temp_dir = create_temp_directory_structure();
+        let test_file = temp_dir.path().join("test_model.stan");
+
+        let mut parser = StanSourceParser::new(test_file.to_str().unwrap());
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let model = parser.read_file().unwrap();
+        let (rendered, source_map) = model.render_with_source_map();
+
+        let datum_line = rendered
+            .lines()
+            .position(|line| line.contains("data_file_datum"))
+            .unwrap();
+        assert_eq!(
+            source_map.origin_of(datum_line + 1),
+            Some((temp_dir.path().join("data").join("data.stan"), 1))
+        );
+
+        let model_line = rendered
+            .lines()
+            .position(|line| line.contains("y ~ normal"))
+            .unwrap();
+        assert_eq!(source_map.origin_of(model_line + 1), Some((test_file, 10)));
+    }
+
+    #[test]
+    fn can_list_stan_files_in_the_search_folders() {
+        let temp_dir = create_temp_directory_structure();
+        write(temp_dir.path().join("notes.txt"), "not stan").unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+
+        let mut parser = StanSourceParser::new("helpers.stan");
+        parser.folders = vec![root.to_string()];
+        parser.add_folder(temp_dir.path().join("data").to_str().unwrap());
+
+        let top_level = [
+            temp_dir.path().join("helpers.stan"),
+            temp_dir.path().join("test_model.stan"),
+        ]
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect::<FileList>();
+        let data_path = temp_dir.path().join("data").join("data.stan");
+        let data = data_path.to_string_lossy().to_string();
+
+        assert_eq!(parser.get_folders(), Some(parser.folders.clone()));
+        assert_eq!(parser.get_files_in_folder(0), Some(top_level.clone()));
+        assert_eq!(parser.get_files_in_folder(1), Some(vec![data.clone()]));
+        assert_eq!(parser.get_files_in_folder(2), None);
+        assert_eq!(
+            parser.get_files_in_folders(),
+            Some(top_level.into_iter().chain([data]).collect())
+        );
+        assert_eq!(
+            parser.find_file_in_folders(),
+            Some(
+                temp_dir
+                    .path()
+                    .join("helpers.stan")
+                    .to_string_lossy()
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn can_inline_includes_into_a_model() {
+        let temp_dir = create_temp_directory_structure();
+        let mut parser = StanSourceParser::new("test_model.stan");
+        parser.add_folder(temp_dir.path().to_str().unwrap());
+
+        let mut model = StanModel::new();
+        model.add_function("#include \"helpers.stan\"");
+        model.add_data("#include <data/data.stan>");
+        model.add_model("y ~ normal(0, 1);");
+
+        let inlined = parser.inline_includes(&model).unwrap();
+
+        let mut expected = StanModel::new();
+        expected.add_function("real helpers_file_function(real x) {");
+        expected.add_function("return x;");
+        expected.add_function("}");
+        expected.add_data("int<lower=0> data_file_datum;");
+        expected.add_model("y ~ normal(0, 1);");
+
+        assert_eq!(inlined, expected);
+        assert!(!inlined.has_include_directive());
     }
 
     /*     #[test]
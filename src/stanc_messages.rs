@@ -0,0 +1,279 @@
+use crate::source_map::{LineOrigin, SourceMap};
+
+/// Rewrite every location in stanc error or warning output so it points at
+/// the original file and line instead of the flattened model.
+///
+/// Handles both the `'flat.stan', line 12, column 4` and the
+/// `File "flat.stan", line 12, column 4` forms, `to line N` ranges, and the
+/// numbered lines of the code excerpt stanc prints between dashed rules.
+/// Locations that are not covered by `source_map` are left untouched.
+pub fn rewrite_stanc_message(message: &str, source_map: &SourceMap) -> String {
+    let mut rewritten = Vec::new();
+    let mut in_excerpt = false;
+    let mut caret_shift = 0;
+
+    for line in message.split('\n') {
+        if line.trim_start().starts_with("---") {
+            in_excerpt = !in_excerpt;
+            caret_shift = 0;
+            rewritten.push(line.to_string());
+        } else if in_excerpt {
+            match rewrite_excerpt_line(line, source_map) {
+                Some((line, shift)) => {
+                    caret_shift = shift;
+                    rewritten.push(line);
+                }
+                None => rewritten.push(format!("{}{}", " ".repeat(caret_shift), line)),
+            }
+        } else {
+            rewritten.push(rewrite_locations(line, source_map));
+        }
+    }
+
+    rewritten.join("\n")
+}
+
+/// Rewrite an excerpt line such as `   12:    y ~ normal(mu, sigma);`,
+/// keeping the code aligned. Returns the line and how many columns the code
+/// moved right, so the caret line below it can follow.
+fn rewrite_excerpt_line(line: &str, source_map: &SourceMap) -> Option<(String, usize)> {
+    let (number, code) = line.split_once(':')?;
+    let line_number = number.trim().parse::<usize>().ok()?;
+    let origin = source_map.get(line_number)?;
+
+    let new_number = origin.line.to_string();
+    let width = number.len().max(new_number.len());
+    let shift = width - number.len();
+    Some((format!("{:>width$}:{}", new_number, code), shift))
+}
+
+/// Rewrite every `"file", line N` location on a line of stanc output.
+fn rewrite_locations(line: &str, source_map: &SourceMap) -> String {
+    let mut rewritten = String::new();
+    let mut rest = line;
+
+    while let Some(location) = find_location(rest) {
+        rewritten.push_str(&rest[..location.start]);
+        match source_map.get(location.line) {
+            Some(origin) => {
+                let quote = &rest[location.start..location.start + 1];
+                rewritten.push_str(&format!(
+                    "{}{}{}, line {}",
+                    quote,
+                    origin.path.display(),
+                    quote,
+                    origin.line
+                ));
+                rest = &rest[location.end..];
+                rest = rewrite_range_end(rest, origin, source_map, &mut rewritten);
+            }
+            None => {
+                rewritten.push_str(&rest[location.start..location.end]);
+                rest = &rest[location.end..];
+            }
+        }
+    }
+
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// A quoted file name followed by `, line N`, as byte offsets into a line.
+struct Location {
+    start: usize,
+    end: usize,
+    line: usize,
+}
+
+fn find_location(text: &str) -> Option<Location> {
+    let mut search_from = 0;
+    while let Some(offset) = text[search_from..].find(", line ") {
+        let marker = search_from + offset;
+        search_from = marker + 1;
+
+        let quote = match text[..marker].chars().last() {
+            Some(quote @ ('\'' | '"')) => quote,
+            _ => continue,
+        };
+        let Some(start) = text[..marker - 1].rfind(quote) else {
+            continue;
+        };
+
+        let digits_start = marker + ", line ".len();
+        let digits = leading_digits(&text[digits_start..]);
+        if let Ok(line) = digits.parse::<usize>() {
+            return Some(Location {
+                start,
+                end: digits_start + digits.len(),
+                line,
+            });
+        }
+    }
+    None
+}
+
+/// Rewrite the end of a `column 4 to line 14, column 2` range. The end line
+/// is mapped when it comes from the same file as the start; otherwise the
+/// end is dropped, since its line number means nothing in the start's file.
+fn rewrite_range_end<'a>(
+    rest: &'a str,
+    start: &LineOrigin,
+    source_map: &SourceMap,
+    rewritten: &mut String,
+) -> &'a str {
+    let Some(range_start) = rest.find(" to line ") else {
+        return rest;
+    };
+    let prefix = &rest[..range_start];
+    let column_only = prefix
+        .strip_prefix(", column ")
+        .is_some_and(|column| leading_digits(column) == column);
+    if !prefix.is_empty() && !column_only {
+        return rest;
+    }
+
+    let digits_start = range_start + " to line ".len();
+    let digits = leading_digits(&rest[digits_start..]);
+    let Ok(end_line) = digits.parse::<usize>() else {
+        return rest;
+    };
+    let end = match source_map.get(end_line) {
+        Some(end) if end.path == start.path => end,
+        _ => {
+            let mut range_end = digits_start + digits.len();
+            if let Some(column) = rest[range_end..].strip_prefix(", column ") {
+                let column_digits = leading_digits(column);
+                if !column_digits.is_empty() {
+                    range_end += ", column ".len() + column_digits.len();
+                }
+            }
+            rewritten.push_str(prefix);
+            return &rest[range_end..];
+        }
+    };
+
+    rewritten.push_str(&rest[..digits_start]);
+    rewritten.push_str(&end.line.to_string());
+    &rest[digits_start + digits.len()..]
+}
+
+fn leading_digits(text: &str) -> &str {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `flat.stan` as produced by flattening `model.stan`, which includes
+    /// `lib/functions.stan` at its second line:
+    ///
+    /// 1 functions {            model.stan:1
+    /// 2 real twice(real x) {   lib/functions.stan:1
+    /// 3   return 2 * x         lib/functions.stan:2
+    /// 4 }                      lib/functions.stan:3
+    /// 5 }                      model.stan:3
+    /// 6 model {                model.stan:4
+    /// 7   y ~ normal(mu, 1);   model.stan:5
+    /// 8 }                      model.stan:6
+    fn source_map() -> SourceMap {
+        [
+            ("model.stan", 1),
+            ("lib/functions.stan", 1),
+            ("lib/functions.stan", 2),
+            ("lib/functions.stan", 3),
+            ("model.stan", 3),
+            ("model.stan", 4),
+            ("model.stan", 5),
+            ("model.stan", 6),
+        ]
+        .into_iter()
+        .map(|(path, line)| Some(LineOrigin::new(path, line)))
+        .collect()
+    }
+
+    const SYNTAX_ERROR: &str =
+        "Syntax error in 'flat.stan', line 3, column 14 to column 15, parsing error:
+   -------------------------------------------------
+     1:  functions {
+     2:  real twice(real x) {
+     3:    return 2 * x
+                       ^
+     4:  }
+     5:  }
+   -------------------------------------------------
+
+Expected \";\" after return statement.";
+
+    #[test]
+    fn rewrites_a_syntax_error_and_its_excerpt() {
+        let expected =
+            "Syntax error in 'lib/functions.stan', line 2, column 14 to column 15, parsing error:
+   -------------------------------------------------
+     1:  functions {
+     1:  real twice(real x) {
+     2:    return 2 * x
+                       ^
+     3:  }
+     3:  }
+   -------------------------------------------------
+
+Expected \";\" after return statement.";
+
+        assert_eq!(rewrite_stanc_message(SYNTAX_ERROR, &source_map()), expected);
+    }
+
+    #[test]
+    fn rewrites_semantic_errors_with_line_ranges() {
+        let message = "Semantic error in 'flat.stan', line 6, column 0 to line 8, column 1:\nIdentifier 'y' not in scope.";
+
+        assert_eq!(
+            rewrite_stanc_message(message, &source_map()),
+            "Semantic error in 'model.stan', line 4, column 0 to line 6, column 1:\nIdentifier 'y' not in scope."
+        );
+    }
+
+    #[test]
+    fn rewrites_file_quoted_locations_and_warnings() {
+        let message = "Warning in 'flat.stan', line 7, column 2: unused.\nFile \"flat.stan\", line 2, column 0: note";
+
+        assert_eq!(
+            rewrite_stanc_message(message, &source_map()),
+            "Warning in 'model.stan', line 5, column 2: unused.\nFile \"lib/functions.stan\", line 1, column 0: note"
+        );
+    }
+
+    #[test]
+    fn range_ends_in_another_file_are_dropped() {
+        let message = "Semantic error in 'flat.stan', line 1, column 0 to line 3, column 2:";
+
+        assert_eq!(
+            rewrite_stanc_message(message, &source_map()),
+            "Semantic error in 'model.stan', line 1, column 0:"
+        );
+    }
+
+    #[test]
+    fn unmapped_locations_and_other_text_are_unchanged() {
+        let message =
+            "Error in 'flat.stan', line 99, column 0: out of range\nmodel, line 3 is fine";
+
+        assert_eq!(rewrite_stanc_message(message, &source_map()), message);
+    }
+
+    #[test]
+    fn excerpt_numbers_that_grow_shift_the_caret() {
+        let map = [Some(LineOrigin::new("long.stan", 120))]
+            .into_iter()
+            .collect::<SourceMap>();
+        let message = "  -----\n 1:  real x\n       ^\n  -----";
+
+        assert_eq!(
+            rewrite_stanc_message(message, &map),
+            "  -----\n120:  real x\n        ^\n  -----"
+        );
+    }
+}
@@ -0,0 +1,1242 @@
+//! Stan's parameter transforms, between the constrained values written in an
+//! inits file and the unconstrained reals a sampler works with.
+//!
+//! The unconstrained reals are laid out as Stan lays them out: parameters in
+//! declaration order, array elements outermost first, and the reals of each
+//! element in the order of Stan's transform, which is column-major for
+//! matrices.
+
+use serde_json::{json, Value};
+
+use crate::ast::{BaseType, VarType};
+use crate::eval::{json_scalar, Data};
+use crate::interface::{
+    array_elements, element_type, json_shape, nested_array, unconstrained_size, Bounds,
+    ElementType, Variable,
+};
+
+/// How far a value may be from satisfying its constraint, such as a simplex
+/// summing to 1, as in Stan's `CONSTRAINT_TOLERANCE`.
+const TOLERANCE: f64 = 1e-8;
+
+/// Constrained parameter values, as in an inits file, with the log absolute
+/// determinant of the Jacobian of the constraining transform.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Constrained {
+    pub values: Data,
+    pub log_jacobian: f64,
+}
+
+/// Unconstrained parameter values, with the log absolute determinant of the
+/// Jacobian of the constraining transform at those values; this is the term
+/// Stan adds to the log density.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Unconstrained {
+    pub values: Vec<f64>,
+    pub log_jacobian: f64,
+}
+
+/// The unconstrained values of the parameters `variables`, given their
+/// constrained values in `inits` and sizes and bounds read from `data`.
+/// Fails if a value is missing, has the wrong shape or does not satisfy its
+/// constraint, so this also checks an inits file.
+pub fn unconstrain(
+    variables: &[Variable],
+    inits: &Data,
+    data: &Data,
+) -> Result<Unconstrained, String> {
+    let mut values = Vec::new();
+    for variable in variables {
+        inits
+            .get(&variable.name)
+            .ok_or_else(|| format!("'{}' is missing from the inits", variable.name))
+            .and_then(|value| free(value, &variable.ty, &variable.name, data, &mut values))
+            .map_err(|error| {
+                format!(
+                    "{}: cannot unconstrain '{}': {}",
+                    variable.location, variable.name, error
+                )
+            })?;
+    }
+    let log_jacobian = constrain(variables, &values, data)?.log_jacobian;
+    Ok(Unconstrained {
+        values,
+        log_jacobian,
+    })
+}
+
+/// The constrained values of the parameters `variables` given their
+/// unconstrained values, with sizes and bounds read from `data`.
+pub fn constrain(
+    variables: &[Variable],
+    unconstrained: &[f64],
+    data: &Data,
+) -> Result<Constrained, String> {
+    let expected = variables
+        .iter()
+        .map(|variable| {
+            unconstrained_size(&variable.ty, data).map_err(|error| {
+                format!(
+                    "{}: cannot size '{}': {}",
+                    variable.location, variable.name, error
+                )
+            })
+        })
+        .sum::<Result<usize, String>>()?;
+    if expected != unconstrained.len() {
+        return Err(format!(
+            "expected {} unconstrained values, found {}",
+            expected,
+            unconstrained.len()
+        ));
+    }
+
+    let mut reader = Reader {
+        unconstrained,
+        position: 0,
+        log_jacobian: 0.0,
+    };
+    let mut values = Data::new();
+    for variable in variables {
+        let value = reader.constrain(&variable.ty, data).map_err(|error| {
+            format!(
+                "{}: cannot constrain '{}': {}",
+                variable.location, variable.name, error
+            )
+        })?;
+        values.insert(variable.name.clone(), value);
+    }
+    Ok(Constrained {
+        values,
+        log_jacobian: reader.log_jacobian,
+    })
+}
+
+/// A JSON real, with non-finite values written as the strings CmdStan
+/// accepts.
+pub fn real_json(value: f64) -> Value {
+    match value {
+        value if value.is_nan() => json!("NaN"),
+        f64::INFINITY => json!("Inf"),
+        f64::NEG_INFINITY => json!("-Inf"),
+        value => json!(value),
+    }
+}
+
+impl Bounds {
+    fn is_affine(&self) -> bool {
+        self.offset.is_some() || self.multiplier.is_some()
+    }
+
+    fn free(&self, x: f64, path: &str) -> Result<f64, String> {
+        if self.is_affine() {
+            return Ok((x - self.offset.unwrap_or(0.0)) / self.multiplier.unwrap_or(1.0));
+        }
+        if let Some(lower) = self.lower.filter(|lower| x < *lower) {
+            return Err(format!(
+                "'{}' is {}, below the lower bound {}",
+                path, x, lower
+            ));
+        }
+        if let Some(upper) = self.upper.filter(|upper| x > *upper) {
+            return Err(format!(
+                "'{}' is {}, above the upper bound {}",
+                path, x, upper
+            ));
+        }
+        Ok(match (self.lower, self.upper) {
+            (Some(lower), Some(upper)) => logit((x - lower) / (upper - lower)),
+            (Some(lower), None) => (x - lower).ln(),
+            (None, Some(upper)) => (upper - x).ln(),
+            (None, None) => x,
+        })
+    }
+
+    fn constrain(&self, y: f64, log_jacobian: &mut f64) -> f64 {
+        if self.is_affine() {
+            let multiplier = self.multiplier.unwrap_or(1.0);
+            *log_jacobian += multiplier.ln();
+            return self.offset.unwrap_or(0.0) + multiplier * y;
+        }
+        match (self.lower, self.upper) {
+            (Some(lower), Some(upper)) => {
+                *log_jacobian += (upper - lower).ln() - y.abs() - 2.0 * log1p_exp(-y.abs());
+                lower + (upper - lower) * inv_logit(y)
+            }
+            (Some(lower), None) => {
+                *log_jacobian += y;
+                lower + y.exp()
+            }
+            (None, Some(upper)) => {
+                *log_jacobian += y;
+                upper - y.exp()
+            }
+            (None, None) => y,
+        }
+    }
+}
+
+/// Append the unconstrained reals of `value`, of type `ty`, to `out`.
+fn free(
+    value: &Value,
+    ty: &VarType,
+    path: &str,
+    data: &Data,
+    out: &mut Vec<f64>,
+) -> Result<(), String> {
+    let shape = json_shape(ty, data)?;
+    let (array_shape, base_shape) = shape.split_at(ty.array_dims.len());
+    let bounds = Bounds::new(ty, data)?;
+    for element in array_elements(value, array_shape, path.to_string()) {
+        let (element, path) = element?;
+        if let BaseType::Tuple(items) = &ty.base {
+            for (index, item) in items.iter().enumerate() {
+                let item_path = format!("{}.{}", path, index + 1);
+                let field = element
+                    .get((index + 1).to_string())
+                    .ok_or_else(|| format!("'{}' is missing from the inits", item_path))?;
+                free(field, item, &item_path, data, out)?;
+            }
+            continue;
+        }
+        let x = read_element(element, &ty.base, base_shape, &path)?;
+        out.extend(free_element(&ty.base, base_shape, &x, bounds, &path)?);
+    }
+    Ok(())
+}
+
+/// The reals of one value of a non-array type, column-major for matrices,
+/// with complex numbers as their real and imaginary parts.
+fn read_element(
+    value: &Value,
+    base: &BaseType,
+    shape: &[usize],
+    path: &str,
+) -> Result<Vec<f64>, String> {
+    let complex = element_type(base) == ElementType::Complex;
+    let scalar = |value: &Value, path: String| -> Result<Vec<f64>, String> {
+        let real = |value: &Value| json_scalar(value).map(|scalar| scalar.as_f64());
+        if !complex {
+            return real(value)
+                .map(|value| vec![value])
+                .ok_or_else(|| format!("'{}' should be a real, found {}", path, value));
+        }
+        match value {
+            Value::Array(parts) if parts.len() == 2 => parts
+                .iter()
+                .map(real)
+                .collect::<Option<Vec<f64>>>()
+                .ok_or_else(|| format!("'{}' should be a complex number, found {}", path, value)),
+            _ => Err(format!(
+                "'{}' should be a complex number as [re, im], found {}",
+                path, value
+            )),
+        }
+    };
+
+    if shape.len() > 2 {
+        return Err(wrong_sizes(base));
+    }
+    let elements = array_elements(value, shape, path.to_string())
+        .into_iter()
+        .collect::<Result<Vec<(&Value, String)>, String>>()?;
+    let rows = shape.first().copied().unwrap_or(1);
+    let columns = elements.len() / rows.max(1);
+    let mut reals = Vec::new();
+    for column in 0..columns {
+        for row in 0..rows {
+            let (element, path) = &elements[row * columns + column];
+            reals.extend(scalar(element, path.clone())?);
+        }
+    }
+    Ok(reals)
+}
+
+/// The JSON value of one non-array value from its reals, the inverse of
+/// `read_element`.
+fn write_element(reals: &[f64], base: &BaseType, shape: &[usize]) -> Result<Value, String> {
+    let width = match element_type(base) {
+        ElementType::Complex => 2,
+        _ => 1,
+    };
+    let scalar = |index: usize| match width {
+        2 => json!([real_json(reals[2 * index]), real_json(reals[2 * index + 1])]),
+        _ => real_json(reals[index]),
+    };
+    Ok(match *shape {
+        [] => scalar(0),
+        [length] => Value::Array((0..length).map(scalar).collect()),
+        [rows, columns] => Value::Array(
+            (0..rows)
+                .map(|row| {
+                    Value::Array(
+                        (0..columns)
+                            .map(|column| scalar(row + column * rows))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        ),
+        _ => return Err(wrong_sizes(base)),
+    })
+}
+
+/// The path of the `index`-th real of a value of shape `shape`, for error
+/// messages.
+fn real_path(path: &str, shape: &[usize], index: usize) -> String {
+    match *shape {
+        [] => path.to_string(),
+        [_] => format!("{}[{}]", path, index + 1),
+        [rows, _] => format!("{}[{}][{}]", path, index % rows + 1, index / rows + 1),
+        _ => format!("{}[{}]", path, index + 1),
+    }
+}
+
+/// The unconstrained reals of the reals `x` of one value of type `base`.
+fn free_element(
+    base: &BaseType,
+    shape: &[usize],
+    x: &[f64],
+    bounds: Bounds,
+    path: &str,
+) -> Result<Vec<f64>, String> {
+    match (base, shape) {
+        (BaseType::Int, _) => Err("ints cannot be parameters".to_string()),
+        (BaseType::Real | BaseType::Vector | BaseType::RowVector | BaseType::Matrix, _) => x
+            .iter()
+            .enumerate()
+            .map(|(index, &x)| bounds.free(x, &real_path(path, shape, index)))
+            .collect(),
+        (BaseType::Simplex, _) => simplex_free(x).map_err(|error| not_a(path, "simplex", error)),
+        (BaseType::UnitVector, _) => {
+            let squares = x.iter().map(|x| x * x).sum::<f64>();
+            if (squares - 1.0).abs() > TOLERANCE {
+                return Err(not_a(
+                    path,
+                    "unit vector",
+                    format!("its squared length is {}", squares),
+                ));
+            }
+            Ok(x.to_vec())
+        }
+        (BaseType::SumToZeroVector, _) => {
+            sum_to_zero_free(x).map_err(|error| not_a(path, "sum-to-zero vector", error))
+        }
+        (BaseType::SumToZeroMatrix, &[rows, columns]) => sum_to_zero_matrix_free(x, rows, columns)
+            .map_err(|error| not_a(path, "sum-to-zero matrix", error)),
+        (BaseType::Ordered, _) => {
+            ordered_free(x, false).map_err(|error| not_a(path, "ordered vector", error))
+        }
+        (BaseType::PositiveOrdered, _) => {
+            ordered_free(x, true).map_err(|error| not_a(path, "positive ordered vector", error))
+        }
+        (BaseType::CholeskyFactorCorr, &[size, _]) => cholesky_corr_free(x, size)
+            .map_err(|error| not_a(path, "Cholesky factor of a correlation matrix", error)),
+        (BaseType::CholeskyFactorCov, &[rows, columns]) => cholesky_cov_free(x, rows, columns)
+            .map_err(|error| not_a(path, "Cholesky factor of a covariance matrix", error)),
+        (BaseType::CorrMatrix, &[size, _]) => {
+            corr_matrix_free(x, size).map_err(|error| not_a(path, "correlation matrix", error))
+        }
+        (BaseType::CovMatrix, &[size, _]) => {
+            cov_matrix_free(x, size).map_err(|error| not_a(path, "covariance matrix", error))
+        }
+        (BaseType::ColumnStochasticMatrix, &[rows, columns]) => {
+            let mut y = Vec::new();
+            for column in x.chunks(rows).take(columns) {
+                y.extend(simplex_free(column).map_err(|error| {
+                    not_a(
+                        path,
+                        "column stochastic matrix",
+                        format!("a column {}", error),
+                    )
+                })?);
+            }
+            Ok(y)
+        }
+        (BaseType::RowStochasticMatrix, &[rows, columns]) => {
+            let mut y = vec![0.0; rows * columns.saturating_sub(1)];
+            for row in 0..rows {
+                let reals = (0..columns)
+                    .map(|column| x[row + column * rows])
+                    .collect::<Vec<f64>>();
+                let free = simplex_free(&reals).map_err(|error| {
+                    not_a(path, "row stochastic matrix", format!("a row {}", error))
+                })?;
+                for (column, value) in free.into_iter().enumerate() {
+                    y[row + column * rows] = value;
+                }
+            }
+            Ok(y)
+        }
+        (
+            BaseType::Complex
+            | BaseType::ComplexVector
+            | BaseType::ComplexRowVector
+            | BaseType::ComplexMatrix,
+            _,
+        ) => Ok(x.to_vec()),
+        (BaseType::Tuple(_), _) => unreachable!("tuples are transformed item by item"),
+        (base, _) => Err(wrong_sizes(base)),
+    }
+}
+
+fn wrong_sizes(base: &BaseType) -> String {
+    format!("'{}' has the wrong number of sizes", base.name())
+}
+
+fn not_a(path: &str, what: &str, error: String) -> String {
+    format!("'{}' is not a valid {}: {}", path, what, error)
+}
+
+/// Reads unconstrained reals in order, adding up the log Jacobian of the
+/// transforms applied to them.
+struct Reader<'a> {
+    unconstrained: &'a [f64],
+    position: usize,
+    log_jacobian: f64,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> &[f64] {
+        let reals = &self.unconstrained[self.position..self.position + count];
+        self.position += count;
+        reals
+    }
+
+    /// The constrained value of type `ty` made from the next unconstrained
+    /// reals.
+    fn constrain(&mut self, ty: &VarType, data: &Data) -> Result<Value, String> {
+        let shape = json_shape(ty, data)?;
+        let (array_shape, base_shape) = shape.split_at(ty.array_dims.len());
+        let bounds = Bounds::new(ty, data)?;
+        let element_size = match &ty.base {
+            BaseType::Tuple(_) => 0,
+            _ => unconstrained_size(
+                &VarType {
+                    array_dims: Vec::new(),
+                    ..ty.clone()
+                },
+                data,
+            )?,
+        };
+        let count = array_shape.iter().product::<usize>();
+        let mut elements = Vec::with_capacity(count);
+        for _ in 0..count {
+            let element = match &ty.base {
+                BaseType::Tuple(items) => Value::Object(
+                    items
+                        .iter()
+                        .enumerate()
+                        .map(|(index, item)| {
+                            Ok(((index + 1).to_string(), self.constrain(item, data)?))
+                        })
+                        .collect::<Result<_, String>>()?,
+                ),
+                base => {
+                    let mut log_jacobian = 0.0;
+                    let y = self.take(element_size);
+                    let x = constrain_element(base, base_shape, y, bounds, &mut log_jacobian)?;
+                    self.log_jacobian += log_jacobian;
+                    write_element(&x, base, base_shape)?
+                }
+            };
+            elements.push(element);
+        }
+        Ok(nested_array(elements, array_shape))
+    }
+}
+
+/// The reals of one value of type `base`, column-major for matrices, made
+/// from its unconstrained reals `y`.
+fn constrain_element(
+    base: &BaseType,
+    shape: &[usize],
+    y: &[f64],
+    bounds: Bounds,
+    log_jacobian: &mut f64,
+) -> Result<Vec<f64>, String> {
+    Ok(match (base, shape) {
+        (BaseType::Int, _) => return Err("ints cannot be parameters".to_string()),
+        (BaseType::Real | BaseType::Vector | BaseType::RowVector | BaseType::Matrix, _) => y
+            .iter()
+            .map(|&y| bounds.constrain(y, log_jacobian))
+            .collect(),
+        (BaseType::Simplex, &[size]) => simplex_constrain(y, size, log_jacobian)?,
+        (BaseType::UnitVector, _) => {
+            let squares = y.iter().map(|y| y * y).sum::<f64>();
+            if squares == 0.0 {
+                return Err("a vector of zeros has no direction".to_string());
+            }
+            *log_jacobian -= 0.5 * squares;
+            y.iter().map(|y| y / squares.sqrt()).collect()
+        }
+        (BaseType::SumToZeroVector, &[size]) => sum_to_zero_constrain(y, size)?,
+        (BaseType::SumToZeroMatrix, &[rows, columns]) => {
+            sum_to_zero_matrix_constrain(y, rows, columns)?
+        }
+        (BaseType::Ordered, _) => ordered_constrain(y, false, log_jacobian),
+        (BaseType::PositiveOrdered, _) => ordered_constrain(y, true, log_jacobian),
+        (BaseType::CholeskyFactorCorr, &[size, _]) => {
+            cholesky_corr_constrain(y, size, log_jacobian)
+        }
+        (BaseType::CholeskyFactorCov, &[rows, columns]) => {
+            cholesky_cov_constrain(y, rows, columns, log_jacobian)?
+        }
+        (BaseType::CorrMatrix, &[size, _]) => corr_matrix_constrain(y, size, log_jacobian),
+        (BaseType::CovMatrix, &[size, _]) => cov_matrix_constrain(y, size, log_jacobian),
+        (BaseType::ColumnStochasticMatrix, &[rows, columns]) => {
+            let mut x = Vec::with_capacity(rows * columns);
+            for column in 0..columns {
+                let free =
+                    &y[column * rows.saturating_sub(1)..(column + 1) * rows.saturating_sub(1)];
+                x.extend(simplex_constrain(free, rows, log_jacobian)?);
+            }
+            x
+        }
+        (BaseType::RowStochasticMatrix, &[rows, columns]) => {
+            let mut x = vec![0.0; rows * columns];
+            for row in 0..rows {
+                let free = (0..columns.saturating_sub(1))
+                    .map(|column| y[row + column * rows])
+                    .collect::<Vec<f64>>();
+                let reals = simplex_constrain(&free, columns, log_jacobian)?;
+                for (column, value) in reals.into_iter().enumerate() {
+                    x[row + column * rows] = value;
+                }
+            }
+            x
+        }
+        (
+            BaseType::Complex
+            | BaseType::ComplexVector
+            | BaseType::ComplexRowVector
+            | BaseType::ComplexMatrix,
+            _,
+        ) => y.to_vec(),
+        (BaseType::Tuple(_), _) => unreachable!("tuples are transformed item by item"),
+        (base, _) => return Err(wrong_sizes(base)),
+    })
+}
+
+fn inv_logit(y: f64) -> f64 {
+    1.0 / (1.0 + (-y).exp())
+}
+
+fn logit(u: f64) -> f64 {
+    (u / (1.0 - u)).ln()
+}
+
+/// `log(1 + exp(a))` without overflow.
+fn log1p_exp(a: f64) -> f64 {
+    if a > 0.0 {
+        a + (-a).exp().ln_1p()
+    } else {
+        a.exp().ln_1p()
+    }
+}
+
+/// Stan's stick-breaking transform from `size - 1` reals to a simplex.
+fn simplex_constrain(y: &[f64], size: usize, log_jacobian: &mut f64) -> Result<Vec<f64>, String> {
+    if size == 0 {
+        return Err("a simplex cannot be empty".to_string());
+    }
+    let mut x = Vec::with_capacity(size);
+    let mut stick = 1.0;
+    for (k, &y_k) in y.iter().enumerate() {
+        let adjusted = y_k - ((y.len() - k) as f64).ln();
+        let x_k = stick * inv_logit(adjusted);
+        *log_jacobian += f64::ln(stick) - log1p_exp(-adjusted) - log1p_exp(adjusted);
+        stick -= x_k;
+        x.push(x_k);
+    }
+    x.push(stick);
+    Ok(x)
+}
+
+fn simplex_free(x: &[f64]) -> Result<Vec<f64>, String> {
+    let Some((&last, rest)) = x.split_last() else {
+        return Err("a simplex cannot be empty".to_string());
+    };
+    if let Some(negative) = x.iter().find(|x| **x < 0.0) {
+        return Err(format!("it has the negative element {}", negative));
+    }
+    let sum = x.iter().sum::<f64>();
+    if (sum - 1.0).abs() > TOLERANCE {
+        return Err(format!("its elements sum to {}", sum));
+    }
+    let mut y = vec![0.0; rest.len()];
+    let mut stick = last;
+    for k in (0..rest.len()).rev() {
+        stick += rest[k];
+        y[k] = logit(rest[k] / stick) + ((rest.len() - k) as f64).ln();
+    }
+    Ok(y)
+}
+
+/// Stan's isometric transform from `size - 1` reals to a vector that sums to
+/// zero. Its Jacobian is constant, so it adds nothing to the log Jacobian.
+fn sum_to_zero_constrain(y: &[f64], size: usize) -> Result<Vec<f64>, String> {
+    if size == 0 {
+        return Err("a sum-to-zero vector cannot be empty".to_string());
+    }
+    let mut z = vec![0.0; size];
+    let mut sum = 0.0;
+    for i in (1..size).rev() {
+        let n = i as f64;
+        let w = y[i - 1] / (n * (n + 1.0)).sqrt();
+        sum += w;
+        z[i - 1] += sum;
+        z[i] -= w * n;
+    }
+    Ok(z)
+}
+
+fn sum_to_zero_free(z: &[f64]) -> Result<Vec<f64>, String> {
+    let sum = z.iter().sum::<f64>();
+    if (sum).abs() > TOLERANCE {
+        return Err(format!("its elements sum to {}", sum));
+    }
+    let Some(n) = z.len().checked_sub(1) else {
+        return Err("a sum-to-zero vector cannot be empty".to_string());
+    };
+    let mut y = vec![0.0; n];
+    if n == 0 {
+        return Ok(y);
+    }
+    y[n - 1] = -z[n] * ((n * (n + 1)) as f64).sqrt() / n as f64;
+    let mut sum = 0.0;
+    for i in (1..n).rev() {
+        let size = i as f64;
+        sum += y[i] / ((size + 1.0) * (size + 2.0)).sqrt();
+        y[i - 1] = (sum - z[i]) * (size * (size + 1.0)).sqrt() / size;
+    }
+    Ok(y)
+}
+
+/// The sum-to-zero transform applied to every column and then every row.
+/// This is `H_rows * Y * H_columns'`, where the columns of `H_n` are the
+/// basis of the vector transform, `(1, ..., 1, -k, 0, ..., 0) / sqrt(k (k +
+/// 1))`; the order in which the two dimensions are transformed does not
+/// matter.
+fn sum_to_zero_matrix_constrain(
+    y: &[f64],
+    rows: usize,
+    columns: usize,
+) -> Result<Vec<f64>, String> {
+    let (free_rows, free_columns) = (rows.saturating_sub(1), columns.saturating_sub(1));
+    let mut by_columns = Vec::with_capacity(rows * free_columns);
+    for column in 0..free_columns {
+        by_columns.extend(sum_to_zero_constrain(
+            &y[column * free_rows..(column + 1) * free_rows],
+            rows,
+        )?);
+    }
+    let mut z = vec![0.0; rows * columns];
+    for row in 0..rows {
+        let free = (0..free_columns)
+            .map(|column| by_columns[row + column * rows])
+            .collect::<Vec<f64>>();
+        for (column, value) in sum_to_zero_constrain(&free, columns)?
+            .into_iter()
+            .enumerate()
+        {
+            z[row + column * rows] = value;
+        }
+    }
+    Ok(z)
+}
+
+fn sum_to_zero_matrix_free(z: &[f64], rows: usize, columns: usize) -> Result<Vec<f64>, String> {
+    let row_sum = |row: usize| {
+        (0..columns)
+            .map(|column| z[row + column * rows])
+            .sum::<f64>()
+    };
+    let column_sum = |column: usize| z[column * rows..(column + 1) * rows].iter().sum::<f64>();
+    if let Some(row) = (0..rows).find(|row| row_sum(*row).abs() > TOLERANCE) {
+        return Err(format!("row {} sums to {}", row + 1, row_sum(row)));
+    }
+    if let Some(column) = (0..columns).find(|column| column_sum(*column).abs() > TOLERANCE) {
+        return Err(format!(
+            "column {} sums to {}",
+            column + 1,
+            column_sum(column)
+        ));
+    }
+    if rows == 0 || columns == 0 {
+        return Err("a sum-to-zero matrix cannot be empty".to_string());
+    }
+
+    let (free_rows, free_columns) = (rows - 1, columns - 1);
+    let mut by_rows = vec![0.0; rows * free_columns];
+    for row in 0..rows {
+        let reals = (0..columns)
+            .map(|column| z[row + column * rows])
+            .collect::<Vec<f64>>();
+        for (column, value) in sum_to_zero_free(&reals)?.into_iter().enumerate() {
+            by_rows[row + column * rows] = value;
+        }
+    }
+    let mut y = Vec::with_capacity(free_rows * free_columns);
+    for column in by_rows.chunks(rows) {
+        y.extend(sum_to_zero_free(column)?);
+    }
+    Ok(y)
+}
+
+/// A vector whose later elements are the earlier ones plus the exponent of
+/// the unconstrained reals. A positive ordered vector also takes the
+/// exponent of its first element.
+fn ordered_constrain(y: &[f64], positive: bool, log_jacobian: &mut f64) -> Vec<f64> {
+    let mut x = Vec::with_capacity(y.len());
+    for &y in y {
+        x.push(match x.last() {
+            None if !positive => y,
+            None => {
+                *log_jacobian += y;
+                y.exp()
+            }
+            Some(previous) => {
+                *log_jacobian += y;
+                previous + y.exp()
+            }
+        });
+    }
+    x
+}
+
+fn ordered_free(x: &[f64], positive: bool) -> Result<Vec<f64>, String> {
+    if let Some(first) = x.first().filter(|first| positive && **first < 0.0) {
+        return Err(format!("its first element {} is negative", first));
+    }
+    let mut y = Vec::with_capacity(x.len());
+    for (k, &x_k) in x.iter().enumerate() {
+        y.push(match k.checked_sub(1) {
+            None if !positive => x_k,
+            None => x_k.ln(),
+            Some(previous) if x[previous] < x_k => (x_k - x[previous]).ln(),
+            Some(previous) => {
+                return Err(format!(
+                    "element {} is {}, not above the previous element {}",
+                    k + 1,
+                    x_k,
+                    x[previous]
+                ))
+            }
+        });
+    }
+    Ok(y)
+}
+
+/// Stan's transform from `size * (size - 1) / 2` reals, read row by row
+/// through the lower triangle, to the Cholesky factor of a correlation
+/// matrix.
+fn cholesky_corr_constrain(y: &[f64], size: usize, log_jacobian: &mut f64) -> Vec<f64> {
+    let z = corr_constrain(y, log_jacobian);
+    let mut x = vec![0.0; size * size];
+    if size == 0 {
+        return x;
+    }
+    x[0] = 1.0;
+    let mut position = 0;
+    for i in 1..size {
+        x[i] = z[position];
+        position += 1;
+        let mut squares = x[i] * x[i];
+        for j in 1..i {
+            *log_jacobian += 0.5 * (1.0 - squares).ln();
+            x[i + j * size] = z[position] * (1.0 - squares).sqrt();
+            position += 1;
+            squares += x[i + j * size] * x[i + j * size];
+        }
+        x[i + i * size] = (1.0 - squares).sqrt();
+    }
+    x
+}
+
+fn cholesky_corr_free(x: &[f64], size: usize) -> Result<Vec<f64>, String> {
+    check_lower_triangular(x, size, size)?;
+    for i in 0..size {
+        let squares = (0..=i)
+            .map(|j| x[i + j * size] * x[i + j * size])
+            .sum::<f64>();
+        if (squares - 1.0).abs() > TOLERANCE {
+            return Err(format!("row {} has squared length {}", i + 1, squares));
+        }
+    }
+    let mut y = Vec::with_capacity(size * size.saturating_sub(1) / 2);
+    for i in 1..size {
+        y.push(x[i].atanh());
+        let mut squares = x[i] * x[i];
+        for j in 1..i {
+            y.push((x[i + j * size] / (1.0 - squares).sqrt()).atanh());
+            squares += x[i + j * size] * x[i + j * size];
+        }
+    }
+    Ok(y)
+}
+
+/// Stan's transform from `columns + columns * (columns - 1) / 2 + (rows -
+/// columns) * columns` reals, read row by row with the logarithm of each
+/// diagonal element, to a Cholesky factor of a covariance matrix.
+fn cholesky_cov_constrain(
+    y: &[f64],
+    rows: usize,
+    columns: usize,
+    log_jacobian: &mut f64,
+) -> Result<Vec<f64>, String> {
+    if rows < columns {
+        return Err(format!("it has {} rows but {} columns", rows, columns));
+    }
+    let mut x = vec![0.0; rows * columns];
+    let mut reals = y.iter();
+    for i in 0..rows {
+        for j in 0..columns.min(i + 1) {
+            let y = *reals.next().expect("the size was checked");
+            x[i + j * rows] = if i == j {
+                *log_jacobian += y;
+                y.exp()
+            } else {
+                y
+            };
+        }
+    }
+    Ok(x)
+}
+
+fn cholesky_cov_free(x: &[f64], rows: usize, columns: usize) -> Result<Vec<f64>, String> {
+    if rows < columns {
+        return Err(format!("it has {} rows but {} columns", rows, columns));
+    }
+    check_lower_triangular(x, rows, columns)?;
+    let mut y = Vec::new();
+    for i in 0..rows {
+        for j in 0..columns.min(i + 1) {
+            y.push(match i == j {
+                true => x[i + j * rows].ln(),
+                false => x[i + j * rows],
+            });
+        }
+    }
+    Ok(y)
+}
+
+fn check_lower_triangular(x: &[f64], rows: usize, columns: usize) -> Result<(), String> {
+    for j in 0..columns {
+        if let Some(i) = (0..j.min(rows)).find(|i| x[i + j * rows] != 0.0) {
+            return Err(format!(
+                "element [{}][{}] is {}, above the diagonal",
+                i + 1,
+                j + 1,
+                x[i + j * rows]
+            ));
+        }
+        if j < rows && x[j + j * rows] <= 0.0 {
+            return Err(format!(
+                "diagonal element {} is {}, not positive",
+                j + 1,
+                x[j + j * rows]
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Canonical partial correlations in `(-1, 1)`.
+fn corr_constrain(y: &[f64], log_jacobian: &mut f64) -> Vec<f64> {
+    y.iter()
+        .map(|y| {
+            let z = y.tanh();
+            *log_jacobian += (1.0 - z * z).ln();
+            z
+        })
+        .collect()
+}
+
+/// Stan's transform from `size * (size - 1) / 2` reals, read column by
+/// column through the lower triangle as canonical partial correlations, to
+/// a correlation matrix.
+fn corr_matrix_constrain(y: &[f64], size: usize, log_jacobian: &mut f64) -> Vec<f64> {
+    let partials = corr_constrain(y, log_jacobian);
+    let mut factor = vec![0.0; size * size];
+    let mut remaining = vec![1.0_f64; size];
+    let mut position = 0;
+    for j in 0..size {
+        factor[j + j * size] = remaining[j].sqrt();
+        for i in j + 1..size {
+            let partial = partials[position];
+            position += 1;
+            *log_jacobian += 0.5 * (size - 2 - j) as f64 * (1.0 - partial * partial).ln();
+            factor[i + j * size] = partial * remaining[i].sqrt();
+            remaining[i] *= 1.0 - partial * partial;
+        }
+    }
+    times_own_transpose(&factor, size)
+}
+
+fn corr_matrix_free(x: &[f64], size: usize) -> Result<Vec<f64>, String> {
+    if let Some(i) = (0..size).find(|i| (x[i + i * size] - 1.0).abs() > TOLERANCE) {
+        return Err(format!(
+            "diagonal element {} is {}, not 1",
+            i + 1,
+            x[i + i * size]
+        ));
+    }
+    let factor = cholesky(x, size)?;
+    let mut remaining = vec![1.0_f64; size];
+    let mut y = Vec::with_capacity(size * size.saturating_sub(1) / 2);
+    for j in 0..size {
+        for i in j + 1..size {
+            let value = factor[i + j * size];
+            y.push((value / remaining[i].sqrt()).atanh());
+            remaining[i] -= value * value;
+        }
+    }
+    Ok(y)
+}
+
+/// Stan's transform from `size + size * (size - 1) / 2` reals, the Cholesky
+/// factor read row by row with the logarithm of each diagonal element, to a
+/// covariance matrix.
+fn cov_matrix_constrain(y: &[f64], size: usize, log_jacobian: &mut f64) -> Vec<f64> {
+    let mut factor = vec![0.0; size * size];
+    let mut reals = y.iter();
+    *log_jacobian += size as f64 * std::f64::consts::LN_2;
+    for i in 0..size {
+        for j in 0..=i {
+            let y = *reals.next().expect("the size was checked");
+            factor[i + j * size] = if i == j {
+                *log_jacobian += (size - i + 1) as f64 * y;
+                y.exp()
+            } else {
+                y
+            };
+        }
+    }
+    times_own_transpose(&factor, size)
+}
+
+fn cov_matrix_free(x: &[f64], size: usize) -> Result<Vec<f64>, String> {
+    let factor = cholesky(x, size)?;
+    let mut y = Vec::with_capacity(size + size * size.saturating_sub(1) / 2);
+    for i in 0..size {
+        for j in 0..i {
+            y.push(factor[i + j * size]);
+        }
+        y.push(factor[i + i * size].ln());
+    }
+    Ok(y)
+}
+
+/// The lower Cholesky factor of the symmetric positive definite `size` by
+/// `size` matrix `x`, both column-major.
+fn cholesky(x: &[f64], size: usize) -> Result<Vec<f64>, String> {
+    for j in 0..size {
+        for i in j + 1..size {
+            if (x[i + j * size] - x[j + i * size]).abs() > TOLERANCE {
+                return Err(format!("it is not symmetric at [{}][{}]", i + 1, j + 1));
+            }
+        }
+    }
+    let mut factor = vec![0.0; size * size];
+    for j in 0..size {
+        let diagonal = x[j + j * size]
+            - (0..j)
+                .map(|k| factor[j + k * size] * factor[j + k * size])
+                .sum::<f64>();
+        if diagonal.is_nan() || diagonal <= 0.0 {
+            return Err("it is not positive definite".to_string());
+        }
+        factor[j + j * size] = diagonal.sqrt();
+        for i in j + 1..size {
+            let dot = (0..j)
+                .map(|k| factor[i + k * size] * factor[j + k * size])
+                .sum::<f64>();
+            factor[i + j * size] = (x[i + j * size] - dot) / factor[j + j * size];
+        }
+    }
+    Ok(factor)
+}
+
+/// `factor * factor'` for a lower triangular `size` by `size` matrix.
+fn times_own_transpose(factor: &[f64], size: usize) -> Vec<f64> {
+    let mut x = vec![0.0; size * size];
+    for i in 0..size {
+        for j in 0..size {
+            x[i + j * size] = (0..=i.min(j))
+                .map(|k| factor[i + k * size] * factor[j + k * size])
+                .sum();
+        }
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::declared_variables;
+    use crate::stan_model_block_type::StanModelBlockType;
+
+    fn parameters(declarations: &[&str]) -> Vec<Variable> {
+        declared_variables(StanModelBlockType::Parameters, declarations)
+    }
+
+    fn object(value: Value) -> Data {
+        let Value::Object(data) = value else {
+            panic!("data must be an object");
+        };
+        data
+    }
+
+    /// The log absolute determinant of the Jacobian of `f` at `y`, by
+    /// central differences and Gaussian elimination.
+    fn numeric_log_jacobian(f: impl Fn(&[f64]) -> Vec<f64>, y: &[f64]) -> f64 {
+        let step = 1e-6;
+        let mut jacobian = (0..y.len())
+            .map(|j| {
+                let (mut above, mut below) = (y.to_vec(), y.to_vec());
+                above[j] += step;
+                below[j] -= step;
+                f(&above)
+                    .iter()
+                    .zip(f(&below))
+                    .map(|(above, below)| (above - below) / (2.0 * step))
+                    .collect::<Vec<f64>>()
+            })
+            .collect::<Vec<Vec<f64>>>();
+        let mut log_determinant = 0.0;
+        for k in 0..y.len() {
+            let pivot = (k..y.len())
+                .max_by(|a, b| jacobian[*a][k].abs().total_cmp(&jacobian[*b][k].abs()))
+                .unwrap();
+            jacobian.swap(k, pivot);
+            log_determinant += jacobian[k][k].abs().ln();
+            let pivot_row = jacobian[k].clone();
+            for row in jacobian.iter_mut().skip(k + 1) {
+                let factor = row[k] / pivot_row[k];
+                for (value, pivot) in row.iter_mut().zip(&pivot_row).skip(k) {
+                    *value -= factor * pivot;
+                }
+            }
+        }
+        log_determinant
+    }
+
+    /// Every real in a JSON value, in the order they are written.
+    fn reals(value: &Value) -> Vec<f64> {
+        match value {
+            Value::Array(items) => items.iter().flat_map(reals).collect(),
+            Value::Object(fields) => fields.values().flat_map(reals).collect(),
+            value => vec![json_scalar(value).unwrap().as_f64()],
+        }
+    }
+
+    #[test]
+    fn constraining_and_unconstraining_are_inverses() {
+        let variables = parameters(&[
+            "real<lower=0> sigma;",
+            "array[2] real<lower=-1, upper=lo + 2> p;",
+            "vector<offset=1, multiplier=2>[2] z;",
+            "matrix<upper=0>[2, 2] m;",
+            "simplex[K] theta;",
+            "ordered[3] cuts;",
+            "positive_ordered[2] rates;",
+            "sum_to_zero_vector[K] effects;",
+            "sum_to_zero_matrix[3, 2] grid;",
+            "cholesky_factor_corr[K] l_omega;",
+            "cholesky_factor_cov[4, 3] l_sigma;",
+            "corr_matrix[K] omega;",
+            "cov_matrix[K] cov;",
+            "column_stochastic_matrix[3, 2] columns;",
+            "row_stochastic_matrix[2, 3] rows;",
+            "complex_vector[2] w;",
+            "array[2] tuple(real<lower=0>, simplex[2]) t;",
+        ]);
+        let data = object(json!({"K": 3, "lo": 0}));
+        let size = variables
+            .iter()
+            .map(|variable| unconstrained_size(&variable.ty, &data).unwrap())
+            .sum::<usize>();
+        let y = (0..size)
+            .map(|i| ((i * 7 % 11) as f64 - 5.0) / 4.0)
+            .collect::<Vec<f64>>();
+
+        let constrained = constrain(&variables, &y, &data).unwrap();
+        let unconstrained = unconstrain(&variables, &constrained.values, &data).unwrap();
+
+        for (i, (y, round_trip)) in y.iter().zip(&unconstrained.values).enumerate() {
+            assert!(
+                (y - round_trip).abs() < 1e-8,
+                "{}: {} != {}",
+                i,
+                y,
+                round_trip
+            );
+        }
+        assert_eq!(unconstrained.values.len(), y.len());
+        assert_eq!(unconstrained.log_jacobian, constrained.log_jacobian);
+
+        let values = &constrained.values;
+        assert!((reals(&values["theta"]).iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(reals(&values["effects"]).iter().sum::<f64>().abs() < 1e-12);
+        assert!(reals(&values["p"]).iter().all(|p| (-1.0..=2.0).contains(p)));
+        assert!((values["omega"][1][1].as_f64().unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(values["l_sigma"].as_array().unwrap().len(), 4);
+        assert_eq!(values["w"][1].as_array().unwrap().len(), 2);
+        assert_eq!(values["t"][1]["2"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn log_jacobians_match_numeric_derivatives() {
+        let cases: &[(&str, &[usize])] = &[
+            ("real<lower=-1, upper=2> x;", &[0]),
+            ("vector<offset=1, multiplier=2>[2] x;", &[0, 1]),
+            ("vector<lower=1>[2] x;", &[0, 1]),
+            ("simplex[4] x;", &[0, 1, 2]),
+            ("ordered[3] x;", &[0, 1, 2]),
+            ("positive_ordered[3] x;", &[0, 1, 2]),
+            ("cholesky_factor_corr[3] x;", &[3, 6, 7]),
+            ("cholesky_factor_cov[3, 2] x;", &[0, 2, 3, 4, 5]),
+            ("corr_matrix[3] x;", &[3, 6, 7]),
+            ("cov_matrix[2] x;", &[0, 2, 3]),
+        ];
+        for (declaration, coordinates) in cases {
+            let variables = parameters(&[declaration]);
+            let y = [0.3, -0.8, 0.5, 1.1, -0.2][..coordinates.len()].to_vec();
+            let constrained = |y: &[f64]| {
+                let values = reals(&constrain(&variables, y, &Data::new()).unwrap().values["x"]);
+                coordinates.iter().map(|i| values[*i]).collect()
+            };
+
+            let log_jacobian = constrain(&variables, &y, &Data::new())
+                .unwrap()
+                .log_jacobian;
+
+            let numeric = numeric_log_jacobian(constrained, &y);
+            assert!(
+                (log_jacobian - numeric).abs() < 1e-5,
+                "{}: {} != {}",
+                declaration,
+                log_jacobian,
+                numeric
+            );
+        }
+    }
+
+    #[test]
+    fn sum_to_zero_matrices_use_the_vector_basis_in_both_dimensions() {
+        let basis = [
+            [1.0, -1.0, 0.0].map(|x: f64| x / 2f64.sqrt()),
+            [1.0, 1.0, -2.0].map(|x: f64| x / 6f64.sqrt()),
+        ];
+        let y = [[1.0, 3.0], [0.0, 2.0]];
+        let expected = (0..3)
+            .map(|row| {
+                (0..3)
+                    .map(|column| {
+                        (0..2)
+                            .flat_map(|i| (0..2).map(move |j| (i, j)))
+                            .map(|(i, j)| y[i][j] * basis[i][row] * basis[j][column])
+                            .sum::<f64>()
+                    })
+                    .collect::<Vec<f64>>()
+            })
+            .collect::<Vec<Vec<f64>>>();
+
+        let vector = sum_to_zero_constrain(&[1.0, 2.0], 3).unwrap();
+        let matrix = parameters(&["sum_to_zero_matrix[3, 3] x;"]);
+        let constrained = constrain(&matrix, &[1.0, 0.0, 3.0, 2.0], &Data::new()).unwrap();
+
+        for (row, value) in vector.iter().enumerate() {
+            assert!((value - (basis[0][row] + 2.0 * basis[1][row])).abs() < 1e-12);
+        }
+        let rows = constrained.values["x"].as_array().unwrap();
+        for (row, expected) in rows.iter().zip(&expected) {
+            for (value, expected) in reals(row).iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn unit_vectors_keep_the_direction() {
+        let variables = parameters(&["unit_vector[2] u;"]);
+
+        let constrained = constrain(&variables, &[3.0, 4.0], &Data::new()).unwrap();
+
+        assert_eq!(constrained.values["u"], json!([0.6, 0.8]));
+        assert_eq!(constrained.log_jacobian, -12.5);
+        let unconstrained = unconstrain(&variables, &constrained.values, &Data::new()).unwrap();
+        assert_eq!(unconstrained.values, vec![0.6, 0.8]);
+    }
+
+    #[test]
+    fn invalid_inits_are_reported() {
+        let variables = parameters(&[
+            "real<lower=0> sigma;",
+            "simplex[3] theta;",
+            "matrix[2, 2] m;",
+        ]);
+        let unconstrain = |inits: Value| {
+            unconstrain(&variables, &object(inits), &Data::new())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            unconstrain(json!({"sigma": -1, "theta": [0.2, 0.3, 0.5], "m": [[1, 2], [3, 4]]})),
+            "<parameters>:1:15: cannot unconstrain 'sigma': 'sigma' is -1, below the lower bound 0"
+        );
+        assert_eq!(
+            unconstrain(json!({"sigma": 1, "theta": [0.2, 0.3, 0.6]})),
+            "<parameters>:2:12: cannot unconstrain 'theta': \
+             'theta' is not a valid simplex: its elements sum to 1.1"
+        );
+        assert_eq!(
+            unconstrain(json!({"sigma": 1, "theta": [0.2, 0.3, 0.5], "m": [[1, 2], [3]]})),
+            "<parameters>:3:14: cannot unconstrain 'm': 'm[2]' should have 2 elements, found 1"
+        );
+        assert_eq!(
+            unconstrain(json!({"sigma": 1, "theta": [0.2, 0.3, 0.5]})),
+            "<parameters>:3:14: cannot unconstrain 'm': 'm' is missing from the inits"
+        );
+    }
+
+    #[test]
+    fn types_with_the_wrong_number_of_sizes_are_errors() {
+        let mut variables = parameters(&["vector[2] x;"]);
+        let size = variables[0].ty.sizes[0].clone();
+        variables[0].ty.sizes.push(size);
+
+        assert_eq!(
+            unconstrain(
+                &variables,
+                &object(json!({"x": [[1, 2], [3, 4]]})),
+                &Data::new()
+            )
+            .unwrap_err(),
+            "<parameters>:1:11: cannot unconstrain 'x': 'vector' takes 1 size, found 2"
+        );
+        assert_eq!(
+            constrain(&variables, &[0.0; 4], &Data::new()).unwrap_err(),
+            "<parameters>:1:11: cannot size 'x': 'vector' takes 1 size, found 2"
+        );
+    }
+
+    #[test]
+    fn the_number_of_unconstrained_values_is_checked() {
+        let variables = parameters(&["simplex[K] theta;"]);
+        let data = object(json!({"K": 4}));
+
+        assert_eq!(
+            constrain(&variables, &[0.0, 1.0], &data).unwrap_err(),
+            "expected 3 unconstrained values, found 2"
+        );
+        assert_eq!(
+            constrain(&variables, &[0.0, 0.0, 0.0], &data)
+                .unwrap()
+                .values["theta"],
+            json!([0.25, 0.25, 0.25, 0.25])
+        );
+    }
+}